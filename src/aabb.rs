@@ -41,14 +41,14 @@ impl AABB {
                 if t0 > ray_t.min {
                     ray_t.min = t0;
                 }
-                if t1 > ray_t.max {
+                if t1 < ray_t.max {
                     ray_t.max = t1;
                 }
             } else {
                 if t1 > ray_t.min {
                     ray_t.min = t1;
                 }
-                if t0 > ray_t.max {
+                if t0 < ray_t.max {
                     ray_t.max = t0;
                 }
             }
@@ -135,3 +135,26 @@ impl Add<AABB> for Vec3 {
         rhs + self
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn unit_box() -> AABB {
+        AABB::from((Vec3::new(0.0, 0.0, 0.0), Vec3::new(1.0, 1.0, 1.0)))
+    }
+
+    #[test]
+    fn ray_through_box_hits() {
+        let r = Ray::new(Vec3::new(-1.0, 2.0, 0.5), Vec3::new(1.0, -1.0, 0.0));
+        assert!(unit_box().hit(&r, Interval::new(0.0, f64::INFINITY)));
+    }
+
+    // The ray crosses the box's x and y slabs, but leaves the x slab before it
+    // enters the y slab.
+    #[test]
+    fn ray_beside_box_misses() {
+        let r = Ray::new(Vec3::new(-1.0, 3.5, 0.5), Vec3::new(1.0, -1.0, 0.0));
+        assert!(!unit_box().hit(&r, Interval::new(0.0, f64::INFINITY)));
+    }
+}
@@ -0,0 +1,118 @@
+use crate::color::{Color, luminance};
+
+// Settings for adaptive sampling, which stops sampling pixels once their noise
+// drops below a threshold instead of always taking the camera's full
+// samples_per_pixel.
+#[derive(Clone, Copy)]
+pub struct AdaptiveSampling {
+    // Samples every pixel takes before it can be considered converged, so that
+    // small or dim features aren't missed entirely.
+    pub min_samples: u32,
+    // Largest estimated error a converged pixel may have, on the scale of
+    // display brightness from zero to one (see PixelEstimate::error).
+    pub threshold: f64,
+}
+
+impl AdaptiveSampling {
+    pub fn new(min_samples: u32, threshold: f64) -> Self {
+        Self {
+            min_samples: min_samples.max(2),
+            threshold,
+        }
+    }
+
+    // Returns whether a pixel with the given samples can stop sampling.
+    pub fn converged(&self, estimate: &PixelEstimate) -> bool {
+        estimate.count >= self.min_samples && estimate.error() < self.threshold
+    }
+}
+
+// Running statistics of the brightness of a pixel's samples.
+#[derive(Default, Clone, Copy)]
+pub struct PixelEstimate {
+    count: u32,
+    sum: f64,
+    sum_squares: f64,
+}
+
+impl PixelEstimate {
+    pub fn add(&mut self, color: Color) {
+        let y = luminance(color);
+        self.count += 1;
+        self.sum += y;
+        self.sum_squares += y * y;
+    }
+
+    // Appends the statistics to `out`, for saving in a checkpoint.
+    pub fn write_values(&self, out: &mut Vec<f64>) {
+        out.extend([self.count as f64, self.sum, self.sum_squares]);
+    }
+
+    // Reads statistics written by write_values.
+    pub fn read_values(values: &mut impl Iterator<Item = f64>) -> Option<Self> {
+        Some(Self {
+            count: values.next()? as u32,
+            sum: values.next()?,
+            sum_squares: values.next()?,
+        })
+    }
+
+    // Estimates the standard error of the pixel's brightness after a square
+    // root, which roughly matches how displays encode brightness, so that dark
+    // pixels need as little absolute noise as the eye can notice there. The
+    // camera's exposure, tone map and transfer function aren't taken into
+    // account: the error is that of the radiance if it were shown as it is.
+    pub fn error(&self) -> f64 {
+        if self.count < 2 {
+            return f64::INFINITY;
+        }
+        let n = self.count as f64;
+        let mean = self.sum / n;
+        let variance = f64::max(self.sum_squares / n - mean * mean, 0.0) / (n - 1.0);
+        // The square root's slope is 1/(2 sqrt(mean)).
+        variance.sqrt() / (2.0 * f64::max(mean, 1e-4).sqrt())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn estimate(samples: &[f64]) -> PixelEstimate {
+        let mut estimate = PixelEstimate::default();
+        for &y in samples {
+            estimate.add(Color::new(y, y, y));
+        }
+        estimate
+    }
+
+    #[test]
+    fn converges_after_the_minimum_samples() {
+        let adaptive = AdaptiveSampling::new(4, 0.01);
+        assert!(!adaptive.converged(&estimate(&[0.5; 3])));
+        assert!(adaptive.converged(&estimate(&[0.5; 4])));
+        // Two samples are needed to estimate the error at all.
+        let adaptive = AdaptiveSampling::new(0, 0.01);
+        assert_eq!(adaptive.min_samples, 2);
+        assert_eq!(estimate(&[0.5]).error(), f64::INFINITY);
+    }
+
+    #[test]
+    fn noisy_pixels_need_more_samples() {
+        let adaptive = AdaptiveSampling::new(4, 0.01);
+        let noisy: Vec<f64> = (0..16).map(|k| (k % 2) as f64).collect();
+        assert!(!adaptive.converged(&estimate(&noisy)));
+        // The error shrinks with the square root of the samples taken.
+        let more: Vec<f64> = (0..64).map(|k| (k % 2) as f64).collect();
+        let ratio = estimate(&noisy).error() / estimate(&more).error();
+        assert!((ratio - f64::sqrt(63.0 / 15.0)).abs() < 1e-9, "{ratio}");
+    }
+
+    #[test]
+    fn error_is_relative_to_brightness() {
+        // The same relative noise is less visible in dark pixels.
+        let bright = estimate(&[0.8, 1.2, 0.8, 1.2]);
+        let dark = estimate(&[0.08, 0.12, 0.08, 0.12]);
+        assert!((bright.error() / dark.error() - f64::sqrt(10.0)).abs() < 1e-9);
+    }
+}
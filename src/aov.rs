@@ -0,0 +1,250 @@
+use std::str::FromStr;
+
+use crate::{
+    color::{Color, heat},
+    hit::{Hit, HitRecord},
+    integrator::LightPaths,
+    interval::Interval,
+    ray::Ray,
+    vec3::{Vec3, unit_vector},
+};
+
+// Arbitrary output variables: extra images rendered alongside the beauty image,
+// for compositing and denoising. All are averaged over the pixel's samples.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Aov {
+    // Reflectance of the first surface hit.
+    Albedo,
+    // Shading normal of the first surface hit, facing the camera.
+    Normal,
+    // Distance from the camera to the first surface hit, infinite for pixels
+    // that don't hit anything.
+    Depth,
+    // World space position of the first surface hit.
+    Position,
+    // Id of the first object hit by the pixel's first sample, zero for
+    // nothing. Not averaged, so ids stay exact. See next_object_id for how ids
+    // are assigned.
+    ObjectId,
+    // Light reflected by the first surface straight from a light.
+    Direct,
+    // All other reflected light.
+    Indirect,
+    // Emitters and the background seen directly.
+    Emission,
+    // Estimated variance of the pixel's color, from the spread of its samples.
+    Variance,
+    // Number of samples the pixel took, as a heat map running from blue (none)
+    // to red (the camera's samples_per_pixel). Useful with adaptive sampling.
+    SampleCount,
+}
+
+impl FromStr for Aov {
+    type Err = ();
+
+    // Parses the pass's name in snake case, e.g. `object_id`.
+    fn from_str(name: &str) -> Result<Self, ()> {
+        Ok(match name {
+            "albedo" => Aov::Albedo,
+            "normal" => Aov::Normal,
+            "depth" => Aov::Depth,
+            "position" => Aov::Position,
+            "object_id" => Aov::ObjectId,
+            "direct" => Aov::Direct,
+            "indirect" => Aov::Indirect,
+            "emission" => Aov::Emission,
+            "variance" => Aov::Variance,
+            "sample_count" => Aov::SampleCount,
+            _ => return Err(()),
+        })
+    }
+}
+
+impl Aov {
+    // Returns whether the pass needs the integrator to split its light.
+    pub fn needs_light_paths(&self) -> bool {
+        matches!(self, Aov::Direct | Aov::Indirect | Aov::Emission)
+    }
+}
+
+// Running sums of the AOVs for one pixel.
+#[derive(Default, Clone)]
+pub struct AovPixel {
+    samples: u32,
+    // Number of samples that hit a surface, and the sums over them.
+    hits: u32,
+    albedo: Color,
+    normal: Vec3,
+    depth: f64,
+    position: Vec3,
+    object_id: Option<u32>,
+    paths: LightPaths,
+    // Sums of the color samples and their squares.
+    radiance: Color,
+    radiance_squared: Color,
+}
+
+impl AovPixel {
+    // Records the geometric passes for a camera ray.
+    pub fn add_hit(&mut self, r: &Ray, world: &dyn Hit) {
+        self.samples += 1;
+        let mut rec = HitRecord::default();
+        if !world.hit(r, Interval::new(0.001, f64::INFINITY), &mut rec) {
+            self.object_id.get_or_insert(0);
+            return;
+        }
+        self.hits += 1;
+        self.albedo += rec.mat.albedo(&rec);
+        self.normal += unit_vector(rec.mat.shading_normal(&rec));
+        self.depth += rec.t * r.direction().length();
+        self.position += rec.p;
+        self.object_id.get_or_insert(rec.object_id);
+    }
+
+    // Records the split radiance of a camera sample.
+    pub fn add_paths(&mut self, paths: LightPaths) {
+        self.paths.emission += paths.emission;
+        self.paths.direct += paths.direct;
+        self.paths.indirect += paths.indirect;
+    }
+
+    // Appends the sums to `out`, for saving in a checkpoint.
+    pub fn write_values(&self, out: &mut Vec<f64>) {
+        out.extend([self.samples as f64, self.hits as f64]);
+        for v in [self.albedo, self.normal] {
+            out.extend([v.x(), v.y(), v.z()]);
+        }
+        out.push(self.depth);
+        out.extend([self.position.x(), self.position.y(), self.position.z()]);
+        out.push(self.object_id.map_or(-1.0, |id| id as f64));
+        let paths = &self.paths;
+        for v in [paths.emission, paths.direct, paths.indirect] {
+            out.extend([v.x(), v.y(), v.z()]);
+        }
+        for v in [self.radiance, self.radiance_squared] {
+            out.extend([v.x(), v.y(), v.z()]);
+        }
+    }
+
+    // Reads sums written by write_values.
+    pub fn read_values(values: &mut impl Iterator<Item = f64>) -> Option<Self> {
+        let vec3 = |values: &mut dyn Iterator<Item = f64>| -> Option<Vec3> {
+            Some(Vec3::new(values.next()?, values.next()?, values.next()?))
+        };
+        let samples = values.next()? as u32;
+        let hits = values.next()? as u32;
+        let albedo = vec3(values)?;
+        let normal = vec3(values)?;
+        let depth = values.next()?;
+        let position = vec3(values)?;
+        let object_id = values.next()?;
+        let paths = LightPaths {
+            emission: vec3(values)?,
+            direct: vec3(values)?,
+            indirect: vec3(values)?,
+        };
+        Some(Self {
+            samples,
+            hits,
+            albedo,
+            normal,
+            depth,
+            position,
+            object_id: (object_id >= 0.0).then_some(object_id as u32),
+            paths,
+            radiance: vec3(values)?,
+            radiance_squared: vec3(values)?,
+        })
+    }
+
+    // Records the color of a camera sample.
+    pub fn add_radiance(&mut self, color: Color) {
+        self.radiance += color;
+        self.radiance_squared += color * color;
+    }
+
+    // Returns the pixel's value for the given pass. `max_samples` is the most
+    // samples a pixel can take.
+    pub fn value(&self, aov: Aov, max_samples: u32) -> Color {
+        let per_sample = 1.0 / self.samples.max(1) as f64;
+        let per_hit = 1.0 / self.hits.max(1) as f64;
+        match aov {
+            Aov::Albedo => self.albedo * per_sample,
+            // Normals can cancel out, e.g. when samples hit both sides of a
+            // thin surface.
+            Aov::Normal if self.normal.length_squared() == 0.0 => Color::default(),
+            Aov::Normal => unit_vector(self.normal),
+            Aov::Depth if self.hits == 0 => Color::new(1.0, 1.0, 1.0) * f64::INFINITY,
+            Aov::Depth => Color::new(1.0, 1.0, 1.0) * (self.depth * per_hit),
+            Aov::Position => self.position * per_hit,
+            Aov::ObjectId => Color::new(1.0, 1.0, 1.0) * self.object_id.unwrap_or(0) as f64,
+            Aov::Direct => self.paths.direct * per_sample,
+            Aov::Indirect => self.paths.indirect * per_sample,
+            Aov::Emission => self.paths.emission * per_sample,
+            Aov::Variance if self.samples < 2 => Color::default(),
+            Aov::Variance => {
+                // Variance of the samples, divided by their number for the
+                // variance of their mean.
+                let mean = self.radiance * per_sample;
+                let spread = self.radiance_squared * per_sample - mean * mean;
+                spread * (1.0 / (self.samples - 1) as f64)
+            }
+            Aov::SampleCount => heat(self.samples as f64 / max_samples.max(1) as f64),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use super::*;
+    use crate::{hit::Hittables, material::Lambertian, quad::Quad};
+
+    // A unit square in the z = 0 plane.
+    fn square() -> Hittables {
+        let mut world = Hittables::default();
+        world.add(Arc::new(Quad::new(
+            Vec3::new(0.0, 0.0, 0.0),
+            Vec3::new(1.0, 0.0, 0.0),
+            Vec3::new(0.0, 1.0, 0.0),
+            Arc::new(Lambertian::new_color(Color::new(0.5, 0.5, 0.5))),
+        )));
+        world
+    }
+
+    #[test]
+    fn normals_facing_the_camera() {
+        let world = square();
+        let mut pixel = AovPixel::default();
+        let r = Ray::new(Vec3::new(0.5, 0.5, 1.0), Vec3::new(0.0, 0.0, -1.0));
+        pixel.add_hit(&r, &world);
+        let normal = pixel.value(Aov::Normal, 1);
+        assert_eq!((normal.x(), normal.y(), normal.z()), (0.0, 0.0, 1.0));
+        assert_eq!(pixel.value(Aov::Depth, 1).x(), 1.0);
+        assert_eq!(pixel.value(Aov::Albedo, 1).y(), 0.5);
+    }
+
+    #[test]
+    fn canceling_normals_are_black() {
+        let world = square();
+        let mut pixel = AovPixel::default();
+        for z in [1.0, -1.0] {
+            let r = Ray::new(Vec3::new(0.5, 0.5, z), Vec3::new(0.0, 0.0, -z));
+            pixel.add_hit(&r, &world);
+        }
+        assert_eq!(pixel.value(Aov::Normal, 2).length_squared(), 0.0);
+    }
+
+    #[test]
+    fn object_id_of_the_first_sample() {
+        let world = square();
+        let mut pixel = AovPixel::default();
+        let miss = Ray::new(Vec3::new(2.0, 2.0, 1.0), Vec3::new(0.0, 0.0, -1.0));
+        let hit = Ray::new(Vec3::new(0.5, 0.5, 1.0), Vec3::new(0.0, 0.0, -1.0));
+        pixel.add_hit(&miss, &world);
+        pixel.add_hit(&hit, &world);
+        assert_eq!(pixel.value(Aov::ObjectId, 2).x(), 0.0);
+        assert_eq!(pixel.value(Aov::Depth, 2).x(), 1.0);
+    }
+}
@@ -0,0 +1,111 @@
+use std::sync::Arc;
+
+use crate::{color::Color, ray::Ray, sphere::Sphere, texture::Texture, vec3::unit_vector};
+
+// What rays see when they escape the scene without hitting anything.
+pub trait Background: Send + Sync {
+    // Returns the color seen along the given ray.
+    fn value(&self, r: &Ray) -> Color;
+}
+
+// A solid color in every direction.
+impl Background for Color {
+    fn value(&self, _r: &Ray) -> Color {
+        *self
+    }
+}
+
+// A vertical gradient between two colors, blending from bottom (looking straight
+// down) to top (looking straight up).
+pub struct GradientBackground {
+    bottom: Color,
+    top: Color,
+}
+
+impl GradientBackground {
+    pub fn new(bottom: Color, top: Color) -> Self {
+        Self { bottom, top }
+    }
+}
+
+// The white to light blue sky from Ray Tracing in One Weekend.
+impl Default for GradientBackground {
+    fn default() -> Self {
+        Self::new(Color::new(1.0, 1.0, 1.0), Color::new(0.5, 0.7, 1.0))
+    }
+}
+
+impl Background for GradientBackground {
+    fn value(&self, r: &Ray) -> Color {
+        let unit_direction = unit_vector(r.direction());
+        let a = 0.5 * (unit_direction.y() + 1.0);
+        self.bottom * (1.0 - a) + self.top * a
+    }
+}
+
+// A texture wrapped around the scene on a sphere at infinity. Textures are looked
+// up with the same (u, v) coordinates a Sphere would use for that direction, and
+// with the unit direction as the point.
+pub struct TextureBackground {
+    tex: Arc<dyn Texture>,
+}
+
+impl TextureBackground {
+    pub fn new(tex: Arc<dyn Texture>) -> Self {
+        Self { tex }
+    }
+}
+
+impl Background for TextureBackground {
+    fn value(&self, r: &Ray) -> Color {
+        let unit_direction = unit_vector(r.direction());
+        let (u, v) = Sphere::get_sphere_uv(unit_direction);
+        self.tex.value(u, v, unit_direction)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::vec3::Vec3;
+
+    // Shows the texture coordinates it is looked up with.
+    struct UvTexture;
+
+    impl Texture for UvTexture {
+        fn value(&self, u: f64, v: f64, _p: Vec3) -> Color {
+            Color::new(u, v, 0.0)
+        }
+    }
+
+    fn ray(direction: Vec3) -> Ray {
+        Ray::new(Vec3::new(5.0, -2.0, 1.0), direction)
+    }
+
+    #[test]
+    fn gradient_runs_from_bottom_to_top() {
+        let background =
+            GradientBackground::new(Color::new(1.0, 0.0, 0.0), Color::new(0.0, 0.0, 1.0));
+        let down = background.value(&ray(Vec3::new(0.0, -2.0, 0.0)));
+        let up = background.value(&ray(Vec3::new(0.0, 3.0, 0.0)));
+        let level = background.value(&ray(Vec3::new(1.0, 0.0, 1.0)));
+        assert_eq!((down.x(), down.z()), (1.0, 0.0));
+        assert_eq!((up.x(), up.z()), (0.0, 1.0));
+        assert_eq!((level.x(), level.z()), (0.5, 0.5));
+    }
+
+    #[test]
+    fn texture_is_wrapped_like_a_sphere() {
+        let background = TextureBackground::new(Arc::new(UvTexture));
+        for direction in [
+            Vec3::new(1.0, 0.0, 0.0),
+            Vec3::new(0.0, 1.0, 0.0),
+            Vec3::new(-0.3, -0.2, 0.9),
+        ] {
+            // Directions are looked up whatever their length and the ray's origin.
+            let color = background.value(&ray(direction * 4.0));
+            let (u, v) = Sphere::get_sphere_uv(unit_vector(direction));
+            assert!((color.x() - u).abs() < 1e-12 && (color.y() - v).abs() < 1e-12);
+        }
+    }
+}
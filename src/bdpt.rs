@@ -0,0 +1,819 @@
+use std::sync::RwLock;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use crate::{
+    camera::Camera,
+    color::Color,
+    hit::{Hit, HitRecord},
+    integrator::Integrator,
+    interval::Interval,
+    light::Light,
+    ray::Ray,
+    utils::random_f64,
+    vec3::{Vec3, dot},
+};
+
+// Resolution of the fixed point sums in SplatFilm.
+const SPLAT_UNIT: f64 = 1.0 / (1u64 << 32) as f64;
+
+#[derive(Clone, Copy, PartialEq)]
+enum VertexKind {
+    Camera,
+    Light,
+    Surface,
+}
+
+// A point along a camera or light subpath.
+#[derive(Clone)]
+struct Vertex {
+    kind: VertexKind,
+    p: Vec3,
+    // Surface normal, or zero for points without a surface (pinhole cameras and
+    // point lights).
+    n: Vec3,
+    // For surface vertices: the hit, the ray that arrived there and the
+    // material's attenuation when it scattered.
+    rec: Option<HitRecord>,
+    r_in: Ray,
+    attenuation: Color,
+    // Index into the finite lights, for light vertices.
+    light: usize,
+    // Contribution of the subpath up to this vertex over the density of
+    // sampling it.
+    beta: Color,
+    // Whether paths can't be connected through this vertex, because its
+    // material scatters specularly or not at all.
+    delta: bool,
+    // Area densities of sampling this vertex from the previous one along its
+    // subpath, and from the next one if the path were traced the other way.
+    pdf_fwd: f64,
+    pdf_rev: f64,
+}
+
+impl Vertex {
+    fn camera(p: Vec3, beta: Color) -> Self {
+        Self {
+            kind: VertexKind::Camera,
+            p,
+            n: Vec3::ZERO,
+            rec: None,
+            r_in: Ray::default(),
+            attenuation: Color::default(),
+            light: 0,
+            beta,
+            delta: false,
+            pdf_fwd: 0.0,
+            pdf_rev: 0.0,
+        }
+    }
+
+    fn light(light: usize, p: Vec3, n: Option<Vec3>, beta: Color, pdf_fwd: f64) -> Self {
+        Self {
+            kind: VertexKind::Light,
+            n: n.unwrap_or(Vec3::ZERO),
+            light,
+            pdf_fwd,
+            ..Self::camera(p, beta)
+        }
+    }
+
+    fn surface(rec: HitRecord, r_in: Ray, beta: Color) -> Self {
+        Self {
+            kind: VertexKind::Surface,
+            p: rec.p,
+            n: rec.normal,
+            rec: Some(rec),
+            r_in,
+            ..Self::camera(Vec3::ZERO, beta)
+        }
+    }
+
+    fn is_connectible(&self) -> bool {
+        !self.delta
+    }
+
+    // Returns the fraction of light arriving at this surface vertex from `other`
+    // that is scattered back along the path, including the cosine term.
+    fn f(&self, other: &Vertex) -> Color {
+        let Some(rec) = &self.rec else {
+            return Color::default();
+        };
+        let scattered = Ray::new(self.p, other.p - self.p);
+        self.attenuation * rec.mat.scattering_pdf(&self.r_in, rec, &scattered)
+    }
+
+    // Returns the area density with which `next` is sampled from this vertex,
+    // having arrived from `prev`.
+    fn pdf(
+        &self,
+        cam: &Camera,
+        lights: &[&dyn Light],
+        prev: Option<&Vertex>,
+        next: &Vertex,
+    ) -> f64 {
+        match self.kind {
+            VertexKind::Camera => convert_density(cam.pdf_direction(next.p - self.p), self, next),
+            VertexKind::Light => self.pdf_light(lights[self.light], next),
+            VertexKind::Surface => {
+                let (Some(rec), Some(prev)) = (&self.rec, prev) else {
+                    return 0.0;
+                };
+                let r_in = Ray::new(prev.p, self.p - prev.p);
+                let scattered = Ray::new(self.p, next.p - self.p);
+                convert_density(rec.mat.scattering_pdf(&r_in, rec, &scattered), self, next)
+            }
+        }
+    }
+
+    // Returns the area density with which light emitted from this vertex, as
+    // part of the given light, reaches `next`.
+    fn pdf_light(&self, light: &dyn Light, next: &Vertex) -> f64 {
+        let w = next.p - self.p;
+        let (_, pdf_dir) = light.emission_pdf(self.p, self.n, w / w.length());
+        convert_density(pdf_dir, self, next)
+    }
+
+    // Returns the area density with which a light path starts at this vertex,
+    // heading towards `next`.
+    fn pdf_light_origin(&self, light: &dyn Light, light_pdf: f64, next: &Vertex) -> f64 {
+        let w = next.p - self.p;
+        let (pdf_pos, _) = light.emission_pdf(self.p, self.n, w / w.length());
+        pdf_pos * light_pdf
+    }
+}
+
+// Converts a solid angle density for sampling `to` from `from` into a density
+// per unit area at `to`.
+fn convert_density(pdf: f64, from: &Vertex, to: &Vertex) -> f64 {
+    let w = to.p - from.p;
+    let dist2 = w.length_squared();
+    if dist2 <= 0.0 {
+        return 0.0;
+    }
+    let mut pdf = pdf / dist2;
+    if to.n.length_squared() > 0.0 {
+        pdf *= f64::abs(dot(to.n, w)) / f64::sqrt(dist2);
+    }
+    pdf
+}
+
+// Returns whether the segment between two points is free of geometry.
+fn visible(world: &dyn Hit, a: Vec3, b: Vec3, time: f64) -> bool {
+    let d = b - a;
+    let distance = d.length();
+    let r = Ray::new(a, d / distance).with_time(time);
+    let mut rec = HitRecord::default();
+    !world.hit(&r, Interval::new(0.001, distance - 0.001), &mut rec)
+}
+
+// Running sums of light added to each pixel by tracing light paths to the
+// camera, shared between rendering threads. Sums are kept in fixed point, in
+// units of SPLAT_UNIT, so that they don't depend on the order threads add to
+// them in and seeded renders are repeatable.
+//
+// A unit of 2^-32 gives each channel a range of about 4e9. Sums saturate there
+// rather than wrapping, so a pixel hit by extreme fireflies stays blown out
+// instead of turning black, and saturating adds still don't depend on order.
+// Splats below half a unit, about 1e-10, round to nothing; light that dim can't
+// show in the image anyway.
+#[derive(Default)]
+struct SplatFilm {
+    width: usize,
+    data: Vec<[AtomicU64; 3]>,
+}
+
+impl SplatFilm {
+    fn new(width: usize, height: usize) -> Self {
+        Self {
+            width,
+            data: (0..width * height)
+                .map(|_| [AtomicU64::new(0), AtomicU64::new(0), AtomicU64::new(0)])
+                .collect(),
+        }
+    }
+
+    fn add(&self, (i, j): (usize, usize), color: Color) {
+        let Some(pixel) = self.data.get(j * self.width + i) else {
+            return;
+        };
+        for (channel, value) in pixel.iter().zip([color.x(), color.y(), color.z()]) {
+            // Negative and NaN values become zero, and too large ones u64::MAX.
+            let units = (value / SPLAT_UNIT).round() as u64;
+            if units > 0 {
+                let _ = channel.fetch_update(Ordering::Relaxed, Ordering::Relaxed, |sum| {
+                    Some(sum.saturating_add(units))
+                });
+            }
+        }
+    }
+
+    fn to_colors(&self) -> Vec<Color> {
+        self.data
+            .iter()
+            .map(|[r, g, b]| {
+                Color::new(
+                    r.load(Ordering::Relaxed) as f64 * SPLAT_UNIT,
+                    g.load(Ordering::Relaxed) as f64 * SPLAT_UNIT,
+                    b.load(Ordering::Relaxed) as f64 * SPLAT_UNIT,
+                )
+            })
+            .collect()
+    }
+}
+
+// A bidirectional path tracer. For every camera sample it traces one subpath
+// from the camera and one from a randomly picked light, then connects every
+// prefix of one to every prefix of the other, weighting each of the resulting
+// ways to build a path with multiple importance sampling. Connections straight
+// to the camera land in arbitrary pixels and are splatted onto the image.
+//
+// This finds light that reaches the camera through paths a path tracer rarely
+// samples, like caustics seen through diffuse surfaces and rooms lit
+// indirectly. Paths are started from the camera's lights that are in the scene
+// (area, point and spot lights); lights at infinity and the background are
+// handled as in the PathTracer. Like it, subpaths are cut short with Russian
+// roulette after the camera's russian_roulette_depth bounces.
+#[derive(Default)]
+pub struct BidirectionalPathTracer {
+    splats: RwLock<SplatFilm>,
+}
+
+impl BidirectionalPathTracer {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    // Extends a subpath from its last vertex along `ray`, which was sampled with
+    // solid angle density pdf_dir and carries `beta`. Stops after the path has
+    // max_vertices vertices, or when Russian roulette ends it. Returns the ray
+    // and its weight if the path escaped the scene.
+    #[allow(clippy::too_many_arguments)]
+    fn random_walk(
+        &self,
+        cam: &Camera,
+        world: &dyn Hit,
+        mut ray: Ray,
+        mut beta: Color,
+        pdf_dir: f64,
+        max_vertices: usize,
+        path: &mut Vec<Vertex>,
+    ) -> Option<(Ray, Color)> {
+        let mut pdf_fwd = pdf_dir;
+        while path.len() < max_vertices {
+            let mut rec = HitRecord::default();
+            if !world.hit(&ray, Interval::new(0.001, f64::INFINITY), &mut rec) {
+                return Some((ray, beta));
+            }
+
+            let prev = path.last().unwrap();
+            let mut vertex = Vertex::surface(rec.clone(), ray, beta);
+            vertex.pdf_fwd = convert_density(pdf_fwd, prev, &vertex);
+
+            let mut scattered = Ray::default();
+            let mut attenuation = Color::default();
+            if !rec
+                .mat
+                .scatter(&ray, &rec, &mut attenuation, &mut scattered)
+            {
+                // Surfaces that don't scatter can't be connected to either.
+                vertex.delta = true;
+                path.push(vertex);
+                return None;
+            }
+            vertex.attenuation = attenuation;
+
+            let pdf = rec.mat.scattering_pdf(&ray, &rec, &scattered);
+            let pdf_rev = if pdf > 0.0 {
+                pdf_fwd = pdf;
+                let reversed = Ray::new(rec.p, -ray.direction());
+                rec.mat.scattering_pdf(&scattered, &rec, &reversed)
+            } else {
+                vertex.delta = true;
+                pdf_fwd = 0.0;
+                0.0
+            };
+            beta = beta * attenuation;
+
+            let prev_pdf_rev = convert_density(pdf_rev, &vertex, path.last().unwrap());
+            path.last_mut().unwrap().pdf_rev = prev_pdf_rev;
+            path.push(vertex);
+
+            // As in the PathTracer, boosting survivors keeps beta unbiased. The
+            // survival probability is left out of the MIS densities: it depends
+            // on the direction the path was traced in, and the weights only sum
+            // to one if every strategy sees the same densities for a path.
+            let bounces = path.len() as u32 - 1;
+            if cam.russian_roulette_depth.is_some_and(|min| bounces >= min) {
+                let survival = f64::min(beta.max_component(), 0.95);
+                if random_f64() >= survival {
+                    return None;
+                }
+                beta /= survival;
+            }
+            ray = scattered;
+        }
+        None
+    }
+
+    // Traces a subpath starting at a randomly picked light.
+    fn light_subpath(
+        &self,
+        cam: &Camera,
+        world: &dyn Hit,
+        lights: &[&dyn Light],
+        time: f64,
+    ) -> Vec<Vertex> {
+        let mut path = Vec::new();
+        if lights.is_empty() {
+            return path;
+        }
+        let index = ((random_f64() * lights.len() as f64) as usize).min(lights.len() - 1);
+        let light_pdf = 1.0 / lights.len() as f64;
+        let Some(emission) = lights[index].sample_emission(time) else {
+            return path;
+        };
+        if emission.pdf_pos <= 0.0 || emission.pdf_dir <= 0.0 {
+            return path;
+        }
+
+        path.push(Vertex::light(
+            index,
+            emission.ray.origin(),
+            emission.normal,
+            emission.radiance,
+            emission.pdf_pos * light_pdf,
+        ));
+        let cos_theta = match emission.normal {
+            Some(n) => f64::abs(dot(n, emission.ray.direction())),
+            None => 1.0,
+        };
+        let beta =
+            emission.radiance * cos_theta / (light_pdf * emission.pdf_pos * emission.pdf_dir);
+        self.random_walk(
+            cam,
+            world,
+            emission.ray,
+            beta,
+            emission.pdf_dir,
+            cam.max_depth as usize + 1,
+            &mut path,
+        );
+        path
+    }
+
+    // Computes the contribution of the path made by joining the first s light
+    // vertices to the first t camera vertices, for t >= 2.
+    #[allow(clippy::too_many_arguments)]
+    fn connect(
+        &self,
+        cam: &Camera,
+        world: &dyn Hit,
+        lights: &[&dyn Light],
+        light_path: &[Vertex],
+        camera_path: &[Vertex],
+        s: usize,
+        t: usize,
+    ) -> Color {
+        let pt = &camera_path[t - 1];
+        let time = camera_path[0].r_in.time();
+        if s == 0 {
+            // The camera subpath itself ends on an emitter.
+            let Some(rec) = &pt.rec else {
+                return Color::default();
+            };
+            let emitted = rec.mat.emitted(&pt.r_in, rec);
+            if emitted.max_component() <= 0.0 {
+                return Color::default();
+            }
+            let weight = self.mis_weight(cam, lights, light_path, camera_path, None, s, t);
+            return pt.beta * emitted * weight;
+        }
+        if !pt.is_connectible() {
+            return Color::default();
+        }
+
+        if s == 1 {
+            // Sample a point on a light, as in next event estimation.
+            let index = ((random_f64() * lights.len() as f64) as usize).min(lights.len() - 1);
+            let light_pdf = 1.0 / lights.len() as f64;
+            let light = lights[index];
+            let Some(sample) = light.sample(pt.p) else {
+                return Color::default();
+            };
+            if sample.radiance.max_component() <= 0.0 {
+                return Color::default();
+            }
+            let p = pt.p + sample.direction * sample.distance;
+            let (pdf_pos, _) =
+                light.emission_pdf(p, sample.normal.unwrap_or(Vec3::ZERO), -sample.direction);
+            let sampled = Vertex::light(
+                index,
+                p,
+                sample.normal,
+                sample.radiance / light_pdf,
+                pdf_pos * light_pdf,
+            );
+            let color = pt.beta * pt.f(&sampled) * sampled.beta;
+            if color.max_component() <= 0.0 || !visible(world, pt.p, p, time) {
+                return Color::default();
+            }
+            let weight =
+                self.mis_weight(cam, lights, light_path, camera_path, Some(&sampled), s, t);
+            return color * weight;
+        }
+
+        let qs = &light_path[s - 1];
+        if !qs.is_connectible() {
+            return Color::default();
+        }
+        let d = qs.p - pt.p;
+        let color = qs.beta * qs.f(pt) * pt.f(qs) * pt.beta / d.length_squared();
+        if color.max_component() <= 0.0 || !visible(world, pt.p, qs.p, time) {
+            return Color::default();
+        }
+        let weight = self.mis_weight(cam, lights, light_path, camera_path, None, s, t);
+        color * weight
+    }
+
+    // Connects the first s light vertices straight to the camera (t = 1),
+    // splatting the result onto the pixel it lands in.
+    #[allow(clippy::too_many_arguments)]
+    fn connect_to_camera(
+        &self,
+        cam: &Camera,
+        world: &dyn Hit,
+        lights: &[&dyn Light],
+        light_path: &[Vertex],
+        camera_path: &[Vertex],
+        s: usize,
+        splats: &SplatFilm,
+    ) {
+        let qs = &light_path[s - 1];
+        if !qs.is_connectible() {
+            return;
+        }
+        let Some(sample) = cam.sample_importance(qs.p) else {
+            return;
+        };
+        let sampled = Vertex::camera(sample.origin, Color::new(1.0, 1.0, 1.0) * sample.weight);
+        let color = qs.beta * qs.f(&sampled) * sampled.beta;
+        let time = camera_path[0].r_in.time();
+        if color.max_component() <= 0.0 || !visible(world, qs.p, sample.origin, time) {
+            return;
+        }
+        let weight = self.mis_weight(cam, lights, light_path, camera_path, Some(&sampled), s, 1);
+        splats.add(sample.pixel, color * weight);
+    }
+
+    // Returns the multiple importance sampling weight (balance heuristic) of the
+    // path built with the (s, t) strategy, against all other strategies that
+    // could have built the same path. `sampled` replaces the connecting vertex
+    // when it was sampled specially (s = 1 or t = 1).
+    #[allow(clippy::too_many_arguments)]
+    fn mis_weight(
+        &self,
+        cam: &Camera,
+        lights: &[&dyn Light],
+        light_path: &[Vertex],
+        camera_path: &[Vertex],
+        sampled: Option<&Vertex>,
+        s: usize,
+        t: usize,
+    ) -> f64 {
+        if s + t == 2 {
+            return 1.0;
+        }
+
+        // Copy the vertices involved, so the densities this strategy changes can
+        // be updated.
+        let mut light_path = match sampled {
+            Some(sampled) if s == 1 => vec![sampled.clone()],
+            _ => light_path[..s].to_vec(),
+        };
+        let mut camera_path = match sampled {
+            Some(sampled) if t == 1 => vec![sampled.clone()],
+            _ => camera_path[..t].to_vec(),
+        };
+
+        // The vertices at either end of the connection are no longer delta.
+        camera_path[t - 1].delta = false;
+        if s > 0 {
+            light_path[s - 1].delta = false;
+        }
+
+        // Update the reverse densities of the vertices around the connection.
+        let pt = camera_path[t - 1].clone();
+        let pt_minus = if t > 1 {
+            Some(camera_path[t - 2].clone())
+        } else {
+            None
+        };
+        let qs = if s > 0 {
+            Some(light_path[s - 1].clone())
+        } else {
+            None
+        };
+        let qs_minus = if s > 1 {
+            Some(light_path[s - 2].clone())
+        } else {
+            None
+        };
+
+        match &qs {
+            Some(qs) => {
+                camera_path[t - 1].pdf_rev = qs.pdf(cam, lights, qs_minus.as_ref(), &pt);
+                if let Some(pt_minus) = &pt_minus {
+                    camera_path[t - 2].pdf_rev = pt.pdf(cam, lights, Some(qs), pt_minus);
+                }
+                light_path[s - 1].pdf_rev = pt.pdf(cam, lights, pt_minus.as_ref(), qs);
+                if let Some(qs_minus) = &qs_minus {
+                    light_path[s - 2].pdf_rev = qs.pdf(cam, lights, Some(&pt), qs_minus);
+                }
+            }
+            None => {
+                // The camera path hit an emitter. If it isn't one of the lights,
+                // no other strategy could have found it.
+                let (Some(rec), Some(pt_minus)) = (&pt.rec, &pt_minus) else {
+                    return 1.0;
+                };
+                let Some(light) = lights
+                    .iter()
+                    .find(|light| light.hit_pdf(&pt.r_in, rec) > 0.0)
+                else {
+                    return 1.0;
+                };
+                let light_pdf = 1.0 / lights.len() as f64;
+                camera_path[t - 1].pdf_rev = pt.pdf_light_origin(*light, light_pdf, pt_minus);
+                camera_path[t - 2].pdf_rev = pt.pdf_light(*light, pt_minus);
+            }
+        }
+
+        // Zero densities come from delta vertices, which are skipped below.
+        let remap = |pdf: f64| if pdf != 0.0 { pdf } else { 1.0 };
+        let mut sum = 0.0;
+
+        // Strategies with fewer camera vertices...
+        let mut ratio = 1.0;
+        for i in (1..t).rev() {
+            ratio *= remap(camera_path[i].pdf_rev) / remap(camera_path[i].pdf_fwd);
+            if !camera_path[i].delta && !camera_path[i - 1].delta {
+                sum += ratio;
+            }
+        }
+
+        // ...and with fewer light vertices.
+        let mut ratio = 1.0;
+        for i in (0..s).rev() {
+            ratio *= remap(light_path[i].pdf_rev) / remap(light_path[i].pdf_fwd);
+            let delta_before = if i > 0 {
+                light_path[i - 1].delta
+            } else {
+                lights[light_path[0].light].is_delta_position()
+            };
+            if !light_path[i].delta && !delta_before {
+                sum += ratio;
+            }
+        }
+
+        1.0 / (1.0 + sum)
+    }
+}
+
+impl Integrator for BidirectionalPathTracer {
+    fn preprocess(&self, cam: &Camera, _world: &dyn Hit) {
+        *self.splats.write().unwrap() = SplatFilm::new(cam.image_width, cam.image_height());
+    }
+
+    fn splats(&self) -> Option<Vec<Color>> {
+        Some(self.splats.read().unwrap().to_colors())
+    }
+
+    fn add_splats(&self, splats: &[Color]) {
+        let film = self.splats.read().unwrap();
+        for (k, &splat) in splats.iter().enumerate() {
+            film.add((k % film.width, k / film.width), splat);
+        }
+    }
+
+    fn li(&self, cam: &Camera, r: &Ray, world: &dyn Hit) -> Color {
+        let max_depth = cam.max_depth as usize;
+        let (finite, infinite): (Vec<&dyn Light>, Vec<&dyn Light>) =
+            cam.all_lights().partition(|light| !light.is_infinite());
+
+        let mut camera_path = vec![Vertex::camera(r.origin(), Color::new(1.0, 1.0, 1.0))];
+        camera_path[0].r_in = *r;
+        let escaped = self.random_walk(
+            cam,
+            world,
+            *r,
+            Color::new(1.0, 1.0, 1.0),
+            cam.pdf_direction(r.direction()),
+            max_depth + 2,
+            &mut camera_path,
+        );
+        let light_path = self.light_subpath(cam, world, &finite, r.time());
+
+        let mut color = Color::default();
+
+        // Lights at infinity and the background, as in the PathTracer.
+        for (i, vertex) in camera_path.iter().enumerate().skip(1) {
+            let Some(rec) = &vertex.rec else { continue };
+            if vertex.delta || i > max_depth {
+                continue;
+            }
+            for light in infinite.iter() {
+                color += vertex.beta
+                    * cam.sample_light(*light, &vertex.r_in, rec, vertex.attenuation, world);
+            }
+        }
+        if let Some((ray, beta)) = escaped {
+            let last = camera_path.last().unwrap();
+            let scatter_pdf = match &last.rec {
+                Some(rec) if !last.delta => Some(rec.mat.scattering_pdf(&last.r_in, rec, &ray)),
+                _ => None,
+            };
+            color += beta * cam.background_color(&ray, camera_path.len() == 1, scatter_pdf);
+        }
+
+        // Every combination of light and camera subpath prefixes.
+        let splats = self.splats.read().unwrap();
+        for t in 1..=camera_path.len() {
+            // The s = 1 strategy samples its own light vertex.
+            for s in 0..=light_path.len().max(1) {
+                let depth = s as isize + t as isize - 2;
+                if (s == 1 && t == 1) || depth < 0 || depth > max_depth as isize {
+                    continue;
+                }
+                if s > 0 && finite.is_empty() {
+                    continue;
+                }
+                if t == 1 {
+                    self.connect_to_camera(
+                        cam,
+                        world,
+                        &finite,
+                        &light_path,
+                        &camera_path,
+                        s,
+                        &splats,
+                    );
+                } else {
+                    color += self.connect(cam, world, &finite, &light_path, &camera_path, s, t);
+                }
+            }
+        }
+        color
+    }
+
+    fn describe(&self) -> String {
+        "bidirectional_path_tracer".to_string()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use super::*;
+    use crate::{
+        hit::Hittables,
+        integrator::PathTracer,
+        light::{AreaLight, Lights},
+        material::{DiffuseLight, Lambertian, Material},
+        quad::Quad,
+        utils::random_range_f64,
+    };
+
+    #[test]
+    fn splats_sum_in_fixed_point() {
+        let film = SplatFilm::new(2, 1);
+        film.add((1, 0), Color::new(0.25, 1.0, -1.0));
+        film.add((1, 0), Color::new(0.5, f64::NAN, 2.0));
+        // Out of the image.
+        film.add((0, 1), Color::new(1.0, 1.0, 1.0));
+        let colors = film.to_colors();
+        assert_eq!(colors.len(), 2);
+        assert_eq!(colors[0].length_squared(), 0.0);
+        assert_eq!(
+            (colors[1].x(), colors[1].y(), colors[1].z()),
+            (0.75, 1.0, 2.0)
+        );
+    }
+
+    #[test]
+    fn splats_saturate() {
+        let film = SplatFilm::new(1, 1);
+        film.add((0, 0), Color::new(1.0, 1e9, f64::INFINITY));
+        film.add((0, 0), Color::new(1e300, 1e10, 1.0));
+        let color = film.to_colors()[0];
+        let max = u64::MAX as f64 * SPLAT_UNIT;
+        assert_eq!((color.x(), color.y(), color.z()), (max, max, max));
+    }
+
+    // A small Cornell box: a unit cube open towards the camera, lit by a patch
+    // in its ceiling. Returns the camera looking into it and the scene.
+    fn cornell_box() -> (Camera, Hittables) {
+        let red: Arc<dyn Material> = Arc::new(Lambertian::new_color(Color::new(0.65, 0.05, 0.05)));
+        let white: Arc<dyn Material> =
+            Arc::new(Lambertian::new_color(Color::new(0.73, 0.73, 0.73)));
+        let green: Arc<dyn Material> =
+            Arc::new(Lambertian::new_color(Color::new(0.12, 0.45, 0.15)));
+        let light =
+            Arc::new(DiffuseLight::new_color(Color::new(4.0, 4.0, 4.0)).with_one_sided(true));
+
+        let (x, y, z) = (
+            Vec3::new(1.0, 0.0, 0.0),
+            Vec3::new(0.0, 1.0, 0.0),
+            Vec3::new(0.0, 0.0, 1.0),
+        );
+        let mut world = Hittables::default();
+        for (q, u, v, mat) in [
+            (Vec3::default(), y, z, &red),
+            (x, y, z, &green),
+            (Vec3::default(), x, z, &white),
+            (y, x, z, &white),
+            (z, x, y, &white),
+        ] {
+            world.add(Arc::new(Quad::new(q, u, v, mat.clone())));
+        }
+        let light_quad = Arc::new(Quad::new(
+            Vec3::new(0.75, 0.999, 0.75),
+            Vec3::new(-0.5, 0.0, 0.0),
+            Vec3::new(0.0, 0.0, -0.5),
+            light,
+        ));
+        world.add(light_quad.clone());
+        let mut lights = Lights::default();
+        lights.add(Arc::new(AreaLight::new(light_quad)));
+
+        let cam = Camera::builder()
+            .with_aspect_ratio(1.0)
+            .with_image_width(8)
+            .with_max_depth(5)
+            .with_background(Color::default())
+            .with_lights(lights)
+            .with_vfov(40.0)
+            .with_lookfrom(Vec3::new(0.5, 0.5, -1.4))
+            .with_lookat(Vec3::new(0.5, 0.5, 0.0))
+            .with_vup(Vec3::new(0.0, 1.0, 0.0))
+            .with_focus_dist(1.0)
+            .build();
+        (cam, world)
+    }
+
+    // Returns the mean luminance of the image and its standard error, from
+    // camera rays through uniformly random points on the film. Light that the
+    // integrator splats straight to pixels is added to the image.
+    fn image_mean(cam: &Camera, integrator: &dyn Integrator, world: &dyn Hit) -> (f64, f64) {
+        integrator.preprocess(cam, world);
+        let h = f64::tan(f64::to_radians(cam.vfov) / 2.0);
+        let n = 100_000;
+        let (mut sum, mut sum_squared) = (0.0, 0.0);
+        for _ in 0..n {
+            let (x, y) = (random_range_f64(-h, h), random_range_f64(-h, h));
+            let r = Ray::new(cam.lookfrom, Vec3::new(x, y, 1.0));
+            let radiance = integrator.li(cam, &r, world).y();
+            sum += radiance;
+            sum_squared += radiance * radiance;
+        }
+        if let Some(splats) = integrator.splats() {
+            sum += splats.iter().map(|splat| splat.y()).sum::<f64>();
+        }
+        let mean = sum / n as f64;
+        let variance = sum_squared / n as f64 - mean * mean;
+        (mean, f64::sqrt(variance / n as f64))
+    }
+
+    #[test]
+    fn matches_the_path_tracer() {
+        let (cam, world) = cornell_box();
+        let (expected, expected_error) = image_mean(&cam, &PathTracer, &world);
+        let (mean, error) = image_mean(&cam, &BidirectionalPathTracer::new(), &world);
+        // The spread of the splats isn't counted, so allow a little more.
+        let sigma = f64::sqrt(expected_error * expected_error + error * error);
+        assert!(
+            (mean - expected).abs() < 6.0 * sigma,
+            "{mean} {expected} {sigma}"
+        );
+        // Tight enough to notice the splats going missing, which carry about a
+        // fifth of the light.
+        assert!(6.0 * sigma < 0.1 * expected, "{sigma}");
+    }
+
+    #[test]
+    fn russian_roulette_is_unbiased() {
+        let (mut cam, world) = cornell_box();
+        // Deep enough that long paths, which roulette ends, carry some light.
+        cam.max_depth = 20;
+        cam.russian_roulette_depth = None;
+        let (expected, expected_error) = image_mean(&cam, &BidirectionalPathTracer::new(), &world);
+        cam.russian_roulette_depth = Some(1);
+        let (mean, error) = image_mean(&cam, &BidirectionalPathTracer::new(), &world);
+        let sigma = f64::sqrt(expected_error * expected_error + error * error);
+        // Survivors not being boosted would lose around seven sigma of light.
+        assert!(
+            (mean - expected).abs() < 5.0 * sigma,
+            "{mean} {expected} {sigma}"
+        );
+    }
+}
@@ -2,9 +2,20 @@ use crate::aabb::AABB;
 use crate::hit::{Hit, Hittables};
 use crate::interval::Interval;
 use crate::utils::random_range_int;
+use std::cell::Cell;
 use std::cmp::Ordering;
 use std::sync::Arc;
 
+thread_local! {
+    // Number of BVH nodes visited by this thread, for visualizing traversal cost.
+    static NODES_VISITED: Cell<u64> = const { Cell::new(0) };
+}
+
+// Returns the number of BVH nodes the current thread has visited so far.
+pub fn nodes_visited() -> u64 {
+    NODES_VISITED.with(|n| n.get())
+}
+
 pub struct BVHNode {
     left: Arc<dyn Hit>,
     right: Arc<dyn Hit>,
@@ -73,6 +84,7 @@ impl Hit for BVHNode {
         ray_t: crate::interval::Interval,
         rec: &mut crate::hit::HitRecord,
     ) -> bool {
+        NODES_VISITED.with(|n| n.set(n.get() + 1));
         if !self.bbox.hit(r, ray_t) {
             return false;
         }
@@ -1,14 +1,46 @@
+use std::fs;
 use std::io;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
 
-use indicatif::ParallelProgressIterator;
-use rayon::iter::{IntoParallelIterator, ParallelIterator};
+use indicatif::{ParallelProgressIterator, ProgressBar};
+use rayon::iter::{ParallelBridge, ParallelIterator};
 
+use crate::adaptive::AdaptiveSampling;
+use crate::aov::Aov;
+use crate::background::Background;
+use crate::checkpoint::Checkpoint;
 use crate::color::{Color, write_color};
+use crate::denoise::Denoiser;
+use crate::distributed::{self, Coordinator, Role};
+use crate::environment::Environment;
+use crate::film::{FilmPixel, FilteredPixel, FilteredTile};
+use crate::filter::{Filter, ReconstructionFilter};
 use crate::hit::{Hit, HitRecord};
+use crate::image::Image;
+use crate::integrator::{Integrator, PathTracer};
 use crate::interval::Interval;
+use crate::light::{Light, Lights, power_heuristic};
 use crate::ray::Ray;
-use crate::utils::random_f64;
-use crate::vec3::{Vec3, cross, unit_vector};
+use crate::sampler::{self, Sampler};
+use crate::tile::{Tile, TileOrder, Tiles};
+use crate::tonemap::{ToneMap, ToneMapping, TransferFunction};
+use crate::utils::{self, random_2d, random_f64};
+use crate::vec3::{Vec3, cross, dot, unit_vector};
+
+// Bounces after which paths are subject to Russian roulette, unless a scene
+// picks its own.
+const DEFAULT_RUSSIAN_ROULETTE_DEPTH: u32 = 3;
+
+// A point on the lens that a scene point was connected to by sample_importance.
+pub struct CameraSample {
+    pub origin: Vec3,
+    // The pixel (column, row) that the connection lands in.
+    pub pixel: (usize, usize),
+    // How much light arriving along the connection adds to the pixel, per
+    // sample.
+    pub weight: f64,
+}
 
 #[derive(Default)]
 pub struct Camera {
@@ -16,13 +48,80 @@ pub struct Camera {
     pub image_width: usize,
     pub samples_per_pixel: u32,
     pub max_depth: u32,
+    // Number of bounces after which paths are randomly terminated based on how
+    // much light they can still carry (Russian roulette). None disables it, so
+    // every path runs until max_depth. It is on by default, which leaves
+    // max_depth as a safety limit for the rare paths that keep carrying light.
+    pub russian_roulette_depth: Option<u32>,
     pub vfov: f64,
     pub lookfrom: Vec3,
     pub lookat: Vec3,
     pub vup: Vec3,
     pub defocus_angle: f64,
     pub focus_dist: f64,
-    pub background: Color,
+    // What escaping rays see. Defaults to black.
+    pub background: Option<Arc<dyn Background>>,
+    // What rays leaving the camera directly see, if it should differ from what
+    // lights the scene.
+    pub camera_background: Option<Arc<dyn Background>>,
+    // Lights that can't be hit by rays and are sampled explicitly at each bounce.
+    pub lights: Lights,
+    // Light surrounding the scene, such as an HDR image or a sky model. When set,
+    // it replaces the background and is also sampled as a light.
+    pub environment: Option<Arc<dyn Environment>>,
+    // The algorithm used to compute the light arriving along camera rays.
+    // Defaults to a PathTracer.
+    pub integrator: Option<Arc<dyn Integrator>>,
+    // Extra passes to render alongside the image, each written to its own PFM
+    // file once rendering finishes.
+    pub aovs: Vec<(Aov, String)>,
+    // Filter run over the rendered image to remove noise, guided by its albedo
+    // and normal passes.
+    pub denoiser: Option<Denoiser>,
+    // When set, pixels stop sampling once converged, and samples_per_pixel is
+    // only the most samples a pixel takes.
+    pub adaptive: Option<AdaptiveSampling>,
+    // Renders in passes of the given number of samples per pixel, writing the
+    // image so far to the given file after each pass.
+    pub progressive: Option<(u32, String)>,
+    // Saves a checkpoint of the render to the given file after every given
+    // number of samples per pixel.
+    pub checkpoint: Option<(u32, String)>,
+    // Checkpoint to continue rendering from, adding samples until pixels have
+    // samples_per_pixel.
+    pub resume: Option<String>,
+    // Where the random numbers for each pixel sample come from. Defaults to
+    // independent random numbers.
+    pub sampler: Option<Arc<dyn Sampler>>,
+    // Makes renders repeatable: each pixel sample draws its random numbers from
+    // a generator seeded by this, the pixel and the sample's index, so results
+    // don't depend on which thread renders what. None seeds from entropy.
+    pub seed: Option<u64>,
+    // Renders for about this long instead of to samples_per_pixel, adding passes
+    // of samples for as long as they are expected to finish in time. Time spent
+    // before rendering, such as building a photon map, counts towards it.
+    pub time_limit: Option<Duration>,
+    // How the image is divided into tiles for rendering. Defaults to 16 pixel
+    // tiles spiralling out from the middle.
+    pub tiles: Tiles,
+    // Renders only this rectangle of pixels of the full image, seen exactly as
+    // it would be in the full image. The output is just the rectangle, unless
+    // `composite` is set. Light that integrators trace onto the image only
+    // comes from the rectangle's samples, so it is noisier than in a full
+    // render.
+    pub crop: Option<Tile>,
+    // PPM image of the full frame that the cropped render is pasted into, such
+    // as an earlier render of the same view, to output the full frame.
+    pub composite: Option<String>,
+    // Renders the image across several processes, as their coordinator or as
+    // one of their workers. Workers output nothing themselves.
+    pub distributed: Option<Role>,
+    // How samples are combined into pixels. None averages each pixel's own
+    // samples, the same as a box filter half a pixel wide. Pixels at the edge
+    // of a crop only get samples from inside it.
+    pub filter: Option<ReconstructionFilter>,
+    // How the rendered radiance is turned into the image's colors.
+    pub tone_mapping: ToneMapping,
 
     image_height: usize,
     center: Vec3,
@@ -30,8 +129,6 @@ pub struct Camera {
     // pixel gaps
     pixel_delta_u: Vec3,
     pixel_delta_v: Vec3,
-    // normalization factor for samples
-    pixel_samples_scale: f64,
     // camera frame basis vectors
     u: Vec3,
     v: Vec3,
@@ -43,7 +140,10 @@ pub struct Camera {
 // builder fns
 impl Camera {
     pub fn builder() -> Self {
-        Self::default()
+        Self {
+            russian_roulette_depth: Some(DEFAULT_RUSSIAN_ROULETTE_DEPTH),
+            ..Self::default()
+        }
     }
 
     pub fn with_aspect_ratio(mut self, aspect_ratio: f64) -> Self {
@@ -66,6 +166,17 @@ impl Camera {
         self
     }
 
+    pub fn with_russian_roulette(mut self, min_bounces: u32) -> Self {
+        self.russian_roulette_depth = Some(min_bounces);
+        self
+    }
+
+    // Lets every path run until max_depth.
+    pub fn without_russian_roulette(mut self) -> Self {
+        self.russian_roulette_depth = None;
+        self
+    }
+
     pub fn with_vfov(mut self, vfov: f64) -> Self {
         self.vfov = vfov;
         self
@@ -96,8 +207,132 @@ impl Camera {
         self
     }
 
-    pub fn with_background(mut self, background: Color) -> Self {
-        self.background = background;
+    pub fn with_background(mut self, background: impl Background + 'static) -> Self {
+        self.background = Some(Arc::new(background));
+        self
+    }
+
+    pub fn with_camera_background(mut self, background: impl Background + 'static) -> Self {
+        self.camera_background = Some(Arc::new(background));
+        self
+    }
+
+    pub fn with_lights(mut self, lights: Lights) -> Self {
+        self.lights = lights;
+        self
+    }
+
+    pub fn with_environment(mut self, environment: Arc<dyn Environment>) -> Self {
+        self.environment = Some(environment);
+        self
+    }
+
+    pub fn with_integrator(mut self, integrator: impl Integrator + 'static) -> Self {
+        self.integrator = Some(Arc::new(integrator));
+        self
+    }
+
+    pub fn with_aov(mut self, aov: Aov, path: impl Into<String>) -> Self {
+        self.aovs.push((aov, path.into()));
+        self
+    }
+
+    pub fn with_denoiser(mut self, denoiser: Denoiser) -> Self {
+        self.denoiser = Some(denoiser);
+        self
+    }
+
+    // Enables adaptive sampling: pixels take between min_samples and
+    // samples_per_pixel samples, stopping once their estimated error (in display
+    // brightness, from zero to one) drops below the threshold.
+    pub fn with_adaptive_sampling(mut self, min_samples: u32, threshold: f64) -> Self {
+        self.adaptive = Some(AdaptiveSampling::new(min_samples, threshold));
+        self
+    }
+
+    pub fn with_progressive(mut self, samples_per_pass: u32, path: impl Into<String>) -> Self {
+        self.progressive = Some((samples_per_pass, path.into()));
+        self
+    }
+
+    pub fn with_checkpoint(mut self, samples_per_pass: u32, path: impl Into<String>) -> Self {
+        self.checkpoint = Some((samples_per_pass, path.into()));
+        self
+    }
+
+    pub fn with_resume(mut self, path: impl Into<String>) -> Self {
+        self.resume = Some(path.into());
+        self
+    }
+
+    pub fn with_time_limit(mut self, time_limit: Duration) -> Self {
+        self.time_limit = Some(time_limit);
+        self
+    }
+
+    pub fn with_tiles(mut self, size: usize, order: TileOrder) -> Self {
+        self.tiles = Tiles { size, order };
+        self
+    }
+
+    // Renders only the rectangle of pixels with its top left corner at column x,
+    // row y of the full image. The rectangle must not be empty and must lie
+    // within the image, or initializing the camera panics.
+    pub fn with_crop(mut self, x: usize, y: usize, width: usize, height: usize) -> Self {
+        let (Some(x1), Some(y1)) = (x.checked_add(width), y.checked_add(height)) else {
+            panic!("Crop window {x},{y},{width},{height} is out of range.");
+        };
+        self.crop = Some(Tile {
+            x0: x,
+            y0: y,
+            x1,
+            y1,
+        });
+        self
+    }
+
+    pub fn with_composite(mut self, path: impl Into<String>) -> Self {
+        self.composite = Some(path.into());
+        self
+    }
+
+    pub fn with_distributed(mut self, role: Role) -> Self {
+        self.distributed = Some(role);
+        self
+    }
+
+    // Combines samples into pixels with the given filter, reaching `radius`
+    // pixels from each sample. Radii below half a pixel are raised to it, so
+    // that every pixel still gets the samples taken within it.
+    pub fn with_filter(mut self, filter: Filter, radius: f64) -> Self {
+        self.filter = Some(ReconstructionFilter::new(filter, radius));
+        self
+    }
+
+    // Brightens (positive) or darkens (negative) the image by the given number
+    // of stops before tone mapping.
+    pub fn with_exposure(mut self, exposure: f64) -> Self {
+        self.tone_mapping.exposure = exposure;
+        self
+    }
+
+    pub fn with_tone_map(mut self, tone_map: ToneMap) -> Self {
+        self.tone_mapping.tone_map = tone_map;
+        self
+    }
+
+    pub fn with_transfer_function(mut self, transfer: TransferFunction) -> Self {
+        self.tone_mapping.transfer = transfer;
+        self
+    }
+
+    pub fn with_sampler(mut self, sampler: impl Sampler + 'static) -> Self {
+        self.sampler = Some(Arc::new(sampler));
+        self
+    }
+
+    pub fn with_seed(mut self, seed: u64) -> Self {
+        self.seed = Some(seed);
         self
     }
 
@@ -116,7 +351,22 @@ impl Camera {
             self.image_height
         };
 
-        self.pixel_samples_scale = 1.0 / self.samples_per_pixel as f64;
+        if let Some(crop) = self.crop {
+            assert!(
+                crop.x0 < crop.x1
+                    && crop.y0 < crop.y1
+                    && crop.x1 <= self.image_width
+                    && crop.y1 <= self.image_height,
+                "Crop window {},{},{},{} doesn't fit in the {}x{} image.",
+                crop.x0,
+                crop.y0,
+                crop.width(),
+                crop.height(),
+                self.image_width,
+                self.image_height
+            );
+        }
+
         self.center = self.lookfrom;
 
         let theta = f64::to_radians(self.vfov);
@@ -143,64 +393,660 @@ impl Camera {
 
     // Outputs the image (in PPM format) to standard output.
     pub fn render(&mut self, world: &dyn Hit) {
+        let started = Instant::now();
         self.initialize();
-        let total = self.image_width * self.image_height;
+        let rect = self.film_rect();
+        let total = rect.width() * rect.height();
 
-        eprintln!("Rendering...");
-        print!("P3\n{} {}\n255\n", self.image_width, self.image_height);
-        let pixels: Vec<Color> = (0..total)
-            .into_par_iter()
-            .progress_count(total as u64)
-            .map(|k| {
-                let j = k / self.image_width;
-                let i = k % self.image_width;
-                let mut pixel_color = Color::new(0.0, 0.0, 0.0);
-                for _ in 0..self.samples_per_pixel {
-                    let r = self.get_ray(i, j);
-                    pixel_color += self.ray_color(&r, self.max_depth, world);
+        let integrator = self
+            .integrator
+            .clone()
+            .unwrap_or_else(|| Arc::new(PathTracer));
+        integrator.preprocess(self, world);
+
+        if let Some(Role::Worker { address }) = &self.distributed {
+            eprintln!("Rendering for {address}...");
+            match distributed::run_worker(self, address, integrator.as_ref(), world) {
+                Ok(()) => eprintln!("Done."),
+                Err(err) => eprintln!("Worker failed: {err}"),
+            }
+            return;
+        }
+
+        let background = self.composite.as_ref().and_then(|path| {
+            let image = Image::load(path)
+                .map_err(|err| err.to_string())
+                .and_then(|image| {
+                    if (image.width(), image.height()) == (self.image_width, self.image_height) {
+                        Ok(image)
+                    } else {
+                        Err(format!(
+                            "it isn't {}x{}",
+                            self.image_width, self.image_height
+                        ))
+                    }
+                });
+            match image {
+                Ok(image) => Some(image),
+                Err(err) => {
+                    eprintln!("Can't composite into {path}, writing just the crop: {err}");
+                    None
+                }
+            }
+        });
+
+        let mut film = vec![FilmPixel::default(); total];
+        let mut filtered = self.filter.map(|_| vec![FilteredPixel::default(); total]);
+        let mut done = 0;
+        if let Some(path) = &self.resume {
+            match Checkpoint::load(path, self) {
+                Ok(checkpoint) => {
+                    eprintln!(
+                        "Resuming from {path} at {} samples per pixel.",
+                        checkpoint.samples_per_pixel
+                    );
+                    film = checkpoint.film;
+                    filtered = checkpoint.filtered;
+                    done = checkpoint.samples_per_pixel;
+                    if let Some(splats) = checkpoint.splats {
+                        integrator.add_splats(&splats);
+                    }
+                }
+                Err(err) => eprintln!("Failed to resume from {path}, starting over: {err}"),
+            }
+        }
+
+        let coordinator = match &self.distributed {
+            Some(Role::Coordinator { address, workers }) => {
+                match Coordinator::start(self, integrator.as_ref(), address, *workers) {
+                    Ok(coordinator) => Some(coordinator),
+                    Err(err) => {
+                        eprintln!("Failed to coordinate on {address}, rendering here: {err}");
+                        None
+                    }
                 }
-                pixel_color * self.pixel_samples_scale
+            }
+            _ => None,
+        };
+
+        // Render in passes, ending each wherever an image or checkpoint is due.
+        // Without a time limit the passes are known up front.
+        let passes = match self.time_limit {
+            Some(_) => 1,
+            None => std::iter::successors(self.next_pass_end(done, started, None), |&end| {
+                self.next_pass_end(end, started, None)
             })
-            .collect();
-        let mut out = io::BufWriter::new(io::stdout().lock());
-        for color in pixels {
-            write_color(&mut out, &color);
+            .count(),
+        };
+        let first_done = done;
+
+        let tiles = self.tiles.split(rect.width(), rect.height());
+        eprintln!("Rendering...");
+        let progress = ProgressBar::new(tiles.len() as u64 * passes as u64);
+        // Includes writing images and checkpoints between passes, so that they
+        // are budgeted for too.
+        let rendering_started = Instant::now();
+        let mut next_end = self.next_pass_end(done, started, None);
+        while let Some(end) = next_end {
+            match &coordinator {
+                Some(coordinator) => {
+                    let splats = coordinator.render_pass(
+                        self,
+                        integrator.as_ref(),
+                        world,
+                        &tiles,
+                        end,
+                        &mut film,
+                        filtered.as_deref_mut(),
+                        &progress,
+                    );
+                    if let Some(splats) = splats {
+                        integrator.add_splats(&splats);
+                    }
+                }
+                None => self.render_pass(
+                    integrator.as_ref(),
+                    world,
+                    &tiles,
+                    end,
+                    &mut film,
+                    filtered.as_deref_mut(),
+                    &progress,
+                ),
+            }
+            done = end;
+
+            let all_converged = self.adaptive.is_some_and(|adaptive| {
+                film.iter().all(|pixel| adaptive.converged(&pixel.estimate))
+            });
+            let sample_time = rendering_started.elapsed() / (done - first_done);
+            next_end = self
+                .next_pass_end(done, started, Some(sample_time))
+                .filter(|_| !all_converged);
+            if self.time_limit.is_some() && next_end.is_some() {
+                progress.inc_length(tiles.len() as u64);
+            }
+
+            let last = next_end.is_none();
+            if let Some(path) = self.due_path(&self.progressive, end, last) {
+                // Snapshots aren't denoised, which would take longer than a
+                // short pass.
+                let pixels = self.develop(&film, filtered.as_deref(), integrator.as_ref(), false);
+                let written = fs::File::create(path).and_then(|file| {
+                    self.write_image(
+                        &mut io::BufWriter::new(file),
+                        &pixels,
+                        background.as_ref(),
+                        integrator.as_ref(),
+                    )
+                });
+                if let Err(err) = written {
+                    eprintln!("\rFailed to write image to {path}: {err}");
+                }
+            }
+            if let Some(path) = self.due_path(&self.checkpoint, end, last) {
+                let splats = integrator.splats();
+                if let Err(err) = Checkpoint::save(
+                    path,
+                    self,
+                    end,
+                    &film,
+                    filtered.as_deref(),
+                    splats.as_deref(),
+                ) {
+                    eprintln!("\rFailed to save checkpoint to {path}: {err}");
+                }
+            }
+        }
+
+        if let Some(coordinator) = coordinator {
+            coordinator.finish();
         }
+        if self.time_limit.is_some() {
+            // Later passes, such as the sample count heatmap, are relative to
+            // the samples actually taken.
+            self.samples_per_pixel = done;
+            eprintln!(
+                "\rRendered {done} samples per pixel in {:.1}s.",
+                started.elapsed().as_secs_f64()
+            );
+        }
+        if self.adaptive.is_some() {
+            let samples_taken: u64 = film.iter().map(|p| p.samples as u64).sum();
+            eprintln!(
+                "\rAverage samples per pixel: {:.1}",
+                samples_taken as f64 / total as f64
+            );
+        }
+        if self.denoiser.is_some() {
+            eprintln!("\rDenoising...");
+        }
+        let pixels = self.develop(&film, filtered.as_deref(), integrator.as_ref(), true);
+        self.write_image(
+            &mut io::BufWriter::new(io::stdout().lock()),
+            &pixels,
+            background.as_ref(),
+            integrator.as_ref(),
+        )
+        .unwrap();
         eprint!("\rDone.              \n");
+
+        for (aov, path) in &self.aovs {
+            let data = film
+                .iter()
+                .map(|p| p.aov.value(*aov, self.samples_per_pixel))
+                .collect();
+            let image = Image::new(rect.width(), rect.height(), data);
+            if let Err(err) = image.save_pfm(path) {
+                eprintln!("Failed to write {aov:?} pass to {path}: {err}");
+            }
+        }
     }
 
-    // Determines the color that the camera sees along this ray. This function calls itself
-    // recursively up to limit `depth` to account for reflection/refraction.
-    fn ray_color(&self, r: &Ray, depth: u32, world: &dyn Hit) -> Color {
-        if depth <= 0 {
-            return Color::default();
+    // Returns the number of samples per pixel the pass after one ending at
+    // `done` should end at, or None if the render is finished. With a time
+    // limit, sample_time is how long each sample per pixel has taken so far.
+    fn next_pass_end(
+        &self,
+        done: u32,
+        started: Instant,
+        sample_time: Option<Duration>,
+    ) -> Option<u32> {
+        let end = match self.time_limit {
+            None => self.samples_per_pixel,
+            Some(limit) => {
+                let remaining = limit.saturating_sub(started.elapsed());
+                // Take at least one sample, then as many as are expected to fit
+                // in the remaining time. Passes at most double the samples, so
+                // the time per sample is measured again before committing to
+                // a long pass.
+                let samples = match sample_time {
+                    None => 1,
+                    Some(t) => (remaining.as_secs_f64() / t.as_secs_f64().max(1e-9)) as u32,
+                };
+                done.saturating_add(samples.min(done.max(1)))
+            }
+        };
+        let end = [&self.progressive, &self.checkpoint]
+            .iter()
+            .filter_map(|output| output.as_ref())
+            .map(|(interval, _)| (done / interval.max(&1) + 1) * interval.max(&1))
+            .fold(end, u32::min);
+        (end > done).then_some(end)
+    }
+
+    // Returns the file to write for an output done every so many samples per
+    // pixel, if one is due once pixels have `end` samples.
+    fn due_path<'a>(
+        &self,
+        output: &'a Option<(u32, String)>,
+        end: u32,
+        last: bool,
+    ) -> Option<&'a String> {
+        let (interval, path) = output.as_ref()?;
+        (end.is_multiple_of(*interval.max(&1)) || last).then_some(path)
+    }
+
+    // Samples the given tiles of the film until their pixels have `end` samples.
+    // Tiles are handed to threads in order, and each is copied out of the film,
+    // sampled, and written back once finished.
+    #[allow(clippy::too_many_arguments)]
+    pub fn render_pass(
+        &self,
+        integrator: &dyn Integrator,
+        world: &dyn Hit,
+        tiles: &[Tile],
+        end: u32,
+        film: &mut [FilmPixel],
+        filtered: Option<&mut [FilteredPixel]>,
+        progress: &ProgressBar,
+    ) {
+        let width = self.film_rect().width();
+        let framebuffer = Mutex::new((film, filtered));
+        tiles
+            .iter()
+            .par_bridge()
+            .progress_with(progress.clone())
+            .for_each(|tile| {
+                let indices = || tile.pixels(width);
+                let mut pixels: Vec<FilmPixel> = {
+                    let (film, _) = &*framebuffer.lock().unwrap();
+                    indices().map(|k| film[k].clone()).collect()
+                };
+                let filtered_tile = self.render_tile(integrator, world, tile, &mut pixels, end);
+                let (film, filtered) = &mut *framebuffer.lock().unwrap();
+                for (k, pixel) in indices().zip(pixels) {
+                    film[k] = pixel;
+                }
+                if let (Some(filtered), Some(filtered_tile)) =
+                    (filtered.as_deref_mut(), filtered_tile)
+                {
+                    filtered_tile.add_to(filtered, width);
+                }
+            });
+    }
+
+    // Samples the pixels of a tile, copied out of the film into `pixels`, until
+    // they have `end` samples. Pixels are sampled one after another, as tiles
+    // are what is rendered in parallel. With a reconstruction filter, also returns what
+    // the samples add to the filtered pixels around them.
+    pub fn render_tile(
+        &self,
+        integrator: &dyn Integrator,
+        world: &dyn Hit,
+        tile: &Tile,
+        pixels: &mut [FilmPixel],
+        end: u32,
+    ) -> Option<FilteredTile> {
+        let rect = self.film_rect();
+        let mut filtered = self.filter.map(|filter| {
+            let reach = filter.radius.ceil() as usize;
+            FilteredTile::new(Tile {
+                x0: tile.x0.saturating_sub(reach),
+                y0: tile.y0.saturating_sub(reach),
+                x1: usize::min(tile.x1 + reach, rect.width()),
+                y1: usize::min(tile.y1 + reach, rect.height()),
+            })
+        });
+        for (pixel, k) in pixels.iter_mut().zip(tile.pixels(rect.width())) {
+            self.sample_pixel(integrator, world, k, pixel, end, filtered.as_mut());
+        }
+        filtered
+    }
+
+    // Takes samples of the pixel at index k until it has `end` of them, or has
+    // converged if sampling adaptively. Each sample is also added to `filtered`
+    // with the camera's reconstruction filter, if given.
+    fn sample_pixel(
+        &self,
+        integrator: &dyn Integrator,
+        world: &dyn Hit,
+        k: usize,
+        pixel: &mut FilmPixel,
+        end: u32,
+        mut filtered: Option<&mut FilteredTile>,
+    ) {
+        let rect = self.film_rect();
+        let i = rect.x0 + k % rect.width();
+        let j = rect.y0 + k / rect.width();
+        let record_aovs = self.records_aovs();
+        let split_paths = self.splits_light_paths();
+        let seed = sampler::hash(&[self.seed.unwrap_or(0), i as u64, j as u64]);
+        while pixel.samples < end {
+            if self
+                .adaptive
+                .is_some_and(|adaptive| adaptive.converged(&pixel.estimate))
+            {
+                break;
+            }
+            self.seed_random(&[i as u64, j as u64, pixel.samples as u64]);
+            let _sample = self
+                .sampler
+                .clone()
+                .map(|s| sampler::begin_sample(s, seed, pixel.samples, self.samples_per_pixel));
+            let offset = Self::sample_square();
+            let r = self.get_ray(i, j, offset);
+            if record_aovs {
+                pixel.aov.add_hit(&r, world);
+            }
+            let color = if split_paths {
+                let paths = integrator.li_paths(self, &r, world);
+                pixel.aov.add_paths(paths);
+                paths.total()
+            } else {
+                integrator.li(self, &r, world)
+            };
+            if record_aovs {
+                pixel.aov.add_radiance(color);
+            }
+            pixel.color += color;
+            pixel.samples += 1;
+            pixel.estimate.add(color);
+            if let (Some(filtered), Some(filter)) = (filtered.as_deref_mut(), &self.filter) {
+                // Pixel centers are half a pixel in from their corners.
+                let x = (k % rect.width()) as f64 + 0.5 + offset.x();
+                let y = (k / rect.width()) as f64 + 0.5 + offset.y();
+                filtered.add_sample(filter, x, y, color);
+            }
         }
+    }
+
+    // Turns the samples taken so far into the final image colors: averages each
+    // pixel's samples (weighted by the reconstruction filter, if any), adds any
+    // light the integrator traced onto the image, and denoises if `denoise` is
+    // set and the camera has a denoiser.
+    fn develop(
+        &self,
+        film: &[FilmPixel],
+        filtered: Option<&[FilteredPixel]>,
+        integrator: &dyn Integrator,
+        denoise: bool,
+    ) -> Vec<Color> {
+        let pixels: Vec<Color> = match filtered {
+            Some(filtered) => film
+                .iter()
+                .zip(filtered)
+                .map(|(pixel, filtered)| filtered.value().unwrap_or_else(|| pixel.mean()))
+                .collect(),
+            None => film.iter().map(FilmPixel::mean).collect(),
+        };
 
-        let mut rec = HitRecord::default();
+        // Light traced onto the image is shared by all pixels of the full image,
+        // so it's scaled by the average number of samples taken, as if every
+        // pixel had been rendered.
+        let rect = self.film_rect();
+        let samples_taken: u64 = film.iter().map(|p| p.samples as u64).sum();
+        let splat_scale =
+            (self.image_width * self.image_height) as f64 / samples_taken.max(1) as f64;
+        let pixels: Vec<Color> = match integrator.splats() {
+            Some(splats) => pixels
+                .iter()
+                .zip(rect.pixels(self.image_width))
+                .map(|(&color, k)| color + splats[k] * splat_scale)
+                .collect(),
+            None => pixels,
+        };
+
+        match self.denoiser.as_ref().filter(|_| denoise) {
+            Some(denoiser) => {
+                let pass = |aov| {
+                    film.iter()
+                        .map(|p| p.aov.value(aov, self.samples_per_pixel))
+                        .collect::<Vec<_>>()
+                };
+                denoiser.denoise(
+                    rect.width(),
+                    rect.height(),
+                    &pixels,
+                    &pass(Aov::Variance),
+                    &pass(Aov::Albedo),
+                    &pass(Aov::Normal),
+                )
+            }
+            None => pixels,
+        }
+    }
+
+    // Tone maps the image and writes it in PPM format, pasted into the
+    // background image if one is given. Images of integrators that output
+    // display values are written as they are.
+    fn write_image(
+        &self,
+        out: &mut impl io::Write,
+        pixels: &[Color],
+        background: Option<&Image>,
+        integrator: &dyn Integrator,
+    ) -> io::Result<()> {
+        let display = |color| {
+            if integrator.outputs_display_values() {
+                color
+            } else {
+                self.tone_mapping.apply(color)
+            }
+        };
+        let rect = self.film_rect();
+        let Some(background) = background else {
+            write!(out, "P3\n{} {}\n255\n", rect.width(), rect.height())?;
+            for &color in pixels {
+                write_color(out, &display(color));
+            }
+            return out.flush();
+        };
+
+        write!(out, "P3\n{} {}\n255\n", self.image_width, self.image_height)?;
+        for j in 0..self.image_height {
+            for i in 0..self.image_width {
+                // The background's pixels are already display values.
+                let color = if rect.contains(i, j) {
+                    display(pixels[(j - rect.y0) * rect.width() + i - rect.x0])
+                } else {
+                    background.pixel(i, j)
+                };
+                write_color(out, &color);
+            }
+        }
+        out.flush()
+    }
 
-        if !world.hit(
-            r,
-            Interval {
-                min: 0.001,
-                max: f64::INFINITY,
+    // Determines the color seen by a ray escaping the scene. Camera rays may see a
+    // different background than the one lighting the scene.
+    // `scatter_pdf` is the density with which a material sampled this ray, or None
+    // for camera rays and specular bounces.
+    pub fn background_color(&self, r: &Ray, camera_ray: bool, scatter_pdf: Option<f64>) -> Color {
+        if camera_ray && let Some(background) = &self.camera_background {
+            return background.value(r);
+        }
+        match (&self.environment, &self.background) {
+            // After a diffuse bounce the environment was also sampled directly,
+            // so weight the two estimates against each other.
+            (Some(env), _) => match scatter_pdf {
+                Some(pdf) => {
+                    env.radiance(r.direction()) * power_heuristic(pdf, env.pdf(r.direction()))
+                }
+                None => env.radiance(r.direction()) + env.unsampled_radiance(r.direction()),
             },
-            &mut rec,
+            (None, Some(background)) => background.value(r),
+            (None, None) => Color::default(),
+        }
+    }
+
+    // Returns the weight for light emitted by the surface that ray r hit, which
+    // may also have been reached by sampling one of the camera's lights.
+    // `scatter_pdf` is as for background_color.
+    pub fn emission_weight(&self, r: &Ray, rec: &HitRecord, scatter_pdf: Option<f64>) -> f64 {
+        let Some(pdf) = scatter_pdf else {
+            return 1.0;
+        };
+        let light_pdf: f64 = self.lights.lights.iter().map(|l| l.hit_pdf(r, rec)).sum();
+        power_heuristic(pdf, light_pdf)
+    }
+
+    // Returns all lights to sample, including the environment.
+    pub fn all_lights(&self) -> impl Iterator<Item = &dyn Light> {
+        let environment = self.environment.as_deref().map(|env| env as &dyn Light);
+        self.lights
+            .lights
+            .iter()
+            .map(|l| l.as_ref())
+            .chain(environment)
+    }
+
+    // Estimates the light arriving at the hit point directly from the camera's
+    // lights and environment (next event estimation), weighted by the surface's
+    // reflectance.
+    pub fn sample_lights(
+        &self,
+        r: &Ray,
+        rec: &HitRecord,
+        attenuation: Color,
+        world: &dyn Hit,
+    ) -> Color {
+        self.all_lights()
+            .map(|light| self.sample_light(light, r, rec, attenuation, world))
+            .fold(Color::default(), |acc, c| acc + c)
+    }
+
+    // Estimates the light arriving at the hit point directly from a single light.
+    pub fn sample_light(
+        &self,
+        light: &dyn Light,
+        r: &Ray,
+        rec: &HitRecord,
+        attenuation: Color,
+        world: &dyn Hit,
+    ) -> Color {
+        let Some(sample) = light.sample(rec.p) else {
+            return Color::default();
+        };
+        let shadow_ray = Ray::new(rec.p, sample.direction).with_time(r.time());
+        let pdf = rec.mat.scattering_pdf(r, rec, &shadow_ray);
+        if pdf <= 0.0 {
+            return Color::default();
+        }
+        let mut shadow_rec = HitRecord::default();
+        if world.hit(
+            &shadow_ray,
+            Interval::new(0.001, sample.distance - 0.001),
+            &mut shadow_rec,
         ) {
-            return self.background;
+            return Color::default();
+        }
+        let weight = power_heuristic(sample.pdf, pdf);
+        attenuation * sample.radiance * pdf * weight
+    }
+
+    // Seeds the current thread's random numbers from the camera's seed and the
+    // given values identifying what is being sampled, if the camera has a seed.
+    pub fn seed_random(&self, stream: &[u64]) {
+        if let Some(seed) = self.seed {
+            utils::seed_random(sampler::hash(&[seed, sampler::hash(stream)]));
+        }
+    }
+
+    pub fn image_height(&self) -> usize {
+        self.image_height
+    }
+
+    // Returns whether pixels keep the sums needed for AOV passes, which the
+    // denoiser uses too.
+    pub fn records_aovs(&self) -> bool {
+        !self.aovs.is_empty() || self.denoiser.is_some()
+    }
+
+    // Returns whether the integrator is asked to split light by how it got to
+    // the camera, for passes that need it.
+    pub fn splits_light_paths(&self) -> bool {
+        self.aovs.iter().any(|(aov, _)| aov.needs_light_paths())
+    }
+
+    // Returns the rectangle of pixels being rendered: the crop, or the whole
+    // image.
+    pub fn film_rect(&self) -> Tile {
+        self.crop.unwrap_or(Tile {
+            x0: 0,
+            y0: 0,
+            x1: self.image_width,
+            y1: self.image_height,
+        })
+    }
+
+    // Returns the solid angle density with which camera rays leave in the given
+    // direction, picking points uniformly over the image.
+    pub fn pdf_direction(&self, direction: Vec3) -> f64 {
+        let cos_theta = dot(unit_vector(direction), -self.w);
+        if cos_theta <= 0.0 {
+            return 0.0;
+        }
+        1.0 / (self.film_area() * cos_theta * cos_theta * cos_theta)
+    }
+
+    // Picks a point on the lens to connect point p to, for tracing light onto the
+    // image. Returns None if p is outside the camera's view.
+    pub fn sample_importance(&self, p: Vec3) -> Option<CameraSample> {
+        let origin = if self.defocus_angle <= 0.0 {
+            self.center
+        } else {
+            self.defocus_disk_sample()
+        };
+        let to_p = p - origin;
+        let distance = to_p.length();
+        if distance <= 0.0 {
+            return None;
+        }
+        let direction = to_p / distance;
+        let cos_theta = dot(direction, -self.w);
+        if cos_theta <= 0.0 {
+            return None;
         }
 
-        let mut scattered = Ray::default();
-        let mut attenuation = Color::default();
-        let color_from_emission = rec.mat.emitted(rec.u, rec.v, rec.p);
-        if !rec.mat.scatter(r, &rec, &mut attenuation, &mut scattered) {
-            return color_from_emission;
+        // Find where the ray crosses the focus plane, in pixel units.
+        let focus = origin + direction * (self.focus_dist / cos_theta);
+        let corner = self.pixel00_loc - (self.pixel_delta_u + self.pixel_delta_v) * 0.5;
+        let x = dot(focus - corner, self.pixel_delta_u) / self.pixel_delta_u.length_squared();
+        let y = dot(focus - corner, self.pixel_delta_v) / self.pixel_delta_v.length_squared();
+        if x < 0.0 || y < 0.0 || x >= self.image_width as f64 || y >= self.image_height as f64 {
+            return None;
         }
-        let color_from_scatter = attenuation * self.ray_color(&scattered, depth - 1, world);
-        color_from_emission + color_from_scatter
+
+        // The camera's importance over the density of picking the lens point,
+        // seen from p. The lens area cancels out.
+        let weight = 1.0 / (self.film_area() * cos_theta.powi(3) * distance * distance);
+        Some(CameraSample {
+            origin,
+            pixel: (x as usize, y as usize),
+            weight,
+        })
+    }
+
+    // Returns the area of the image projected onto a plane at unit distance.
+    fn film_area(&self) -> f64 {
+        let width = self.pixel_delta_u.length() * self.image_width as f64;
+        let height = self.pixel_delta_v.length() * self.image_height as f64;
+        width * height / (self.focus_dist * self.focus_dist)
     }
 
-    fn get_ray(&self, i: usize, j: usize) -> Ray {
-        let offset = Self::sample_square();
+    // Returns a ray through the given offset from the center of pixel (i, j).
+    fn get_ray(&self, i: usize, j: usize, offset: Vec3) -> Ray {
         let pixel_sample = self.pixel00_loc
             + (self.pixel_delta_u * (i as f64 + offset.x()))
             + (self.pixel_delta_v * (j as f64 + offset.y()));
@@ -215,7 +1061,8 @@ impl Camera {
     }
 
     fn sample_square() -> Vec3 {
-        Vec3::new(random_f64() - 0.5, random_f64() - 0.5, 0.0)
+        let (x, y) = random_2d();
+        Vec3::new(x - 0.5, y - 0.5, 0.0)
     }
 
     fn defocus_disk_sample(&self) -> Vec3 {
@@ -223,3 +1070,248 @@ impl Camera {
         return self.center + (self.defocus_disk_u * p[0]) + (self.defocus_disk_v * p[1]);
     }
 }
+
+#[cfg(test)]
+pub mod tests {
+    use super::*;
+    use crate::{
+        background::GradientBackground,
+        bdpt::BidirectionalPathTracer,
+        filter::Filter,
+        hit::Hittables,
+        light::AreaLight,
+        material::{DiffuseLight, Lambertian},
+        quad::Quad,
+        sphere::Sphere,
+    };
+
+    // An unbuilt 8x4 camera at the origin looking down -z, for tests to add
+    // their settings to.
+    pub fn test_camera() -> Camera {
+        Camera::builder()
+            .with_aspect_ratio(2.0)
+            .with_image_width(8)
+            .with_vfov(90.0)
+            .with_lookat(Vec3::new(0.0, 0.0, -1.0))
+            .with_vup(Vec3::new(0.0, 1.0, 0.0))
+            .with_focus_dist(1.0)
+    }
+
+    // A sphere on a floor under a square light, seen from in front. Returns the
+    // scene's lights and the scene.
+    pub fn lit_sphere() -> (Lights, Hittables) {
+        let grey = Arc::new(Lambertian::new_color(Color::new(0.5, 0.5, 0.5)));
+        let light_quad = Arc::new(Quad::new(
+            Vec3::new(-0.5, 2.0, -2.5),
+            Vec3::new(1.0, 0.0, 0.0),
+            Vec3::new(0.0, 0.0, 1.0),
+            Arc::new(DiffuseLight::new_color(Color::new(8.0, 8.0, 8.0))),
+        ));
+        let mut world = Hittables::default();
+        world.add(Arc::new(Sphere::new(
+            Vec3::new(0.0, 0.0, -2.0),
+            0.5,
+            grey.clone(),
+        )));
+        world.add(Arc::new(Quad::new(
+            Vec3::new(-4.0, -0.5, 0.0),
+            Vec3::new(8.0, 0.0, 0.0),
+            Vec3::new(0.0, 0.0, -8.0),
+            grey,
+        )));
+        world.add(light_quad.clone());
+        let mut lights = Lights::default();
+        lights.add(Arc::new(AreaLight::new(light_quad)));
+        (lights, world)
+    }
+
+    // Renders the whole image in memory the way `render` does, returning its
+    // colors before tone mapping.
+    fn render_pixels(cam: &Camera, integrator: &dyn Integrator, world: &dyn Hit) -> Vec<Color> {
+        integrator.preprocess(cam, world);
+        let rect = cam.film_rect();
+        let mut film = vec![FilmPixel::default(); rect.width() * rect.height()];
+        let mut filtered = cam
+            .filter
+            .map(|_| vec![FilteredPixel::default(); film.len()]);
+        let tiles = cam.tiles.split(rect.width(), rect.height());
+        cam.render_pass(
+            integrator,
+            world,
+            &tiles,
+            cam.samples_per_pixel,
+            &mut film,
+            filtered.as_deref_mut(),
+            &ProgressBar::hidden(),
+        );
+        cam.develop(&film, filtered.as_deref(), integrator, true)
+    }
+
+    // Seeded renders come out the same to the bit however many threads render
+    // them, including light that BDPT splats onto the image from any thread.
+    #[test]
+    fn seeded_renders_dont_depend_on_threads() {
+        let (lights, world) = lit_sphere();
+        let cam = test_camera()
+            .with_samples_per_pixel(8)
+            .with_max_depth(4)
+            .with_lights(lights)
+            .with_tiles(2, TileOrder::Spiral)
+            .with_seed(5)
+            .build();
+        let render = |threads: usize, bdpt: bool| {
+            let pool = rayon::ThreadPoolBuilder::new()
+                .num_threads(threads)
+                .build()
+                .unwrap();
+            let integrator: Box<dyn Integrator> = if bdpt {
+                Box::new(BidirectionalPathTracer::new())
+            } else {
+                Box::new(PathTracer)
+            };
+            let pixels = pool.install(|| render_pixels(&cam, integrator.as_ref(), &world));
+            let splatted = integrator
+                .splats()
+                .is_some_and(|splats| splats.iter().any(|splat| splat.length_squared() > 0.0));
+            assert_eq!(splatted, bdpt);
+            pixels
+        };
+        for bdpt in [false, true] {
+            let (one, four) = (render(1, bdpt), render(4, bdpt));
+            for (a, b) in one.iter().zip(&four) {
+                assert_eq!(
+                    [a.x(), a.y(), a.z()].map(f64::to_bits),
+                    [b.x(), b.y(), b.z()].map(f64::to_bits),
+                    "bdpt: {bdpt}"
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn passes_continue_where_the_last_left_off() {
+        let mut world = Hittables::default();
+        world.add(Arc::new(Sphere::new(
+            Vec3::new(0.0, 0.0, -2.0),
+            1.0,
+            Arc::new(Lambertian::new_color(Color::new(0.5, 0.5, 0.5))),
+        )));
+        let cam = test_camera()
+            .with_samples_per_pixel(10)
+            .with_max_depth(4)
+            .with_background(GradientBackground::default())
+            .with_seed(7)
+            .build();
+        for k in 0..8 {
+            let mut at_once = FilmPixel::default();
+            cam.sample_pixel(&PathTracer, &world, k, &mut at_once, 10, None);
+            let mut in_passes = FilmPixel::default();
+            for end in [4, 8, 10] {
+                cam.sample_pixel(&PathTracer, &world, k, &mut in_passes, end, None);
+            }
+            assert_eq!(in_passes.samples, 10);
+            assert_eq!((at_once.color - in_passes.color).length_squared(), 0.0);
+        }
+    }
+
+    // The lit sphere scene, and an unbuilt seeded camera looking at it.
+    fn lit_sphere_camera() -> (Camera, Hittables) {
+        let (lights, world) = lit_sphere();
+        let cam = test_camera()
+            .with_samples_per_pixel(6)
+            .with_max_depth(4)
+            .with_lights(lights)
+            .with_seed(9);
+        (cam, world)
+    }
+
+    // A box filter half a pixel wide only sees each pixel's own samples, with
+    // equal weights.
+    #[test]
+    fn narrow_box_filter_averages_pixels() {
+        let (cam, world) = lit_sphere_camera();
+        let expected = render_pixels(&cam.build(), &PathTracer, &world);
+        let (cam, world) = lit_sphere_camera();
+        let filtered = render_pixels(
+            &cam.with_filter(Filter::Box, 0.5).build(),
+            &PathTracer,
+            &world,
+        );
+        for (a, b) in filtered.iter().zip(&expected) {
+            assert!((*a - *b).length() < 1e-6, "{a:?} {b:?}");
+        }
+    }
+
+    // Samples near a tile's edge reach pixels of the tiles around it, which
+    // end up the same whichever tile they are sampled in.
+    #[test]
+    fn filtered_images_dont_depend_on_tiles() {
+        let render = |size: usize| {
+            let (cam, world) = lit_sphere_camera();
+            let cam = cam
+                .with_filter(Filter::Mitchell, 2.0)
+                .with_tiles(size, TileOrder::Hilbert)
+                .build();
+            render_pixels(&cam, &PathTracer, &world)
+        };
+        let whole = render(16);
+        for size in [1, 3] {
+            for (a, b) in render(size).iter().zip(&whole) {
+                assert_eq!(
+                    [a.x(), a.y(), a.z()].map(f64::to_bits),
+                    [b.x(), b.y(), b.z()].map(f64::to_bits),
+                    "{size}"
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn time_limited_passes_grow_to_fit_the_budget() {
+        let cam = test_camera()
+            .with_samples_per_pixel(10)
+            .with_time_limit(Duration::from_secs(3600));
+        let started = Instant::now();
+        let fast = Some(Duration::from_micros(1));
+        let slow = Some(Duration::from_secs(1000));
+        // One sample first, to time it, then passes at most double the samples
+        // taken, however many the camera asks for.
+        assert_eq!(cam.next_pass_end(0, started, None), Some(1));
+        assert_eq!(cam.next_pass_end(1, started, fast), Some(2));
+        assert_eq!(cam.next_pass_end(64, started, fast), Some(128));
+        // Just under an hour left fits three more slow samples.
+        assert_eq!(cam.next_pass_end(64, started, slow), Some(67));
+        // Passes end early for snapshots.
+        let snapshots = cam.with_progressive(4, "progress.ppm");
+        assert_eq!(snapshots.next_pass_end(1, started, fast), Some(2));
+        assert_eq!(snapshots.next_pass_end(5, started, fast), Some(8));
+        let out_of_time = snapshots.with_time_limit(Duration::ZERO);
+        assert_eq!(out_of_time.next_pass_end(5, started, fast), None);
+    }
+
+    #[test]
+    fn film_rect_is_the_crop() {
+        let rect = test_camera().build().film_rect();
+        assert_eq!((rect.x0, rect.y0, rect.x1, rect.y1), (0, 0, 8, 4));
+        let rect = test_camera().with_crop(2, 1, 6, 3).build().film_rect();
+        assert_eq!((rect.x0, rect.y0, rect.x1, rect.y1), (2, 1, 8, 4));
+    }
+
+    #[test]
+    #[should_panic(expected = "doesn't fit")]
+    fn rejects_crops_outside_the_image() {
+        test_camera().with_crop(4, 0, 5, 1).build();
+    }
+
+    #[test]
+    #[should_panic(expected = "doesn't fit")]
+    fn rejects_empty_crops() {
+        test_camera().with_crop(1, 1, 0, 2).build();
+    }
+
+    #[test]
+    #[should_panic(expected = "out of range")]
+    fn rejects_crops_that_overflow() {
+        test_camera().with_crop(1, 1, usize::MAX, 2);
+    }
+}
@@ -0,0 +1,312 @@
+use std::fs;
+use std::io::{self, Write};
+
+use crate::{
+    camera::Camera,
+    color::Color,
+    film::{FilmPixel, FilteredPixel},
+};
+
+const MAGIC: &str = "raytracer checkpoint 1";
+
+// A render saved part way through, to be resumed later by adding more samples.
+//
+// The file holds a text header with the settings that must match to resume,
+// followed by every pixel's sums as little endian f64s. No random number
+// generator state is stored, as each sample's random numbers only depend on the
+// camera's seed, the pixel and how many samples it already has.
+pub struct Checkpoint {
+    // Number of samples every pixel has, apart from ones that converged early.
+    pub samples_per_pixel: u32,
+    pub film: Vec<FilmPixel>,
+    // Sums weighted by the camera's reconstruction filter, if it has one.
+    pub filtered: Option<Vec<FilteredPixel>>,
+    // Light the integrator traced onto the image, if any.
+    pub splats: Option<Vec<Color>>,
+}
+
+impl Checkpoint {
+    // Writes a checkpoint of the camera's render, where every pixel has
+    // samples_per_pixel samples or has converged. The file is replaced in one
+    // step, so a crash while saving leaves the previous checkpoint intact.
+    pub fn save(
+        path: &str,
+        cam: &Camera,
+        samples_per_pixel: u32,
+        film: &[FilmPixel],
+        filtered: Option<&[FilteredPixel]>,
+        splats: Option<&[Color]>,
+    ) -> io::Result<()> {
+        let mut values = Vec::new();
+        film[0].write_values(&mut values);
+        let values_per_pixel = values.len();
+
+        let temp_path = format!("{path}.tmp");
+        let mut out = io::BufWriter::new(fs::File::create(&temp_path)?);
+        writeln!(out, "{MAGIC}")?;
+        writeln!(out, "width {}", cam.image_width)?;
+        writeln!(out, "height {}", cam.image_height())?;
+        let rect = cam.film_rect();
+        writeln!(out, "crop {} {} {} {}", rect.x0, rect.y0, rect.x1, rect.y1)?;
+        writeln!(out, "samples_per_pixel {samples_per_pixel}")?;
+        writeln!(out, "max_depth {}", cam.max_depth)?;
+        match cam.seed {
+            Some(seed) => writeln!(out, "seed {seed}")?,
+            None => writeln!(out, "seed none")?,
+        }
+        writeln!(out, "filter {}", filter_setting(cam))?;
+        writeln!(out, "values_per_pixel {values_per_pixel}")?;
+        writeln!(out, "splats {}", splats.is_some() as u8)?;
+        writeln!(out)?;
+        for pixel in film {
+            values.clear();
+            pixel.write_values(&mut values);
+            for value in &values {
+                out.write_all(&value.to_le_bytes())?;
+            }
+        }
+        for pixel in filtered.unwrap_or_default() {
+            let color = pixel.color;
+            for value in [color.x(), color.y(), color.z(), pixel.weight] {
+                out.write_all(&value.to_le_bytes())?;
+            }
+        }
+        for splat in splats.unwrap_or_default() {
+            for value in [splat.x(), splat.y(), splat.z()] {
+                out.write_all(&value.to_le_bytes())?;
+            }
+        }
+        out.into_inner()
+            .map_err(|err| err.into_error())?
+            .sync_all()?;
+        fs::rename(temp_path, path)
+    }
+
+    // Reads a checkpoint to resume a render with the given camera. Fails if the
+    // image size or crop differs, and warns about other settings that differ, as their
+    // samples can still be combined.
+    pub fn load(path: &str, cam: &Camera) -> io::Result<Self> {
+        let bytes = fs::read(path)?;
+        let header_end = bytes
+            .windows(2)
+            .position(|w| w == b"\n\n")
+            .ok_or_else(|| invalid("truncated checkpoint header"))?;
+        let header =
+            std::str::from_utf8(&bytes[..header_end]).map_err(|_| invalid("bad checkpoint"))?;
+        let mut lines = header.lines();
+        if lines.next() != Some(MAGIC) {
+            return Err(invalid("not a checkpoint file"));
+        }
+        let mut settings = Vec::new();
+        for line in lines {
+            let (key, value) = line
+                .split_once(' ')
+                .ok_or_else(|| invalid("bad checkpoint header"))?;
+            settings.push((key, value));
+        }
+        let setting = |name: &str| -> io::Result<&str> {
+            settings
+                .iter()
+                .find(|(key, _)| *key == name)
+                .map(|&(_, value)| value)
+                .ok_or_else(|| invalid(&format!("checkpoint is missing {name}")))
+        };
+        let number = |name: &str| -> io::Result<usize> {
+            setting(name)?
+                .parse()
+                .map_err(|_| invalid(&format!("bad checkpoint {name}")))
+        };
+
+        let (width, height) = (number("width")?, number("height")?);
+        if width != cam.image_width || height != cam.image_height() {
+            return Err(invalid(&format!(
+                "checkpoint is {width}x{height}, but the image is {}x{}",
+                cam.image_width,
+                cam.image_height()
+            )));
+        }
+        let rect = cam.film_rect();
+        let crop = format!("{} {} {} {}", rect.x0, rect.y0, rect.x1, rect.y1);
+        if setting("crop")? != crop {
+            return Err(invalid(&format!(
+                "checkpoint is of the pixels {}, but the render is of {crop}",
+                setting("crop")?
+            )));
+        }
+        let seed = match cam.seed {
+            Some(seed) => seed.to_string(),
+            None => "none".to_string(),
+        };
+        let filter = setting("filter")?;
+        if (filter == "none") != cam.filter.is_none() {
+            return Err(invalid(
+                "checkpoint and render differ in using a reconstruction filter",
+            ));
+        }
+        if number("max_depth")? != cam.max_depth as usize
+            || setting("seed")? != seed
+            || filter != filter_setting(cam)
+        {
+            eprintln!("Warning: resuming a checkpoint rendered with different settings.");
+        }
+
+        // Pixels are only readable if they hold the same sums as this version.
+        let mut pixel_values = Vec::new();
+        FilmPixel::default().write_values(&mut pixel_values);
+        if number("values_per_pixel")? != pixel_values.len() {
+            return Err(invalid("checkpoint was saved by a different version"));
+        }
+
+        let data = &bytes[header_end + 2..];
+        let splat_values = if number("splats")? != 0 { 3 } else { 0 };
+        let pixels = rect.width() * rect.height();
+        let filtered_values = if cam.filter.is_some() { 4 } else { 0 };
+        let expected =
+            pixels * (pixel_values.len() + filtered_values) + width * height * splat_values;
+        if data.len() != expected * 8 {
+            return Err(invalid("checkpoint data has the wrong size"));
+        }
+        let mut values = data
+            .chunks_exact(8)
+            .map(|b| f64::from_le_bytes(b.try_into().unwrap()));
+        let film = (0..pixels)
+            .map(|_| FilmPixel::read_values(&mut values))
+            .collect::<Option<Vec<_>>>()
+            .ok_or_else(|| invalid("truncated checkpoint data"))?;
+        let filtered = if filtered_values != 0 {
+            let filtered = (0..pixels)
+                .map(|_| {
+                    Some(FilteredPixel {
+                        color: Color::new(values.next()?, values.next()?, values.next()?),
+                        weight: values.next()?,
+                    })
+                })
+                .collect::<Option<Vec<_>>>()
+                .ok_or_else(|| invalid("truncated checkpoint data"))?;
+            Some(filtered)
+        } else {
+            None
+        };
+        let splats = if splat_values != 0 {
+            let splats = (0..width * height)
+                .map(|_| Some(Color::new(values.next()?, values.next()?, values.next()?)))
+                .collect::<Option<Vec<_>>>()
+                .ok_or_else(|| invalid("truncated checkpoint data"))?;
+            Some(splats)
+        } else {
+            None
+        };
+
+        Ok(Self {
+            samples_per_pixel: number("samples_per_pixel")? as u32,
+            film,
+            filtered,
+            splats,
+        })
+    }
+}
+
+// Describes the camera's reconstruction filter, such as "Mitchell 2".
+fn filter_setting(cam: &Camera) -> String {
+    match cam.filter {
+        Some(filter) => format!("{:?} {}", filter.filter, filter.radius),
+        None => "none".to_string(),
+    }
+}
+
+fn invalid(msg: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{camera::tests::test_camera, filter::Filter};
+
+    fn camera() -> Camera {
+        test_camera()
+            .with_crop(2, 1, 3, 2)
+            .with_filter(Filter::Mitchell, 2.0)
+            .with_seed(3)
+            .build()
+    }
+
+    fn temp_path(name: &str) -> String {
+        let dir = std::env::temp_dir();
+        format!("{}/raytracer-{}-{name}", dir.display(), std::process::id())
+    }
+
+    fn pixel_values(film: &[FilmPixel]) -> Vec<f64> {
+        let mut values = Vec::new();
+        for pixel in film {
+            pixel.write_values(&mut values);
+        }
+        values
+    }
+
+    #[test]
+    fn round_trip() {
+        let cam = camera();
+        let film: Vec<FilmPixel> = (0..6)
+            .map(|k| {
+                let mut pixel = FilmPixel {
+                    color: Color::new(k as f64, 0.5, 1.0 / 3.0),
+                    samples: 4,
+                    ..Default::default()
+                };
+                pixel.estimate.add(pixel.color);
+                pixel
+            })
+            .collect();
+        let filtered: Vec<FilteredPixel> = (0..6)
+            .map(|k| FilteredPixel {
+                color: Color::new(0.25, k as f64, 2.0),
+                weight: -0.5,
+            })
+            .collect();
+        let splats: Vec<Color> = (0..32).map(|k| Color::new(k as f64, 0.0, 1.0)).collect();
+
+        let path = temp_path("round-trip");
+        Checkpoint::save(&path, &cam, 4, &film, Some(&filtered), Some(&splats)).unwrap();
+        let loaded = Checkpoint::load(&path, &cam).unwrap();
+        fs::remove_file(&path).unwrap();
+
+        assert_eq!(loaded.samples_per_pixel, 4);
+        assert_eq!(pixel_values(&loaded.film), pixel_values(&film));
+        let loaded_filtered = loaded.filtered.unwrap();
+        assert_eq!(loaded_filtered.len(), filtered.len());
+        for (a, b) in loaded_filtered.iter().zip(&filtered) {
+            assert_eq!((a.color - b.color).length_squared(), 0.0);
+            assert_eq!(a.weight, b.weight);
+        }
+        let loaded_splats = loaded.splats.unwrap();
+        assert_eq!(loaded_splats.len(), splats.len());
+        for (a, b) in loaded_splats.iter().zip(&splats) {
+            assert_eq!((*a - *b).length_squared(), 0.0);
+        }
+    }
+
+    #[test]
+    fn rejects_mismatched_or_truncated_checkpoints() {
+        let cam = camera();
+        let film = vec![FilmPixel::default(); 6];
+        let filtered = vec![FilteredPixel::default(); 6];
+        let path = temp_path("mismatched");
+        Checkpoint::save(&path, &cam, 1, &film, Some(&filtered), None).unwrap();
+
+        let wider = camera().with_image_width(10).build();
+        let uncropped = camera().with_crop(0, 0, 8, 4).build();
+        let mut unfiltered = camera();
+        unfiltered.filter = None;
+        for other in [wider, uncropped, unfiltered] {
+            let err = Checkpoint::load(&path, &other).err().unwrap();
+            assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        }
+
+        let bytes = fs::read(&path).unwrap();
+        fs::write(&path, &bytes[..bytes.len() - 1]).unwrap();
+        let err = Checkpoint::load(&path, &cam).err().unwrap();
+        fs::remove_file(&path).unwrap();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+    }
+}
@@ -4,6 +4,21 @@ use crate::{interval::Interval, vec3::Vec3};
 
 pub type Color = Vec3;
 
+// Returns the luminance (Y) of a linear sRGB color.
+pub fn luminance(color: Color) -> f64 {
+    0.2126 * color.x() + 0.7152 * color.y() + 0.0722 * color.z()
+}
+
+// Returns a color for a value in [0,1], running from blue through green to red.
+pub fn heat(t: f64) -> Color {
+    let t = t.clamp(0.0, 1.0);
+    if t < 0.5 {
+        Color::new(0.0, 2.0 * t, 1.0 - 2.0 * t)
+    } else {
+        Color::new(2.0 * t - 1.0, 2.0 - 2.0 * t, 0.0)
+    }
+}
+
 pub fn linear_to_gamma(linear_component: f64) -> f64 {
     if linear_component > 0.0 {
         f64::sqrt(linear_component)
@@ -12,10 +27,59 @@ pub fn linear_to_gamma(linear_component: f64) -> f64 {
     }
 }
 
+// Encodes a linear value in [0,1] with the sRGB transfer function.
+pub fn linear_to_srgb(linear_component: f64) -> f64 {
+    if linear_component <= 0.0031308 {
+        12.92 * linear_component.max(0.0)
+    } else {
+        1.055 * linear_component.powf(1.0 / 2.4) - 0.055
+    }
+}
+
+// Returns the color of a blackbody radiator at the given temperature (in kelvin)
+// in linear sRGB, normalized to unit luminance. The spectrum is integrated against
+// the analytic fit of the CIE 1931 color matching functions by Wyman et al.
+pub fn blackbody(kelvin: f64) -> Color {
+    const H: f64 = 6.62607015e-34;
+    const C: f64 = 2.99792458e8;
+    const K: f64 = 1.380649e-23;
+
+    let lobe = |x: f64, mu: f64, sigma1: f64, sigma2: f64| {
+        let t = (x - mu) / if x < mu { sigma1 } else { sigma2 };
+        f64::exp(-0.5 * t * t)
+    };
+
+    let (mut x, mut y, mut z) = (0.0, 0.0, 0.0);
+    for nm in (360..=830).step_by(5) {
+        let lambda = nm as f64;
+        let meters = lambda * 1e-9;
+        let radiance =
+            2.0 * H * C * C / (meters.powi(5) * (f64::exp(H * C / (meters * K * kelvin)) - 1.0));
+
+        x += radiance
+            * (1.056 * lobe(lambda, 599.8, 37.9, 31.0) + 0.362 * lobe(lambda, 442.0, 16.0, 26.7)
+                - 0.065 * lobe(lambda, 501.1, 20.4, 26.2));
+        y += radiance
+            * (0.821 * lobe(lambda, 568.8, 46.9, 40.5) + 0.286 * lobe(lambda, 530.9, 16.3, 31.1));
+        z += radiance
+            * (1.217 * lobe(lambda, 437.0, 11.8, 36.0) + 0.681 * lobe(lambda, 459.0, 26.0, 13.8));
+    }
+    if y <= 0.0 {
+        return Color::default();
+    }
+    let (x, z) = (x / y, z / y);
+
+    // XYZ to linear sRGB
+    let r = 3.2406 * x - 1.5372 - 0.4986 * z;
+    let g = -0.9689 * x + 1.8758 + 0.0415 * z;
+    let b = 0.0557 * x - 0.2040 + 1.0570 * z;
+    Color::new(r.max(0.0), g.max(0.0), b.max(0.0))
+}
+
+// Writes a color whose channels are already encoded display values in [0,1],
+// such as those from ToneMapping::apply, as bytes.
 pub fn write_color(w: &mut impl Write, color: &Color) {
-    let r = linear_to_gamma(color.x());
-    let g = linear_to_gamma(color.y());
-    let b = linear_to_gamma(color.z());
+    let (r, g, b) = (color.x(), color.y(), color.z());
 
     let intensity = Interval {
         min: 0.0,
@@ -27,3 +91,21 @@ pub fn write_color(w: &mut impl Write, color: &Color) {
 
     write!(w, "{rbyte} {gbyte} {bbyte}\n").unwrap();
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn blackbody_colors() {
+        let daylight = blackbody(6500.0);
+        assert!((luminance(daylight) - 1.0).abs() < 0.01);
+        for c in 0..3 {
+            assert!((daylight[c] - 1.0).abs() < 0.1, "{daylight:?}");
+        }
+        let candle = blackbody(1900.0);
+        assert!(candle.x() > candle.y() && candle.y() > candle.z());
+        let sky = blackbody(15000.0);
+        assert!(sky.z() > sky.y() && sky.y() > sky.x());
+    }
+}
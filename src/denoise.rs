@@ -0,0 +1,255 @@
+use rayon::iter::{IntoParallelIterator, ParallelIterator};
+
+use crate::{color::Color, vec3::Vec3};
+
+// Albedo channels darker than this aren't divided out, as the lighting behind
+// them can't be recovered reliably.
+const MIN_ALBEDO: f64 = 0.01;
+
+// A non-local means filter for removing noise from rendered images, guided by
+// the albedo and shading normal of the first surface each pixel sees, following
+// Rousselle et al., "Adaptive Rendering with Non-Local Means Filtering".
+//
+// Lighting is filtered separately from surface color: the image is divided by
+// the albedo, so textures stay sharp, and multiplied back afterwards. Each pixel
+// is then averaged with its neighbors, weighted by how similar the patches of
+// lighting around the two are compared to how noisy they are, and by how close
+// their albedos and normals are, so edges between surfaces are kept.
+pub struct Denoiser {
+    // Half the width of the window of neighbors averaged for each pixel.
+    radius: usize,
+    // How many standard deviations of noise two patches of lighting can differ
+    // by and still be averaged.
+    strength: f64,
+    // How different albedos and normals can be and still be averaged.
+    albedo_sigma: f64,
+    normal_sigma: f64,
+}
+
+impl Denoiser {
+    pub fn new() -> Self {
+        Self {
+            radius: 7,
+            strength: 0.5,
+            albedo_sigma: 0.1,
+            normal_sigma: 0.2,
+        }
+    }
+
+    // Sets how many pixels around each pixel are averaged. Larger radii remove
+    // more noise from very noisy images but are slower.
+    pub fn with_radius(mut self, radius: usize) -> Self {
+        self.radius = radius;
+        self
+    }
+
+    // Sets how aggressively noise is removed. Zero leaves the image untouched,
+    // larger values smooth more and start blurring fine lighting detail.
+    pub fn with_strength(mut self, strength: f64) -> Self {
+        self.strength = strength;
+        self
+    }
+
+    // Sets how closely albedos and normals must match for pixels to be averaged.
+    pub fn with_guide_sigmas(mut self, albedo: f64, normal: f64) -> Self {
+        self.albedo_sigma = albedo;
+        self.normal_sigma = normal;
+        self
+    }
+
+    // Returns a denoised copy of the width by height image `color`, given the
+    // variance, albedo and normal of every pixel.
+    pub fn denoise(
+        &self,
+        width: usize,
+        height: usize,
+        color: &[Color],
+        variance: &[Color],
+        albedo: &[Color],
+        normal: &[Vec3],
+    ) -> Vec<Color> {
+        if self.strength <= 0.0 || self.radius == 0 {
+            return color.to_vec();
+        }
+
+        let at = |x: isize, y: isize| {
+            let x = x.clamp(0, width as isize - 1) as usize;
+            let y = y.clamp(0, height as isize - 1) as usize;
+            y * width + x
+        };
+
+        // Separate the lighting from the surface color, scaling its variance to
+        // match.
+        let lighting: Vec<Color> = color
+            .iter()
+            .zip(albedo)
+            .map(|(&c, &a)| demodulate(c, a))
+            .collect();
+        let variance: Vec<Color> = variance
+            .iter()
+            .zip(albedo)
+            .map(|(&v, &a)| demodulate(demodulate(v, a), a))
+            .collect();
+        // Variance estimated from a few samples is itself noisy, so smooth it.
+        let variance: Vec<Color> = (0..width * height)
+            .map(|k| {
+                let (x, y) = ((k % width) as isize, (k / width) as isize);
+                let mut sum = Color::default();
+                for dy in -1..=1 {
+                    for dx in -1..=1 {
+                        sum += variance[at(x + dx, y + dy)];
+                    }
+                }
+                sum / 9.0
+            })
+            .collect();
+
+        let radius = self.radius as isize;
+        let k2 = self.strength * self.strength;
+        (0..width * height)
+            .into_par_iter()
+            .map(|k| {
+                let x = (k % width) as isize;
+                let y = (k / width) as isize;
+                let mut sum = Color::default();
+                let mut total_weight = 0.0;
+                for ny in y - radius..=y + radius {
+                    for nx in x - radius..=x + radius {
+                        if nx < 0 || ny < 0 || nx >= width as isize || ny >= height as isize {
+                            continue;
+                        }
+                        let n = at(nx, ny);
+
+                        let guide_distance = (albedo[k] - albedo[n]).length_squared()
+                            / (self.albedo_sigma * self.albedo_sigma)
+                            + (normal[k] - normal[n]).length_squared()
+                                / (self.normal_sigma * self.normal_sigma);
+
+                        // Average difference between the 3x3 patches around the
+                        // two pixels, relative to the noise expected in it.
+                        let mut patch_distance = 0.0;
+                        for py in -1..=1 {
+                            for px in -1..=1 {
+                                let p = at(x + px, y + py);
+                                let q = at(nx + px, ny + py);
+                                for c in 0..3 {
+                                    let (var_p, var_q) = (variance[p][c], variance[q][c]);
+                                    let diff = lighting[p][c] - lighting[q][c];
+                                    patch_distance += (diff * diff - (var_p + var_p.min(var_q)))
+                                        / (1e-10 + k2 * (var_p + var_q));
+                                }
+                            }
+                        }
+                        let patch_distance = f64::max(patch_distance / 27.0, 0.0);
+
+                        let weight = f64::exp(-patch_distance - 0.5 * guide_distance);
+                        sum += lighting[n] * weight;
+                        total_weight += weight;
+                    }
+                }
+                remodulate(sum / total_weight, albedo[k])
+            })
+            .collect()
+    }
+}
+
+impl Default for Denoiser {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+fn demodulate(color: Color, albedo: Color) -> Color {
+    let mut lighting = color;
+    for c in 0..3 {
+        if albedo[c] > MIN_ALBEDO {
+            lighting[c] /= albedo[c];
+        }
+    }
+    lighting
+}
+
+fn remodulate(lighting: Color, albedo: Color) -> Color {
+    let mut color = lighting;
+    for c in 0..3 {
+        if albedo[c] > MIN_ALBEDO {
+            color[c] *= albedo[c];
+        }
+    }
+    color
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const WIDTH: usize = 12;
+    const HEIGHT: usize = 10;
+
+    // A flat gray surface lit by noisy light, with its variance and guides.
+    fn noisy_image() -> (Vec<Color>, Vec<Color>, Vec<Color>, Vec<Vec3>) {
+        let color = (0..WIDTH * HEIGHT)
+            .map(|k| {
+                let noise = ((k * 7919) % 13) as f64 / 13.0 - 0.5;
+                Color::new(0.5, 0.5, 0.5) * (1.0 + noise)
+            })
+            .collect();
+        let variance = vec![Color::new(0.02, 0.02, 0.02); WIDTH * HEIGHT];
+        let albedo = vec![Color::new(0.5, 0.5, 0.5); WIDTH * HEIGHT];
+        let normal = vec![Vec3::new(0.0, 0.0, 1.0); WIDTH * HEIGHT];
+        (color, variance, albedo, normal)
+    }
+
+    #[test]
+    fn constant_image_is_unchanged() {
+        let (_, variance, albedo, normal) = noisy_image();
+        let color = vec![Color::new(0.2, 0.4, 0.8); WIDTH * HEIGHT];
+        let denoised = Denoiser::new().denoise(WIDTH, HEIGHT, &color, &variance, &albedo, &normal);
+        for (a, b) in denoised.iter().zip(&color) {
+            assert!((*a - *b).length() < 1e-12, "{a:?}");
+        }
+    }
+
+    #[test]
+    fn zero_strength_is_the_identity() {
+        let (color, variance, albedo, normal) = noisy_image();
+        let denoised = Denoiser::new()
+            .with_strength(0.0)
+            .denoise(WIDTH, HEIGHT, &color, &variance, &albedo, &normal);
+        for (a, b) in denoised.iter().zip(&color) {
+            assert_eq!((*a - *b).length_squared(), 0.0);
+        }
+    }
+
+    #[test]
+    fn removes_noise_but_keeps_edges_between_surfaces() {
+        let (color, variance, mut albedo, normal) = noisy_image();
+        // The right half is a much darker surface.
+        let mut color = color;
+        for k in 0..WIDTH * HEIGHT {
+            if k % WIDTH >= WIDTH / 2 {
+                albedo[k] = Color::new(0.05, 0.05, 0.05);
+                color[k] *= 0.1;
+            }
+        }
+        let denoised = Denoiser::new().denoise(WIDTH, HEIGHT, &color, &variance, &albedo, &normal);
+        let spread = |image: &[Color]| {
+            let left: Vec<f64> = image
+                .iter()
+                .enumerate()
+                .filter(|(k, _)| k % WIDTH < WIDTH / 2)
+                .map(|(_, c)| c.x())
+                .collect();
+            let mean = left.iter().sum::<f64>() / left.len() as f64;
+            left.iter().map(|x| (x - mean) * (x - mean)).sum::<f64>() / left.len() as f64
+        };
+        assert!(spread(&denoised) < 0.25 * spread(&color));
+        for (k, c) in denoised.iter().enumerate() {
+            if k % WIDTH >= WIDTH / 2 {
+                assert!(c.x() < 0.1, "{k}: {c:?}");
+            } else {
+                assert!(c.x() > 0.3, "{k}: {c:?}");
+            }
+        }
+    }
+}
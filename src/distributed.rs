@@ -0,0 +1,1088 @@
+use std::collections::{HashMap, VecDeque};
+use std::env;
+use std::io::{self, BufReader, BufWriter, Read, Write};
+use std::net::{Ipv4Addr, Ipv6Addr, SocketAddr, TcpListener, TcpStream};
+use std::process::{Child, Command, Stdio};
+use std::sync::{Arc, Condvar, Mutex};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+
+use indicatif::ProgressBar;
+use rayon::iter::{ParallelBridge, ParallelIterator};
+
+use crate::{
+    camera::Camera,
+    color::Color,
+    film::{FilmPixel, FilteredPixel, FilteredTile},
+    hit::Hit,
+    integrator::Integrator,
+    sampler,
+    tile::Tile,
+};
+
+// Messages from the coordinator to a worker.
+const TILE: u8 = 1;
+const FLUSH: u8 = 2;
+const DONE: u8 = 3;
+
+// Replies to a worker's settings.
+const ACCEPTED: u8 = 1;
+const REJECTED: u8 = 0;
+
+// Identifies the protocol at the start of a worker's settings.
+const MAGIC: u64 = 0x7261797472616331;
+
+// How long a worker keeps trying to reach a coordinator that isn't listening
+// yet.
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(30);
+
+// The part a process plays in rendering an image across several processes.
+// Every process builds the same scene and camera, then the coordinator hands out
+// tiles of the image to the workers connected to it over TCP and combines what
+// they render into its output. The coordinator renders tiles too while it
+// waits, so the render finishes even if no workers connect or they all stop.
+pub enum Role {
+    // Listens for workers on the given address, and starts `workers` worker
+    // processes on this machine.
+    Coordinator { address: String, workers: usize },
+    // Renders tiles for the coordinator at the given address.
+    Worker { address: String },
+}
+
+// Tiles of pixels waiting to be rendered, or rendered and waiting to be merged.
+struct Unit {
+    tile: Tile,
+    // Number of samples per pixel to render up to.
+    end: u32,
+    pixels: Vec<FilmPixel>,
+    // What the tile's samples added to the filtered pixels around it, once
+    // rendered with a reconstruction filter.
+    filtered: Option<FilteredTile>,
+}
+
+// Work shared between the coordinator and the threads talking to its workers.
+#[derive(Default)]
+struct State {
+    units: VecDeque<Unit>,
+    results: Vec<Unit>,
+    // Units handed out in the current pass whose results haven't arrived.
+    outstanding: usize,
+    // Light that workers splatted onto the image since they were last asked.
+    splats: Vec<(usize, Color)>,
+    // Increased to ask every worker for its splats.
+    generation: u64,
+    // The last generation each connected worker answered, by connection.
+    // Workers joining count as having answered the current one, as they have
+    // nothing to send yet.
+    flushed: HashMap<u64, u64>,
+    next_connection: u64,
+    finished: bool,
+}
+
+#[derive(Default)]
+struct Shared {
+    state: Mutex<State>,
+    changed: Condvar,
+}
+
+// The coordinator's side of a distributed render.
+pub struct Coordinator {
+    shared: Arc<Shared>,
+    listener: JoinHandle<()>,
+    children: Vec<Child>,
+}
+
+impl Coordinator {
+    // Starts listening for workers at the given address, and starts `workers`
+    // local worker processes running this program with the same arguments.
+    pub fn start(
+        cam: &Camera,
+        integrator: &dyn Integrator,
+        address: &str,
+        workers: usize,
+    ) -> io::Result<Self> {
+        let listener = TcpListener::bind(address)?;
+        let mut address = listener.local_addr()?;
+        listener.set_nonblocking(true)?;
+        eprintln!("Waiting for workers on {address}.");
+        // Local workers can't connect to an address that means any interface,
+        // such as 0.0.0.0, but reach every interface through loopback.
+        if address.ip().is_unspecified() {
+            address.set_ip(match address {
+                SocketAddr::V4(_) => Ipv4Addr::LOCALHOST.into(),
+                SocketAddr::V6(_) => Ipv6Addr::LOCALHOST.into(),
+            });
+        }
+
+        let shared = Arc::new(Shared::default());
+        let settings = settings(cam, integrator);
+        let limits = Limits::new(cam);
+        let listener = {
+            let shared = shared.clone();
+            thread::spawn(move || accept_workers(listener, &shared, &settings, limits))
+        };
+        let mut coordinator = Self {
+            shared,
+            listener,
+            children: Vec::new(),
+        };
+        for _ in 0..workers {
+            let child = Command::new(env::current_exe()?)
+                .args(worker_args(env::args().skip(1)))
+                .arg("--worker")
+                .arg(address.to_string())
+                .stdout(Stdio::null())
+                .spawn()?;
+            coordinator.children.push(child);
+        }
+        Ok(coordinator)
+    }
+
+    // Has the workers render the given tiles of the film until their pixels
+    // have `end` samples, rendering tiles here while no worker takes them.
+    // Returns the light the workers splatted onto the image in the meantime, if
+    // any.
+    #[allow(clippy::too_many_arguments)]
+    pub fn render_pass(
+        &self,
+        cam: &Camera,
+        integrator: &dyn Integrator,
+        world: &dyn Hit,
+        tiles: &[Tile],
+        end: u32,
+        film: &mut [FilmPixel],
+        mut filtered: Option<&mut [FilteredPixel]>,
+        progress: &ProgressBar,
+    ) -> Option<Vec<Color>> {
+        let width = cam.film_rect().width();
+        let mut state = self.shared.state.lock().unwrap();
+        for &tile in tiles {
+            let pixels = tile.pixels(width).map(|k| film[k].clone()).collect();
+            state.units.push_back(Unit {
+                tile,
+                end,
+                pixels,
+                filtered: None,
+            });
+            state.outstanding += 1;
+        }
+        self.shared.changed.notify_all();
+
+        while state.outstanding > 0 || !state.results.is_empty() {
+            for unit in state.results.drain(..) {
+                merge(unit, width, film, filtered.as_deref_mut());
+                progress.inc(1);
+            }
+            if !state.units.is_empty() {
+                drop(state);
+                self.render_units(
+                    cam,
+                    integrator,
+                    world,
+                    film,
+                    filtered.as_deref_mut(),
+                    progress,
+                );
+                state = self.shared.state.lock().unwrap();
+                continue;
+            }
+            state = self
+                .shared
+                .changed
+                .wait_while(state, |s| {
+                    s.outstanding > 0 && s.results.is_empty() && s.units.is_empty()
+                })
+                .unwrap();
+        }
+
+        state.generation += 1;
+        self.shared.changed.notify_all();
+        let mut state = self
+            .shared
+            .changed
+            .wait_while(state, |s| {
+                s.flushed
+                    .values()
+                    .any(|&generation| generation < s.generation)
+            })
+            .unwrap();
+        let splats = std::mem::take(&mut state.splats);
+        drop(state);
+
+        if splats.is_empty() {
+            return None;
+        }
+        let mut image = vec![Color::default(); cam.image_width * cam.image_height()];
+        for (k, splat) in splats {
+            image[k] += splat;
+        }
+        Some(image)
+    }
+
+    // Renders units waiting to be handed out on the thread pool, alongside the
+    // workers, until there are none left.
+    fn render_units(
+        &self,
+        cam: &Camera,
+        integrator: &dyn Integrator,
+        world: &dyn Hit,
+        film: &mut [FilmPixel],
+        filtered: Option<&mut [FilteredPixel]>,
+        progress: &ProgressBar,
+    ) {
+        let width = cam.film_rect().width();
+        let framebuffer = Mutex::new((film, filtered));
+        std::iter::from_fn(|| self.shared.state.lock().unwrap().units.pop_front())
+            .par_bridge()
+            .for_each(|mut unit| {
+                unit.filtered =
+                    cam.render_tile(integrator, world, &unit.tile, &mut unit.pixels, unit.end);
+                let (film, filtered) = &mut *framebuffer.lock().unwrap();
+                merge(unit, width, film, filtered.as_deref_mut());
+                progress.inc(1);
+                self.shared.state.lock().unwrap().outstanding -= 1;
+            });
+    }
+
+    // Tells the workers the render is done and waits for them to finish.
+    pub fn finish(mut self) {
+        self.shared.state.lock().unwrap().finished = true;
+        self.shared.changed.notify_all();
+        let _ = self.listener.join();
+        for child in &mut self.children {
+            let _ = child.wait();
+        }
+    }
+}
+
+// Writes a rendered unit's pixels back into a film of the given width.
+fn merge(unit: Unit, width: usize, film: &mut [FilmPixel], filtered: Option<&mut [FilteredPixel]>) {
+    for (k, pixel) in unit.tile.pixels(width).zip(unit.pixels) {
+        film[k] = pixel;
+    }
+    if let (Some(filtered), Some(filtered_tile)) = (filtered, unit.filtered) {
+        filtered_tile.add_to(filtered, width);
+    }
+}
+
+// Returns the arguments for a local worker: the coordinator's own, which pick
+// the same scene and settings, without the ones that make it the coordinator.
+fn worker_args(mut args: impl Iterator<Item = String>) -> Vec<String> {
+    let mut worker_args = Vec::new();
+    while let Some(arg) = args.next() {
+        if arg == "--coordinator" || arg == "--workers" {
+            args.next();
+        } else {
+            worker_args.push(arg);
+        }
+    }
+    worker_args
+}
+
+// Accepts workers until the render finishes, talking to each on its own thread.
+fn accept_workers(
+    listener: TcpListener,
+    shared: &Arc<Shared>,
+    settings: &[(&'static str, u64)],
+    limits: Limits,
+) {
+    let mut threads = Vec::new();
+    while !shared.state.lock().unwrap().finished {
+        match listener.accept() {
+            Ok((stream, address)) => {
+                let shared = shared.clone();
+                let settings = settings.to_vec();
+                threads.push(thread::spawn(move || {
+                    if let Err(err) = serve_worker(stream, &shared, &settings, limits) {
+                        eprintln!("\rWorker {address} stopped: {err}");
+                    }
+                }));
+            }
+            Err(err) if err.kind() == io::ErrorKind::WouldBlock => {
+                thread::sleep(Duration::from_millis(50));
+            }
+            Err(err) => {
+                eprintln!("\rFailed to accept a worker: {err}");
+                thread::sleep(Duration::from_millis(50));
+            }
+        }
+    }
+    for thread in threads {
+        let _ = thread.join();
+    }
+}
+
+// Checks that a worker is rendering the same image, then hands it units until
+// the render finishes.
+fn serve_worker(
+    stream: TcpStream,
+    shared: &Shared,
+    settings: &[(&'static str, u64)],
+    limits: Limits,
+) -> io::Result<()> {
+    stream.set_nonblocking(false)?;
+    stream.set_nodelay(true)?;
+    let mut reader = BufReader::new(stream.try_clone()?);
+    let mut writer = BufWriter::new(stream);
+
+    let worker_settings = read_u64s(&mut reader, settings.len())?;
+    let differing = settings
+        .iter()
+        .zip(worker_settings)
+        .find(|&(&(_, value), worker_value)| value != worker_value);
+    if let Some(((name, _), _)) = differing {
+        write_u8(&mut writer, REJECTED)?;
+        writer.flush()?;
+        return Err(invalid(&format!(
+            "the worker is rendering with a different {name}"
+        )));
+    }
+    write_u8(&mut writer, ACCEPTED)?;
+    writer.flush()?;
+
+    let (connection, generation) = {
+        let mut state = shared.state.lock().unwrap();
+        let connection = state.next_connection;
+        state.next_connection += 1;
+        let generation = state.generation;
+        state.flushed.insert(connection, generation);
+        (connection, generation)
+    };
+    let result = run_worker_connection(
+        &mut reader,
+        &mut writer,
+        shared,
+        connection,
+        generation,
+        limits,
+    );
+    shared.state.lock().unwrap().flushed.remove(&connection);
+    shared.changed.notify_all();
+    result
+}
+
+fn run_worker_connection(
+    reader: &mut impl Read,
+    writer: &mut impl Write,
+    shared: &Shared,
+    connection: u64,
+    mut generation: u64,
+    limits: Limits,
+) -> io::Result<()> {
+    let values_per_pixel = values_per_pixel();
+    loop {
+        let mut state = shared
+            .changed
+            .wait_while(shared.state.lock().unwrap(), |s| {
+                s.units.is_empty() && s.generation == generation && !s.finished
+            })
+            .unwrap();
+
+        if state.generation != generation {
+            generation = state.generation;
+            drop(state);
+            write_u8(writer, FLUSH)?;
+            writer.flush()?;
+            let splats = read_splats(reader, limits.image_pixels)?;
+            let mut state = shared.state.lock().unwrap();
+            state.splats.extend(splats);
+            state.flushed.insert(connection, generation);
+            shared.changed.notify_all();
+        } else if let Some(mut unit) = state.units.pop_front() {
+            drop(state);
+            let rendered = send_unit(writer, &unit).and_then(|()| {
+                let values = read_f64s(reader, unit.pixels.len() * values_per_pixel)?;
+                Ok((
+                    read_pixels(&values, unit.pixels.len())?,
+                    read_filtered(reader, limits)?,
+                ))
+            });
+            let mut state = shared.state.lock().unwrap();
+            match rendered {
+                Ok((pixels, filtered)) => {
+                    unit.pixels = pixels;
+                    unit.filtered = filtered;
+                    state.results.push(unit);
+                    state.outstanding -= 1;
+                    shared.changed.notify_all();
+                }
+                Err(err) => {
+                    // Give the unit to another worker.
+                    state.units.push_front(unit);
+                    shared.changed.notify_all();
+                    return Err(err);
+                }
+            }
+        } else {
+            drop(state);
+            write_u8(writer, DONE)?;
+            return writer.flush();
+        }
+    }
+}
+
+fn send_unit(writer: &mut impl Write, unit: &Unit) -> io::Result<()> {
+    write_u8(writer, TILE)?;
+    let tile = unit.tile;
+    for value in [tile.x0, tile.y0, tile.x1, tile.y1] {
+        write_u64(writer, value as u64)?;
+    }
+    write_u64(writer, unit.end as u64)?;
+    let mut values = Vec::new();
+    for pixel in &unit.pixels {
+        pixel.write_values(&mut values);
+    }
+    write_f64s(writer, &values)?;
+    writer.flush()
+}
+
+// Connects to the coordinator at the given address and renders the tiles it
+// hands out, until it says the render is done. Each thread of the thread pool
+// connects on its own and renders one tile at a time.
+pub fn run_worker(
+    cam: &Camera,
+    address: &str,
+    integrator: &dyn Integrator,
+    world: &dyn Hit,
+) -> io::Result<()> {
+    // Splats already sent by any connection, so only new ones are sent when
+    // asked.
+    let sent_splats = Mutex::new(integrator.splats());
+    thread::scope(|scope| {
+        let connections: Vec<_> = (0..rayon::current_num_threads())
+            .map(|_| {
+                scope
+                    .spawn(|| render_for_coordinator(cam, address, integrator, world, &sent_splats))
+            })
+            .collect();
+        connections
+            .into_iter()
+            .try_for_each(|connection| connection.join().unwrap())
+    })
+}
+
+// Renders the tiles the coordinator hands out over one connection.
+fn render_for_coordinator(
+    cam: &Camera,
+    address: &str,
+    integrator: &dyn Integrator,
+    world: &dyn Hit,
+    sent_splats: &Mutex<Option<Vec<Color>>>,
+) -> io::Result<()> {
+    let stream = connect(address)?;
+    stream.set_nodelay(true)?;
+    let mut reader = BufReader::new(stream.try_clone()?);
+    let mut writer = BufWriter::new(stream);
+    let settings: Vec<u64> = settings(cam, integrator)
+        .into_iter()
+        .map(|(_, value)| value)
+        .collect();
+    write_u64s(&mut writer, &settings)?;
+    writer.flush()?;
+    if read_u8(&mut reader)? != ACCEPTED {
+        return Err(invalid(
+            "the coordinator is rendering with different settings",
+        ));
+    }
+
+    let values_per_pixel = values_per_pixel();
+    let limits = Limits::new(cam);
+    loop {
+        match read_u8(&mut reader)? {
+            TILE => {
+                let tile = read_tile(&mut reader, limits)?;
+                let end = u32::try_from(read_u64(&mut reader)?)
+                    .map_err(|_| invalid("too many samples per pixel"))?;
+                let count = tile.width() * tile.height();
+                let values = read_f64s(&mut reader, count * values_per_pixel)?;
+                let mut pixels = read_pixels(&values, count)?;
+                let filtered = cam.render_tile(integrator, world, &tile, &mut pixels, end);
+
+                let mut values = Vec::with_capacity(values.len());
+                for pixel in &pixels {
+                    pixel.write_values(&mut values);
+                }
+                write_f64s(&mut writer, &values)?;
+                write_filtered(&mut writer, filtered.as_ref())?;
+                writer.flush()?;
+            }
+            FLUSH => {
+                // The coordinator only asks once every tile handed out is
+                // rendered, so the first connection asked sends every
+                // connection's splats and the others send nothing new.
+                let mut sent_splats = sent_splats.lock().unwrap();
+                let splats = integrator.splats();
+                // Sums only grow, and are exact multiples of a fixed unit, so
+                // the differences are exact while sums fit in an f64's 53 bit
+                // mantissa, up to about 2e6.
+                let new: Vec<(usize, Color)> = match (&splats, &*sent_splats) {
+                    (Some(splats), Some(sent)) => splats
+                        .iter()
+                        .zip(sent)
+                        .map(|(&splat, &sent)| splat - sent)
+                        .enumerate()
+                        .filter(|(_, splat)| splat.length_squared() != 0.0)
+                        .collect(),
+                    _ => Vec::new(),
+                };
+                write_splats(&mut writer, &new)?;
+                writer.flush()?;
+                *sent_splats = splats;
+            }
+            DONE => return Ok(()),
+            _ => return Err(invalid("unexpected message from the coordinator")),
+        }
+    }
+}
+
+// Connects to the given address, retrying while the coordinator starts up.
+fn connect(address: &str) -> io::Result<TcpStream> {
+    let started = Instant::now();
+    loop {
+        match TcpStream::connect(address) {
+            Ok(stream) => return Ok(stream),
+            Err(err) if started.elapsed() > CONNECT_TIMEOUT => return Err(err),
+            Err(_) => thread::sleep(Duration::from_millis(100)),
+        }
+    }
+}
+
+// Settings the coordinator and its workers must agree on for their pixels to be
+// combined, by name.
+fn settings(cam: &Camera, integrator: &dyn Integrator) -> Vec<(&'static str, u64)> {
+    let rect = cam.film_rect();
+    let sampler = cam
+        .sampler
+        .as_ref()
+        .map_or("none".to_string(), |sampler| sampler.describe());
+    vec![
+        ("protocol", MAGIC),
+        ("image width", cam.image_width as u64),
+        ("image height", cam.image_height() as u64),
+        (
+            "crop",
+            sampler::hash(&[rect.x0, rect.y0, rect.x1, rect.y1].map(|v| v as u64)),
+        ),
+        ("samples per pixel", cam.samples_per_pixel as u64),
+        ("max depth", cam.max_depth as u64),
+        (
+            "Russian roulette depth",
+            cam.russian_roulette_depth
+                .map_or(0, |depth| depth as u64 + 1),
+        ),
+        ("seed", cam.seed.map_or(0, |seed| sampler::hash(&[1, seed]))),
+        ("sampler", text_hash(&sampler)),
+        ("integrator", text_hash(&integrator.describe())),
+        (
+            "adaptive sampling",
+            cam.adaptive.map_or(0, |adaptive| {
+                sampler::hash(&[adaptive.min_samples as u64, adaptive.threshold.to_bits()])
+            }),
+        ),
+        (
+            "set of passes",
+            cam.records_aovs() as u64 | (cam.splits_light_paths() as u64) << 1,
+        ),
+        ("build", values_per_pixel() as u64),
+        (
+            "reconstruction filter",
+            cam.filter.map_or(0, |filter| {
+                sampler::hash(&[filter.filter as u64, filter.radius.to_bits()])
+            }),
+        ),
+    ]
+}
+
+fn text_hash(text: &str) -> u64 {
+    sampler::hash(&text.bytes().map(u64::from).collect::<Vec<_>>())
+}
+
+// Sizes that messages from the other side are checked against before anything
+// is allocated or indexed with them.
+#[derive(Clone, Copy)]
+struct Limits {
+    // Size of the film, the part of the image being rendered.
+    width: usize,
+    height: usize,
+    // Number of pixels in the whole image, which splats are indices into.
+    image_pixels: usize,
+}
+
+impl Limits {
+    fn new(cam: &Camera) -> Self {
+        let rect = cam.film_rect();
+        Self {
+            width: rect.width(),
+            height: rect.height(),
+            image_pixels: cam.image_width * cam.image_height(),
+        }
+    }
+}
+
+fn values_per_pixel() -> usize {
+    let mut values = Vec::new();
+    FilmPixel::default().write_values(&mut values);
+    values.len()
+}
+
+fn read_pixels(values: &[f64], count: usize) -> io::Result<Vec<FilmPixel>> {
+    let mut values = values.iter().copied();
+    (0..count)
+        .map(|_| FilmPixel::read_values(&mut values))
+        .collect::<Option<Vec<_>>>()
+        .ok_or_else(|| invalid("truncated pixels"))
+}
+
+fn write_filtered(writer: &mut impl Write, filtered: Option<&FilteredTile>) -> io::Result<()> {
+    let Some(filtered) = filtered else {
+        return write_u8(writer, 0);
+    };
+    write_u8(writer, 1)?;
+    let region = filtered.region;
+    write_u64s(
+        writer,
+        &[region.x0, region.y0, region.x1, region.y1].map(|v| v as u64),
+    )?;
+    for pixel in &filtered.pixels {
+        let color = pixel.color;
+        write_f64s(writer, &[color.x(), color.y(), color.z(), pixel.weight])?;
+    }
+    Ok(())
+}
+
+fn read_filtered(reader: &mut impl Read, limits: Limits) -> io::Result<Option<FilteredTile>> {
+    if read_u8(reader)? == 0 {
+        return Ok(None);
+    }
+    let mut filtered = FilteredTile::new(read_tile(reader, limits)?);
+    for pixel in &mut filtered.pixels {
+        let [r, g, b, weight] = read_f64s(reader, 4)?[..] else {
+            unreachable!()
+        };
+        *pixel = FilteredPixel {
+            color: Color::new(r, g, b),
+            weight,
+        };
+    }
+    Ok(Some(filtered))
+}
+
+// Reads a rectangle of the film, checking that it's inside it.
+fn read_tile(reader: &mut impl Read, limits: Limits) -> io::Result<Tile> {
+    let [x0, y0, x1, y1] = read_u64s(reader, 4)?[..] else {
+        unreachable!()
+    };
+    if x0 > x1 || x1 > limits.width as u64 || y0 > y1 || y1 > limits.height as u64 {
+        return Err(invalid("tile outside the image"));
+    }
+    Ok(Tile {
+        x0: x0 as usize,
+        y0: y0 as usize,
+        x1: x1 as usize,
+        y1: y1 as usize,
+    })
+}
+
+fn write_splats(writer: &mut impl Write, splats: &[(usize, Color)]) -> io::Result<()> {
+    write_u64(writer, splats.len() as u64)?;
+    for &(k, splat) in splats {
+        write_u64(writer, k as u64)?;
+        write_f64s(writer, &[splat.x(), splat.y(), splat.z()])?;
+    }
+    Ok(())
+}
+
+// Reads light splatted onto pixels of the image, checking that there are no
+// more splats than pixels and that every one is on the image.
+fn read_splats(reader: &mut impl Read, image_pixels: usize) -> io::Result<Vec<(usize, Color)>> {
+    let count = read_u64(reader)?;
+    if count > image_pixels as u64 {
+        return Err(invalid("more splats than pixels"));
+    }
+    let mut splats = Vec::with_capacity(count as usize);
+    for _ in 0..count {
+        let k = read_u64(reader)?;
+        if k >= image_pixels as u64 {
+            return Err(invalid("splat outside the image"));
+        }
+        let [r, g, b] = read_f64s(reader, 3)?[..] else {
+            unreachable!()
+        };
+        splats.push((k as usize, Color::new(r, g, b)));
+    }
+    Ok(splats)
+}
+
+fn write_u8(writer: &mut impl Write, value: u8) -> io::Result<()> {
+    writer.write_all(&[value])
+}
+
+fn read_u8(reader: &mut impl Read) -> io::Result<u8> {
+    let mut bytes = [0; 1];
+    reader.read_exact(&mut bytes)?;
+    Ok(bytes[0])
+}
+
+fn write_u64(writer: &mut impl Write, value: u64) -> io::Result<()> {
+    writer.write_all(&value.to_le_bytes())
+}
+
+fn read_u64(reader: &mut impl Read) -> io::Result<u64> {
+    let mut bytes = [0; 8];
+    reader.read_exact(&mut bytes)?;
+    Ok(u64::from_le_bytes(bytes))
+}
+
+fn write_u64s(writer: &mut impl Write, values: &[u64]) -> io::Result<()> {
+    values
+        .iter()
+        .try_for_each(|&value| write_u64(writer, value))
+}
+
+fn read_u64s(reader: &mut impl Read, count: usize) -> io::Result<Vec<u64>> {
+    (0..count).map(|_| read_u64(reader)).collect()
+}
+
+fn write_f64s(writer: &mut impl Write, values: &[f64]) -> io::Result<()> {
+    values
+        .iter()
+        .try_for_each(|value| writer.write_all(&value.to_le_bytes()))
+}
+
+fn read_f64s(reader: &mut impl Read, count: usize) -> io::Result<Vec<f64>> {
+    (0..count)
+        .map(|_| read_u64(reader).map(f64::from_bits))
+        .collect()
+}
+
+fn invalid(msg: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        bdpt::BidirectionalPathTracer,
+        camera::tests::{lit_sphere, test_camera},
+        hit::Hittables,
+        integrator::PathTracer,
+        sampler::SobolSampler,
+        tile::{TileOrder, Tiles},
+    };
+
+    fn camera() -> Camera {
+        test_camera()
+            .with_samples_per_pixel(4)
+            .with_max_depth(4)
+            .with_background(Color::new(0.5, 0.7, 1.0))
+            .with_seed(1)
+            .build()
+    }
+
+    fn limits() -> Limits {
+        Limits::new(&camera())
+    }
+
+    fn encode(write: impl FnOnce(&mut Vec<u8>) -> io::Result<()>) -> Vec<u8> {
+        let mut bytes = Vec::new();
+        write(&mut bytes).unwrap();
+        bytes
+    }
+
+    #[test]
+    fn worker_args_drop_the_coordinator_role() {
+        let args = [
+            "--scene",
+            "quads",
+            "--coordinator",
+            "0.0.0.0:7878",
+            "--workers",
+            "4",
+            "--seed",
+            "1",
+        ]
+        .map(String::from);
+        assert_eq!(
+            worker_args(args.into_iter()),
+            ["--scene", "quads", "--seed", "1"]
+        );
+    }
+
+    #[test]
+    fn splats_round_trip() {
+        let splats = vec![
+            (0, Color::new(1.0, 2.0, 3.0)),
+            (31, Color::new(0.5, 0.0, 0.25)),
+        ];
+        let bytes = encode(|w| write_splats(w, &splats));
+        let read = read_splats(&mut bytes.as_slice(), 32).unwrap();
+        assert_eq!(read.len(), 2);
+        for ((k, a), (l, b)) in read.iter().zip(&splats) {
+            assert_eq!(k, l);
+            assert_eq!((*a - *b).length_squared(), 0.0);
+        }
+    }
+
+    #[test]
+    fn rejects_bad_splats() {
+        let bytes = encode(|w| write_splats(w, &[(32, Color::default())]));
+        let err = read_splats(&mut bytes.as_slice(), 32).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+
+        // A count far larger than the image, with nothing following it.
+        let bytes = encode(|w| write_u64(w, u64::MAX));
+        let err = read_splats(&mut bytes.as_slice(), 32).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+
+        let bytes = encode(|w| write_splats(w, &[(3, Color::default())]));
+        let err = read_splats(&mut &bytes[..bytes.len() - 1], 32).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::UnexpectedEof);
+    }
+
+    #[test]
+    fn filtered_tiles_round_trip() {
+        let mut tile = FilteredTile::new(Tile {
+            x0: 1,
+            y0: 0,
+            x1: 4,
+            y1: 2,
+        });
+        for (k, pixel) in tile.pixels.iter_mut().enumerate() {
+            pixel.color = Color::new(k as f64, 1.0, 2.0);
+            pixel.weight = -0.5 * k as f64;
+        }
+        let bytes = encode(|w| write_filtered(w, Some(&tile)));
+        let read = read_filtered(&mut bytes.as_slice(), limits())
+            .unwrap()
+            .unwrap();
+        let region = read.region;
+        assert_eq!((region.x0, region.y0, region.x1, region.y1), (1, 0, 4, 2));
+        assert_eq!(read.pixels.len(), tile.pixels.len());
+        for (a, b) in read.pixels.iter().zip(&tile.pixels) {
+            assert_eq!((a.color - b.color).length_squared(), 0.0);
+            assert_eq!(a.weight, b.weight);
+        }
+
+        let bytes = encode(|w| write_filtered(w, None));
+        assert!(
+            read_filtered(&mut bytes.as_slice(), limits())
+                .unwrap()
+                .is_none()
+        );
+    }
+
+    #[test]
+    fn rejects_tiles_outside_the_film() {
+        // The film is 8x4.
+        for region in [
+            [0, 0, 9, 1],
+            [0, 0, 1, 5],
+            [3, 0, 2, 1],
+            [0, 0, u64::MAX, u64::MAX],
+        ] {
+            let bytes = encode(|w| {
+                write_u8(w, 1)?;
+                write_u64s(w, &region)
+            });
+            let err = read_filtered(&mut bytes.as_slice(), limits())
+                .err()
+                .unwrap();
+            assert_eq!(err.kind(), io::ErrorKind::InvalidData, "{region:?}");
+        }
+    }
+
+    #[test]
+    fn settings_include_sampling() {
+        let named = |cam: &Camera, integrator: &dyn Integrator| {
+            let settings = settings(cam, integrator);
+            move |name: &str| settings.iter().find(|(n, _)| *n == name).unwrap().1
+        };
+        let base = named(&camera(), &PathTracer);
+        let more_samples = named(&camera().with_samples_per_pixel(8), &PathTracer);
+        assert_ne!(base("samples per pixel"), more_samples("samples per pixel"));
+        let sobol = named(&camera().with_sampler(SobolSampler), &PathTracer);
+        assert_ne!(base("sampler"), sobol("sampler"));
+        let bdpt = BidirectionalPathTracer::new();
+        let other = named(&camera(), &bdpt);
+        assert_ne!(base("integrator"), other("integrator"));
+        assert_eq!(base("image width"), other("image width"));
+    }
+
+    // Without any workers, the coordinator renders every tile itself, the same
+    // as rendering without one.
+    #[test]
+    fn coordinator_renders_without_workers() {
+        let cam = camera();
+        let world = Hittables::default();
+        let rect = cam.film_rect();
+        let tiles = Tiles {
+            size: 3,
+            order: TileOrder::Scanline,
+        }
+        .split(rect.width(), rect.height());
+        let mut film = vec![FilmPixel::default(); rect.width() * rect.height()];
+        let coordinator = Coordinator::start(&cam, &PathTracer, "127.0.0.1:0", 0).unwrap();
+        let splats = coordinator.render_pass(
+            &cam,
+            &PathTracer,
+            &world,
+            &tiles,
+            4,
+            &mut film,
+            None,
+            &ProgressBar::hidden(),
+        );
+        coordinator.finish();
+        assert!(splats.is_none());
+
+        let mut expected = vec![FilmPixel::default(); film.len()];
+        for tile in &tiles {
+            let mut pixels: Vec<FilmPixel> = tile
+                .pixels(rect.width())
+                .map(|_| FilmPixel::default())
+                .collect();
+            cam.render_tile(&PathTracer, &world, tile, &mut pixels, 4);
+            for (k, pixel) in tile.pixels(rect.width()).zip(pixels) {
+                expected[k] = pixel;
+            }
+        }
+        for (pixel, expected) in film.iter().zip(&expected) {
+            assert_eq!(pixel.samples, 4);
+            assert_eq!((pixel.color - expected.color).length_squared(), 0.0);
+        }
+    }
+
+    type NewIntegrator = fn() -> Box<dyn Integrator>;
+
+    // Renders the camera's image with a coordinator on localhost and `workers`
+    // workers on threads, all using integrators from `integrator`, in two
+    // passes. Returns the film and the integrator's splats.
+    fn render_distributed(
+        cam: &Camera,
+        integrator: NewIntegrator,
+        world: &dyn Hit,
+        tiles: &[Tile],
+        workers: usize,
+    ) -> (Vec<FilmPixel>, Option<Vec<Color>>) {
+        let coordinator_integrator = integrator();
+        coordinator_integrator.preprocess(cam, world);
+        // Pick a free port for the workers to connect to.
+        let address = TcpListener::bind("127.0.0.1:0")
+            .and_then(|listener| listener.local_addr())
+            .unwrap()
+            .to_string();
+        let coordinator =
+            Coordinator::start(cam, coordinator_integrator.as_ref(), &address, 0).unwrap();
+
+        thread::scope(|scope| {
+            let threads: Vec<_> = (0..workers)
+                .map(|_| {
+                    scope.spawn(|| {
+                        let integrator = integrator();
+                        integrator.preprocess(cam, world);
+                        run_worker(cam, &address, integrator.as_ref(), world)
+                    })
+                })
+                .collect();
+            // Every thread of each worker connects.
+            let connections = workers * rayon::current_num_threads();
+            let started = Instant::now();
+            while coordinator.shared.state.lock().unwrap().flushed.len() < connections {
+                assert!(
+                    started.elapsed() < CONNECT_TIMEOUT,
+                    "workers didn't connect"
+                );
+                thread::sleep(Duration::from_millis(10));
+            }
+
+            let rect = cam.film_rect();
+            let mut film = vec![FilmPixel::default(); rect.width() * rect.height()];
+            for end in [cam.samples_per_pixel / 2, cam.samples_per_pixel] {
+                let splats = coordinator.render_pass(
+                    cam,
+                    coordinator_integrator.as_ref(),
+                    world,
+                    tiles,
+                    end,
+                    &mut film,
+                    None,
+                    &ProgressBar::hidden(),
+                );
+                if let Some(splats) = splats {
+                    coordinator_integrator.add_splats(&splats);
+                }
+            }
+            coordinator.finish();
+            for thread in threads {
+                thread.join().unwrap().unwrap();
+            }
+            (film, coordinator_integrator.splats())
+        })
+    }
+
+    // Workers render exactly what the coordinator would have rendered itself,
+    // including the light BDPT splats onto the image.
+    #[test]
+    fn workers_render_the_same_image() {
+        let (lights, world) = lit_sphere();
+        let cam = camera().with_lights(lights).build();
+        let rect = cam.film_rect();
+        let tiles = Tiles {
+            size: 1,
+            order: TileOrder::Scanline,
+        }
+        .split(rect.width(), rect.height());
+        let integrators: [(NewIntegrator, bool); 2] = [
+            (|| Box::new(PathTracer), false),
+            (|| Box::new(BidirectionalPathTracer::new()), true),
+        ];
+        for (integrator, splats_light) in integrators {
+            let (film, splats) = render_distributed(&cam, integrator, &world, &tiles, 2);
+
+            let local = integrator();
+            local.preprocess(&cam, &world);
+            let mut expected = vec![FilmPixel::default(); film.len()];
+            cam.render_pass(
+                local.as_ref(),
+                &world,
+                &tiles,
+                cam.samples_per_pixel,
+                &mut expected,
+                None,
+                &ProgressBar::hidden(),
+            );
+            for (pixel, expected) in film.iter().zip(&expected) {
+                assert_eq!(pixel.samples, 4);
+                assert_eq!((pixel.color - expected.color).length_squared(), 0.0);
+            }
+            let bits = |colors: Option<Vec<Color>>| {
+                colors.map(|colors| {
+                    colors
+                        .iter()
+                        .map(|c| [c.x(), c.y(), c.z()].map(f64::to_bits))
+                        .collect::<Vec<_>>()
+                })
+            };
+            let expected_splats = bits(local.splats());
+            assert_eq!(bits(splats), expected_splats);
+            assert_eq!(expected_splats.is_some(), splats_light);
+        }
+    }
+
+    #[test]
+    fn rejects_workers_with_other_settings() {
+        let cam = camera();
+        let address = TcpListener::bind("127.0.0.1:0")
+            .and_then(|listener| listener.local_addr())
+            .unwrap()
+            .to_string();
+        let coordinator = Coordinator::start(&cam, &PathTracer, &address, 0).unwrap();
+        let worker_cam = camera().with_samples_per_pixel(8).build();
+        let err =
+            run_worker(&worker_cam, &address, &PathTracer, &Hittables::default()).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        assert!(coordinator.shared.state.lock().unwrap().flushed.is_empty());
+        coordinator.finish();
+    }
+}
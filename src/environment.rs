@@ -0,0 +1,323 @@
+use std::f64::consts::PI;
+use std::sync::Arc;
+
+use crate::{
+    color::{Color, luminance},
+    image::Image,
+    light::{Light, LightSample},
+    utils::random_2d,
+    vec3::{Vec3, unit_vector},
+};
+
+// Light arriving from infinitely far away, seen by rays that escape the scene and
+// sampled as a light.
+pub trait Environment: Light {
+    // Returns the radiance arriving from the given direction.
+    fn radiance(&self, direction: Vec3) -> Color;
+
+    // Returns the solid angle density with which sampling the light picks the
+    // given direction.
+    fn pdf(&self, direction: Vec3) -> f64;
+
+    // Returns radiance that is lit through a separate light, like the disk of a
+    // sun, so should only be seen by camera rays and specular bounces.
+    fn unsampled_radiance(&self, _direction: Vec3) -> Color {
+        Color::default()
+    }
+}
+
+// A piecewise constant probability distribution over [0,1), split into equally
+// sized buckets with the given weights.
+struct Distribution1D {
+    weights: Vec<f64>,
+    cdf: Vec<f64>,
+    total: f64,
+}
+
+impl Distribution1D {
+    fn new(weights: Vec<f64>) -> Self {
+        let mut cdf = Vec::with_capacity(weights.len() + 1);
+        let mut total = 0.0;
+        cdf.push(0.0);
+        for w in weights.iter() {
+            total += w;
+            cdf.push(total);
+        }
+        Self {
+            weights,
+            cdf,
+            total,
+        }
+    }
+
+    // Maps a uniform random number u to a bucket index and the continuous position
+    // in [0,1) within the whole distribution.
+    fn sample(&self, u: f64) -> (usize, f64) {
+        let n = self.weights.len();
+        if self.total <= 0.0 {
+            let x = u * n as f64;
+            return ((x as usize).min(n - 1), u);
+        }
+        let target = u * self.total;
+        let i = self
+            .cdf
+            .partition_point(|&c| c <= target)
+            .saturating_sub(1)
+            .min(n - 1);
+        let offset = if self.weights[i] > 0.0 {
+            (target - self.cdf[i]) / self.weights[i]
+        } else {
+            0.0
+        };
+        (i, (i as f64 + offset.clamp(0.0, 1.0)) / n as f64)
+    }
+
+    // Returns the density of bucket i, relative to the uniform density over [0,1).
+    fn pdf(&self, i: usize) -> f64 {
+        if self.total <= 0.0 {
+            1.0
+        } else {
+            self.weights[i] * self.weights.len() as f64 / self.total
+        }
+    }
+}
+
+// Light arriving from infinitely far away in every direction, given by an
+// equirectangular (latitude-longitude) image. The top row of the image is
+// straight up (+y) and the image wraps around the vertical axis.
+pub struct EnvironmentLight {
+    image: Arc<Image>,
+    intensity: f64,
+    // Rotation around the vertical axis, as sin/cos of the angle.
+    sin_rotation: f64,
+    cos_rotation: f64,
+    // Distributions for importance sampling pixels by luminance: one over rows,
+    // and one over the columns of each row.
+    rows: Distribution1D,
+    columns: Vec<Distribution1D>,
+}
+
+impl EnvironmentLight {
+    pub fn new(image: Arc<Image>) -> Self {
+        let (width, height) = (image.width(), image.height());
+        // Weight pixels by the solid angle they cover, which shrinks towards the
+        // poles.
+        let columns: Vec<Distribution1D> = (0..height)
+            .map(|y| {
+                let sin_theta = f64::sin(PI * (y as f64 + 0.5) / height as f64);
+                Distribution1D::new(
+                    (0..width)
+                        .map(|x| luminance(image.pixel(x, y)).max(0.0) * sin_theta)
+                        .collect(),
+                )
+            })
+            .collect();
+        let rows = Distribution1D::new(columns.iter().map(|c| c.total).collect());
+        Self {
+            image,
+            intensity: 1.0,
+            sin_rotation: 0.0,
+            cos_rotation: 1.0,
+            rows,
+            columns,
+        }
+    }
+
+    pub fn with_intensity(mut self, intensity: f64) -> Self {
+        self.intensity = intensity;
+        self
+    }
+
+    // Rotates the environment around the vertical axis by the given angle in
+    // degrees.
+    pub fn with_rotation(mut self, angle: f64) -> Self {
+        let rads = f64::to_radians(angle);
+        self.sin_rotation = f64::sin(rads);
+        self.cos_rotation = f64::cos(rads);
+        self
+    }
+
+    // Picks a direction with probability proportional to the luminance of the
+    // image, returning it along with its solid angle density.
+    pub fn sample_direction(&self) -> Option<(Vec3, f64)> {
+        let (r1, r2) = random_2d();
+        let (y, v) = self.rows.sample(r1);
+        let (_, u) = self.columns[y].sample(r2);
+        let direction = self.direction_of(u, v);
+        let pdf = self.pdf(direction);
+        if pdf <= 0.0 {
+            None
+        } else {
+            Some((direction, pdf))
+        }
+    }
+
+    // Converts a world space direction to the image pixel it maps to.
+    fn pixel_of(&self, direction: Vec3) -> (usize, usize) {
+        let d = unit_vector(direction);
+        // Undo the environment's rotation
+        let x = self.cos_rotation * d.x() - self.sin_rotation * d.z();
+        let z = self.sin_rotation * d.x() + self.cos_rotation * d.z();
+        let theta = f64::acos(d.y().clamp(-1.0, 1.0));
+        let phi = f64::atan2(z, x) + PI;
+
+        let (width, height) = (self.image.width(), self.image.height());
+        let u = phi / (2.0 * PI);
+        let v = theta / PI;
+        (
+            ((u * width as f64) as usize).min(width - 1),
+            ((v * height as f64) as usize).min(height - 1),
+        )
+    }
+
+    // Converts image coordinates (u, v) in [0,1) to a world space direction.
+    fn direction_of(&self, u: f64, v: f64) -> Vec3 {
+        let theta = v * PI;
+        let phi = u * 2.0 * PI - PI;
+        let x = f64::sin(theta) * f64::cos(phi);
+        let z = f64::sin(theta) * f64::sin(phi);
+        // Apply the environment's rotation
+        Vec3::new(
+            self.cos_rotation * x + self.sin_rotation * z,
+            f64::cos(theta),
+            -self.sin_rotation * x + self.cos_rotation * z,
+        )
+    }
+}
+
+impl Environment for EnvironmentLight {
+    fn radiance(&self, direction: Vec3) -> Color {
+        let (x, y) = self.pixel_of(direction);
+        self.image.pixel(x, y) * self.intensity
+    }
+
+    fn pdf(&self, direction: Vec3) -> f64 {
+        let (x, y) = self.pixel_of(direction);
+        let cos_theta = unit_vector(direction).y();
+        let sin_theta = f64::sqrt(f64::max(0.0, 1.0 - cos_theta * cos_theta));
+        if sin_theta <= 0.0 {
+            return 0.0;
+        }
+        self.rows.pdf(y) * self.columns[y].pdf(x) / (2.0 * PI * PI * sin_theta)
+    }
+}
+
+impl Light for EnvironmentLight {
+    fn sample(&self, _p: Vec3) -> Option<LightSample> {
+        let (direction, pdf) = self.sample_direction()?;
+        Some(LightSample {
+            direction,
+            distance: f64::INFINITY,
+            radiance: self.radiance(direction) / pdf,
+            pdf,
+            normal: None,
+        })
+    }
+
+    fn is_infinite(&self) -> bool {
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // A small map with a bright spot, so sampling isn't uniform.
+    fn environment() -> EnvironmentLight {
+        let (width, height) = (8, 4);
+        let data = (0..width * height)
+            .map(|k| {
+                let level = if k == 10 { 20.0 } else { 1.0 + (k % 3) as f64 };
+                Color::new(level, level, level)
+            })
+            .collect();
+        EnvironmentLight::new(Arc::new(Image::new(width, height, data)))
+    }
+
+    #[test]
+    fn distribution_samples_match_its_pdf() {
+        let distribution = Distribution1D::new(vec![1.0, 0.0, 3.0, 4.0]);
+        let n = 1000;
+        let mut counts = [0; 4];
+        for k in 0..n {
+            let (i, x) = distribution.sample((k as f64 + 0.5) / n as f64);
+            assert_eq!(i, (x * 4.0) as usize);
+            counts[i] += 1;
+        }
+        for (i, &count) in counts.iter().enumerate() {
+            let expected = distribution.pdf(i) / 4.0 * n as f64;
+            assert!((count as f64 - expected).abs() <= 1.0, "{i}: {count}");
+        }
+    }
+
+    #[test]
+    fn sampled_directions_match_the_pdf() {
+        let env = environment();
+        let n = 200_000;
+        let mut counts = [0; 32];
+        for _ in 0..n {
+            let (direction, pdf) = env.sample_direction().unwrap();
+            assert!((pdf - env.pdf(direction)).abs() < 1e-9 * pdf);
+            let (x, y) = env.pixel_of(direction);
+            counts[y * 8 + x] += 1;
+        }
+        // The pdf integrated over each pixel, at the pixel's center, times the
+        // solid angle it covers.
+        for (k, &count) in counts.iter().enumerate() {
+            let (x, y) = (k % 8, k / 8);
+            let direction = env.direction_of((x as f64 + 0.5) / 8.0, (y as f64 + 0.5) / 4.0);
+            let theta = PI * (y as f64 + 0.5) / 4.0;
+            let solid_angle = 2.0 * PI / 8.0 * PI / 4.0 * f64::sin(theta);
+            let p = env.pdf(direction) * solid_angle;
+            let sigma = f64::sqrt(n as f64 * p * (1.0 - p));
+            assert!(
+                (count as f64 - n as f64 * p).abs() < 5.0 * sigma,
+                "{k}: {count}"
+            );
+        }
+    }
+
+    #[test]
+    fn pdf_integrates_to_one() {
+        let env = environment().with_rotation(30.0);
+        let (steps_theta, steps_phi) = (400, 800);
+        let mut total = 0.0;
+        for i in 0..steps_theta {
+            let theta = PI * (i as f64 + 0.5) / steps_theta as f64;
+            for j in 0..steps_phi {
+                let phi = 2.0 * PI * (j as f64 + 0.5) / steps_phi as f64;
+                let direction = Vec3::new(
+                    f64::sin(theta) * f64::cos(phi),
+                    f64::cos(theta),
+                    f64::sin(theta) * f64::sin(phi),
+                );
+                total += env.pdf(direction) * f64::sin(theta);
+            }
+        }
+        total *= PI / steps_theta as f64 * 2.0 * PI / steps_phi as f64;
+        assert!((total - 1.0).abs() < 0.01, "{total}");
+    }
+
+    #[test]
+    fn constant_map_integrates_to_pi_l() {
+        let radiance = Color::new(0.5, 1.0, 2.0);
+        let env = EnvironmentLight::new(Arc::new(Image::new(16, 8, vec![radiance; 128])))
+            .with_intensity(2.0);
+        // Irradiance on a surface facing up, from sampling the light.
+        let n = 100_000;
+        let mut irradiance = Color::default();
+        for _ in 0..n {
+            let sample = env.sample(Vec3::default()).unwrap();
+            irradiance += sample.radiance * f64::max(sample.direction.y(), 0.0);
+        }
+        irradiance /= n as f64;
+        let expected = radiance * 2.0 * PI;
+        for c in 0..3 {
+            assert!(
+                (irradiance[c] / expected[c] - 1.0).abs() < 0.02,
+                "{irradiance:?}"
+            );
+        }
+    }
+}
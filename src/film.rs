@@ -0,0 +1,120 @@
+use crate::{
+    adaptive::PixelEstimate, aov::AovPixel, color::Color, filter::ReconstructionFilter, tile::Tile,
+};
+
+// Resolution that filtered sums are rounded to, so that they are exact like the
+// sums in bdpt's SplatFilm. Sums stay exact up to about 500 million.
+const FILTER_UNIT: f64 = 1.0 / (1u64 << 24) as f64;
+
+// Everything accumulated for one pixel while rendering, so that more samples
+// can be added to it later.
+#[derive(Default, Clone)]
+pub struct FilmPixel {
+    // Sum of the pixel's samples, and how many there are.
+    pub color: Color,
+    pub samples: u32,
+    pub estimate: PixelEstimate,
+    pub aov: AovPixel,
+}
+
+impl FilmPixel {
+    // Returns the average of the pixel's samples.
+    pub fn mean(&self) -> Color {
+        self.color / self.samples.max(1) as f64
+    }
+
+    // Appends everything accumulated to `out`, for saving in a checkpoint.
+    pub fn write_values(&self, out: &mut Vec<f64>) {
+        out.extend([self.color.x(), self.color.y(), self.color.z()]);
+        out.push(self.samples as f64);
+        self.estimate.write_values(out);
+        self.aov.write_values(out);
+    }
+
+    // Reads a pixel written by write_values.
+    pub fn read_values(values: &mut impl Iterator<Item = f64>) -> Option<Self> {
+        let color = Color::new(values.next()?, values.next()?, values.next()?);
+        Some(Self {
+            color,
+            samples: values.next()? as u32,
+            estimate: PixelEstimate::read_values(values)?,
+            aov: AovPixel::read_values(values)?,
+        })
+    }
+}
+
+// Sums of samples weighted by a reconstruction filter, from every sample within
+// the filter's radius of a pixel rather than just the pixel's own.
+#[derive(Default, Clone, Copy)]
+pub struct FilteredPixel {
+    pub color: Color,
+    pub weight: f64,
+}
+
+impl FilteredPixel {
+    // Returns the weighted average of the samples, or None if their weights
+    // don't add up to anything positive, which filters with negative lobes can
+    // cause.
+    pub fn value(&self) -> Option<Color> {
+        (self.weight > 0.0).then(|| self.color / self.weight)
+    }
+}
+
+// What the samples of one tile add to the filtered pixels around them, which
+// reach past the tile by up to the filter's radius.
+pub struct FilteredTile {
+    pub region: Tile,
+    pub pixels: Vec<FilteredPixel>,
+}
+
+impl FilteredTile {
+    pub fn new(region: Tile) -> Self {
+        Self {
+            region,
+            pixels: vec![FilteredPixel::default(); region.width() * region.height()],
+        }
+    }
+
+    // Adds a sample's color with the given weight to the pixel at (x, y).
+    pub fn add(&mut self, x: usize, y: usize, color: Color, weight: f64) {
+        let quantize = |value: f64| (value / FILTER_UNIT).round() * FILTER_UNIT;
+        let k = (y - self.region.y0) * self.region.width() + x - self.region.x0;
+        let pixel = &mut self.pixels[k];
+        let color = color * weight;
+        pixel.color += Color::new(
+            quantize(color.x()),
+            quantize(color.y()),
+            quantize(color.z()),
+        );
+        pixel.weight += quantize(weight);
+    }
+
+    // Adds a sample at (x, y), in pixels from the film's corner, to every pixel
+    // of the region whose center is within the filter's radius of it.
+    pub fn add_sample(&mut self, filter: &ReconstructionFilter, x: f64, y: f64, color: Color) {
+        // Returns the pixels along one axis, within [start, end), whose centers
+        // are within the filter's radius of a sample at s.
+        let within = |s: f64, start: usize, end: usize| {
+            let first = (s - 0.5 - filter.radius).ceil().max(start as f64) as usize;
+            let last = ((s - 0.5 + filter.radius).floor() + 1.0).clamp(0.0, end as f64) as usize;
+            first..last
+        };
+        let region = self.region;
+        for py in within(y, region.y0, region.y1) {
+            for px in within(x, region.x0, region.x1) {
+                let weight = filter.evaluate(px as f64 + 0.5 - x, py as f64 + 0.5 - y);
+                if weight != 0.0 {
+                    self.add(px, py, color, weight);
+                }
+            }
+        }
+    }
+
+    // Adds the tile's sums to a film of the given width.
+    pub fn add_to(&self, film: &mut [FilteredPixel], width: usize) {
+        for (k, pixel) in self.region.pixels(width).zip(&self.pixels) {
+            film[k].color += pixel.color;
+            film[k].weight += pixel.weight;
+        }
+    }
+}
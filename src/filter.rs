@@ -0,0 +1,162 @@
+use std::f64::consts::PI;
+use std::str::FromStr;
+
+// The shape of a pixel reconstruction filter.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Filter {
+    // Every sample within the radius counts equally.
+    Box,
+    // Weights fall off linearly to zero at the radius.
+    Tent,
+    // A Gaussian with a standard deviation of a third of the radius, shifted
+    // down to reach zero at the radius.
+    Gaussian,
+    // The Mitchell-Netravali cubic with B = C = 1/3, a good compromise between
+    // blurring and ringing. Its negative lobes sharpen edges.
+    Mitchell,
+    // A sinc windowed by a wider sinc, with as many lobes as the radius. The
+    // sharpest of the filters, but rings around bright edges.
+    Lanczos,
+}
+
+impl FromStr for Filter {
+    type Err = ();
+
+    // Parses the filter's name in lower case, e.g. `mitchell`.
+    fn from_str(name: &str) -> Result<Self, ()> {
+        Ok(match name {
+            "box" => Filter::Box,
+            "tent" => Filter::Tent,
+            "gaussian" => Filter::Gaussian,
+            "mitchell" => Filter::Mitchell,
+            "lanczos" => Filter::Lanczos,
+            _ => return Err(()),
+        })
+    }
+}
+
+// How samples are combined into pixels: each sample adds to every pixel whose
+// center is within `radius` pixels of it in both directions, weighted by the
+// filter at their offset, and pixels are the weighted average of their samples.
+#[derive(Clone, Copy)]
+pub struct ReconstructionFilter {
+    pub filter: Filter,
+    pub radius: f64,
+}
+
+impl ReconstructionFilter {
+    // Creates a filter reaching `radius` pixels. Radii below half a pixel, where
+    // samples could miss every pixel center, are raised to half a pixel.
+    pub fn new(filter: Filter, radius: f64) -> Self {
+        Self {
+            filter,
+            radius: radius.max(0.5),
+        }
+    }
+
+    // Returns the weight of a sample offset (dx, dy) pixels from a pixel's
+    // center. Filters are separable, the product of their weights along x and y.
+    pub fn evaluate(&self, dx: f64, dy: f64) -> f64 {
+        self.evaluate_1d(dx) * self.evaluate_1d(dy)
+    }
+
+    fn evaluate_1d(&self, x: f64) -> f64 {
+        let r = self.radius;
+        let x = x.abs();
+        if x > r {
+            return 0.0;
+        }
+        match self.filter {
+            Filter::Box => 1.0,
+            Filter::Tent => r - x,
+            Filter::Gaussian => {
+                let sigma = r / 3.0;
+                let gaussian = |x: f64| f64::exp(-x * x / (2.0 * sigma * sigma));
+                gaussian(x) - gaussian(r)
+            }
+            // The cubic covers [-2, 2], so it's stretched to the radius.
+            Filter::Mitchell => mitchell(2.0 * x / r, 1.0 / 3.0, 1.0 / 3.0),
+            Filter::Lanczos => sinc(x) * sinc(x / r),
+        }
+    }
+}
+
+fn mitchell(x: f64, b: f64, c: f64) -> f64 {
+    let value = if x > 1.0 {
+        (-b - 6.0 * c) * x.powi(3)
+            + (6.0 * b + 30.0 * c) * x.powi(2)
+            + (-12.0 * b - 48.0 * c) * x
+            + (8.0 * b + 24.0 * c)
+    } else {
+        (12.0 - 9.0 * b - 6.0 * c) * x.powi(3)
+            + (-18.0 + 12.0 * b + 6.0 * c) * x.powi(2)
+            + (6.0 - 2.0 * b)
+    };
+    value / 6.0
+}
+
+fn sinc(x: f64) -> f64 {
+    if x < 1e-5 {
+        return 1.0;
+    }
+    f64::sin(PI * x) / (PI * x)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const FILTERS: [Filter; 5] = [
+        Filter::Box,
+        Filter::Tent,
+        Filter::Gaussian,
+        Filter::Mitchell,
+        Filter::Lanczos,
+    ];
+
+    fn assert_close(a: f64, b: f64) {
+        assert!((a - b).abs() < 1e-9, "{a} != {b}");
+    }
+
+    #[test]
+    fn weights_are_symmetric_and_vanish_outside_the_radius() {
+        for filter in FILTERS {
+            let f = ReconstructionFilter::new(filter, 2.0);
+            for x in [0.0, 0.3, 1.0, 1.7] {
+                assert_close(f.evaluate(x, 0.2), f.evaluate(-x, -0.2));
+                assert_close(f.evaluate(x, 0.2), f.evaluate_1d(x) * f.evaluate_1d(0.2));
+            }
+            for x in [2.01, 3.0, 100.0] {
+                assert_eq!(f.evaluate(x, 0.0), 0.0, "{filter:?}");
+                assert_eq!(f.evaluate(0.0, -x), 0.0, "{filter:?}");
+            }
+            assert!(f.evaluate(0.0, 0.0) > 0.0, "{filter:?}");
+        }
+    }
+
+    #[test]
+    fn filter_shapes() {
+        let weight = |filter, radius, x| ReconstructionFilter::new(filter, radius).evaluate_1d(x);
+        assert_eq!(weight(Filter::Box, 1.5, 1.4), 1.0);
+        assert_close(weight(Filter::Tent, 2.0, 0.5), 1.5);
+        assert_close(weight(Filter::Tent, 2.0, 2.0), 0.0);
+        assert_close(weight(Filter::Gaussian, 1.5, 1.5), 0.0);
+        assert!(weight(Filter::Gaussian, 1.5, 0.5) < weight(Filter::Gaussian, 1.5, 0.0));
+        // The Mitchell-Netravali cubic is (6 - 2B) / 6 at its center, with
+        // negative lobes and zero at its edge.
+        assert_close(weight(Filter::Mitchell, 2.0, 0.0), 8.0 / 9.0);
+        assert!(weight(Filter::Mitchell, 2.0, 1.5) < 0.0);
+        assert_close(weight(Filter::Mitchell, 2.0, 2.0), 0.0);
+        // Lanczos is one at its center and zero at every other whole pixel.
+        assert_close(weight(Filter::Lanczos, 3.0, 0.0), 1.0);
+        for x in [1.0, 2.0, 3.0] {
+            assert_close(weight(Filter::Lanczos, 3.0, x), 0.0);
+        }
+    }
+
+    #[test]
+    fn radius_is_at_least_half_a_pixel() {
+        assert_eq!(ReconstructionFilter::new(Filter::Box, 0.1).radius, 0.5);
+        assert_eq!(ReconstructionFilter::new(Filter::Box, 0.75).radius, 0.75);
+    }
+}
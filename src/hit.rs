@@ -1,4 +1,10 @@
-use std::{default, sync::Arc};
+use std::{
+    default,
+    sync::{
+        Arc,
+        atomic::{AtomicU32, Ordering},
+    },
+};
 
 use crate::{
     aabb::AABB,
@@ -6,6 +12,7 @@ use crate::{
     interval::Interval,
     material::{Lambertian, Material},
     ray::Ray,
+    utils::random_f64,
     vec3::{Vec3, dot},
 };
 
@@ -18,6 +25,12 @@ pub struct HitRecord {
     pub mat: Arc<dyn Material>,
     pub u: f64,
     pub v: f64,
+    // Partial derivatives of the surface position with respect to (u, v). These
+    // span the tangent plane and are used to orient shading normal perturbations.
+    pub dpdu: Vec3,
+    pub dpdv: Vec3,
+    // Identifies the primitive that was hit, see next_object_id.
+    pub object_id: u32,
 }
 
 impl Default for HitRecord {
@@ -30,6 +43,9 @@ impl Default for HitRecord {
             mat: Arc::new(Lambertian::new_color(Color::default())),
             u: Default::default(),
             v: Default::default(),
+            dpdu: Vec3::default(),
+            dpdv: Vec3::default(),
+            object_id: 0,
         }
     }
 }
@@ -47,6 +63,17 @@ impl HitRecord {
     }
 }
 
+static NEXT_OBJECT_ID: AtomicU32 = AtomicU32::new(1);
+
+// Returns a new unique id for a primitive to report in its hit records. Zero is
+// left for "no object". Ids are handed out in the order primitives are created,
+// so a scene gets the same ids on every run, but adding or reordering objects
+// renumbers the ones created after them. Primitives making up one object, like
+// the sides of a box, share its id.
+pub fn next_object_id() -> u32 {
+    NEXT_OBJECT_ID.fetch_add(1, Ordering::Relaxed)
+}
+
 pub trait Hit: Send + Sync {
     // Determines if the given ray will hit the implementer such that t lies in
     // the interval (ray_tmin, ray_tmax). If so, the HitRecord struct will be populated
@@ -55,6 +82,19 @@ pub trait Hit: Send + Sync {
 
     // Returns an axis-aligned bounding box surrounding this object.
     fn bounding_box(&self) -> AABB;
+
+    // Returns the surface area of the object. Only objects that can be sampled
+    // with sample_surface need to provide it.
+    fn area(&self) -> f64 {
+        0.0
+    }
+
+    // Picks a point uniformly over the object's surface at the given time,
+    // returning it along with the outward unit normal there. Returns None for
+    // objects that can't be sampled, which therefore can't be area lights.
+    fn sample_surface(&self, _time: f64) -> Option<(Vec3, Vec3)> {
+        None
+    }
 }
 
 #[derive(Default)]
@@ -109,6 +149,24 @@ impl Hit for Hittables {
     fn bounding_box(&self) -> AABB {
         self.bbox
     }
+
+    fn area(&self) -> f64 {
+        self.objects.iter().map(|object| object.area()).sum()
+    }
+
+    // Picks an object with probability proportional to its area, then a point
+    // on it.
+    fn sample_surface(&self, time: f64) -> Option<(Vec3, Vec3)> {
+        let mut target = random_f64() * self.area();
+        for object in self.objects.iter() {
+            let area = object.area();
+            if target < area {
+                return object.sample_surface(time);
+            }
+            target -= area;
+        }
+        None
+    }
 }
 
 pub struct Translated {
@@ -142,6 +200,15 @@ impl Hit for Translated {
         rec.p += self.offset;
         true
     }
+
+    fn area(&self) -> f64 {
+        self.object.area()
+    }
+
+    fn sample_surface(&self, time: f64) -> Option<(Vec3, Vec3)> {
+        let (p, normal) = self.object.sample_surface(time)?;
+        Some((p + self.offset, normal))
+    }
 }
 
 pub struct Rotated {
@@ -189,6 +256,15 @@ impl Rotated {
             bbox: AABB::from((mn, mx)),
         }
     }
+
+    // Transforms a vector from object space back into world space.
+    fn rotate_to_world(&self, v: Vec3) -> Vec3 {
+        Vec3::new(
+            (self.cos_theta * v.x()) + (self.sin_theta * v.z()),
+            v.y(),
+            (-self.sin_theta * v.x()) + (self.cos_theta * v.z()),
+        )
+    }
 }
 
 impl Hit for Rotated {
@@ -210,16 +286,10 @@ impl Hit for Rotated {
             return false;
         }
 
-        rec.p = Vec3::new(
-            (self.cos_theta * rec.p.x()) + (self.sin_theta * rec.p.z()),
-            rec.p.y(),
-            (-self.sin_theta * rec.p.x()) + (self.cos_theta * rec.p.z()),
-        );
-        rec.normal = Vec3::new(
-            (self.cos_theta * rec.normal.x()) + (self.sin_theta * rec.normal.z()),
-            rec.normal.y(),
-            (-self.sin_theta * rec.normal.x()) + (self.cos_theta * rec.normal.z()),
-        );
+        rec.p = self.rotate_to_world(rec.p);
+        rec.normal = self.rotate_to_world(rec.normal);
+        rec.dpdu = self.rotate_to_world(rec.dpdu);
+        rec.dpdv = self.rotate_to_world(rec.dpdv);
 
         true
     }
@@ -227,4 +297,13 @@ impl Hit for Rotated {
     fn bounding_box(&self) -> AABB {
         self.bbox
     }
+
+    fn area(&self) -> f64 {
+        self.object.area()
+    }
+
+    fn sample_surface(&self, time: f64) -> Option<(Vec3, Vec3)> {
+        let (p, normal) = self.object.sample_surface(time)?;
+        Some((self.rotate_to_world(p), self.rotate_to_world(normal)))
+    }
 }
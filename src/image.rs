@@ -0,0 +1,406 @@
+use std::fs;
+use std::io::{self, Write};
+use std::path::Path;
+
+use crate::color::Color;
+
+// A simple in-memory RGB image. Pixel values are stored exactly as they appear
+// in the file: LDR formats are scaled into [0,1], HDR formats are left as is.
+pub struct Image {
+    width: usize,
+    height: usize,
+    data: Vec<Color>,
+}
+
+impl Image {
+    pub fn new(width: usize, height: usize, data: Vec<Color>) -> Self {
+        assert_eq!(data.len(), width * height);
+        Self {
+            width,
+            height,
+            data,
+        }
+    }
+
+    // Loads an image from disk. Supported formats are PPM (P3 and P6) and
+    // Radiance HDR (.hdr/.pic).
+    pub fn load(path: impl AsRef<Path>) -> io::Result<Self> {
+        let bytes = fs::read(path)?;
+        match bytes.get(..2) {
+            Some(b"#?") => parse_hdr(&bytes),
+            Some(b"P3") | Some(b"P6") => parse_ppm(&bytes),
+            _ if bytes.starts_with(&[0x76, 0x2f, 0x31, 0x01]) => {
+                Err(invalid("OpenEXR images are not supported, convert to .hdr"))
+            }
+            _ => Err(invalid("unrecognized image format")),
+        }
+    }
+
+    pub fn width(&self) -> usize {
+        self.width
+    }
+
+    pub fn height(&self) -> usize {
+        self.height
+    }
+
+    // Returns the pixel at column x, row y. Out of range coordinates are
+    // clamped to the edge of the image.
+    pub fn pixel(&self, x: usize, y: usize) -> Color {
+        let x = x.min(self.width - 1);
+        let y = y.min(self.height - 1);
+        self.data[y * self.width + x]
+    }
+
+    // Writes the image as a Portable Float Map, which keeps values outside [0,1]
+    // exactly, for compositing and other tools to read.
+    pub fn save_pfm(&self, path: impl AsRef<Path>) -> io::Result<()> {
+        let mut out = io::BufWriter::new(fs::File::create(path)?);
+        // A negative scale marks the data as little endian. Rows are stored
+        // from the bottom of the image up.
+        write!(out, "PF\n{} {}\n-1.0\n", self.width, self.height)?;
+        for row in self.data.chunks_exact(self.width).rev() {
+            for color in row {
+                for c in 0..3 {
+                    out.write_all(&(color[c] as f32).to_le_bytes())?;
+                }
+            }
+        }
+        out.flush()
+    }
+}
+
+fn invalid(msg: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg.to_string())
+}
+
+// Reads whitespace separated header tokens, skipping `#` comments.
+struct Tokens<'a> {
+    bytes: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Tokens<'a> {
+    fn next_token(&mut self) -> io::Result<&'a str> {
+        loop {
+            match self.bytes.get(self.pos) {
+                Some(b'#') => {
+                    while self.pos < self.bytes.len() && self.bytes[self.pos] != b'\n' {
+                        self.pos += 1;
+                    }
+                }
+                Some(c) if c.is_ascii_whitespace() => self.pos += 1,
+                Some(_) => break,
+                None => return Err(invalid("unexpected end of PPM data")),
+            }
+        }
+        let start = self.pos;
+        while self.pos < self.bytes.len() && !self.bytes[self.pos].is_ascii_whitespace() {
+            self.pos += 1;
+        }
+        std::str::from_utf8(&self.bytes[start..self.pos]).map_err(|_| invalid("bad PPM token"))
+    }
+
+    fn next_usize(&mut self) -> io::Result<usize> {
+        self.next_token()?
+            .parse()
+            .map_err(|_| invalid("bad PPM number"))
+    }
+}
+
+fn parse_ppm(bytes: &[u8]) -> io::Result<Image> {
+    let mut tokens = Tokens { bytes, pos: 0 };
+    let magic = tokens.next_token()?;
+    if magic != "P3" && magic != "P6" {
+        return Err(invalid("unsupported image format, expected PPM"));
+    }
+    let width = tokens.next_usize()?;
+    let height = tokens.next_usize()?;
+    let maxval = tokens.next_usize()?;
+    if width == 0 || height == 0 || maxval == 0 || maxval > 65535 {
+        return Err(invalid("bad PPM header"));
+    }
+    let scale = 1.0 / maxval as f64;
+    let count = width
+        .checked_mul(height)
+        .and_then(|n| n.checked_mul(3))
+        .ok_or_else(|| invalid("bad PPM header"))?;
+
+    let samples: Vec<f64> = if magic == "P3" {
+        (0..count)
+            .map(|_| tokens.next_usize().map(|s| s as f64 * scale))
+            .collect::<io::Result<_>>()?
+    } else {
+        // Exactly one whitespace byte separates the header from the raster.
+        let raster = bytes
+            .get(tokens.pos + 1..)
+            .ok_or_else(|| invalid("truncated PPM raster"))?;
+        let width_bytes = if maxval < 256 { 1 } else { 2 };
+        if raster.len() / width_bytes < count {
+            return Err(invalid("truncated PPM raster"));
+        }
+        if width_bytes == 1 {
+            raster[..count].iter().map(|&s| s as f64 * scale).collect()
+        } else {
+            raster[..count * 2]
+                .chunks_exact(2)
+                .map(|s| u16::from_be_bytes([s[0], s[1]]) as f64 * scale)
+                .collect()
+        }
+    };
+
+    let data = samples
+        .chunks_exact(3)
+        .map(|s| Color::new(s[0], s[1], s[2]))
+        .collect();
+    Ok(Image::new(width, height, data))
+}
+
+// Parses a Radiance RGBE image, in either flat or run-length encoded form.
+fn parse_hdr(bytes: &[u8]) -> io::Result<Image> {
+    let mut pos = 0;
+    let mut next_line = || -> io::Result<&[u8]> {
+        let start = pos;
+        let len = bytes[start..]
+            .iter()
+            .position(|&c| c == b'\n')
+            .ok_or_else(|| invalid("truncated HDR header"))?;
+        pos = start + len + 1;
+        Ok(&bytes[start..start + len])
+    };
+
+    // Header lines run until the first blank line.
+    loop {
+        let line = next_line()?;
+        if line.is_empty() {
+            break;
+        }
+        if let Some(format) = line.strip_prefix(b"FORMAT=")
+            && format != b"32-bit_rle_rgbe"
+        {
+            return Err(invalid("unsupported HDR pixel format"));
+        }
+    }
+
+    // Only the standard orientation (top to bottom, left to right) is supported.
+    let resolution = std::str::from_utf8(next_line()?).map_err(|_| invalid("bad HDR size"))?;
+    let fields: Vec<&str> = resolution.split_whitespace().collect();
+    let (height, width) = match fields.as_slice() {
+        ["-Y", h, "+X", w] => (
+            h.parse::<usize>().map_err(|_| invalid("bad HDR size"))?,
+            w.parse::<usize>().map_err(|_| invalid("bad HDR size"))?,
+        ),
+        _ => return Err(invalid("unsupported HDR orientation")),
+    };
+    if width == 0 || height == 0 || width.checked_mul(height).is_none() {
+        return Err(invalid("bad HDR size"));
+    }
+
+    let mut data = &bytes[pos..];
+    let mut pixels = Vec::with_capacity(width * height);
+    let mut scanline = vec![[0u8; 4]; width];
+    for _ in 0..height {
+        data = read_hdr_scanline(data, &mut scanline)?;
+        pixels.extend(scanline.iter().map(|&rgbe| rgbe_to_color(rgbe)));
+    }
+    Ok(Image::new(width, height, pixels))
+}
+
+// Decodes one scanline into `scanline`, returning the remaining data.
+fn read_hdr_scanline<'a>(mut data: &'a [u8], scanline: &mut [[u8; 4]]) -> io::Result<&'a [u8]> {
+    let truncated = || invalid("truncated HDR data");
+    let width = scanline.len();
+
+    // New-style RLE scanlines start with 2, 2 and the width as a big endian u16.
+    let is_rle = (8..32768).contains(&width)
+        && data.len() >= 4
+        && data[0] == 2
+        && data[1] == 2
+        && data[2] & 0x80 == 0;
+    if !is_rle {
+        if data.len() < width * 4 {
+            return Err(truncated());
+        }
+        for (pixel, chunk) in scanline.iter_mut().zip(data.chunks_exact(4)) {
+            // Old-style RLE (from before 1991) marks runs with pixels whose
+            // mantissas are all one, which would otherwise decode as colors.
+            if chunk[..3] == [1, 1, 1] {
+                return Err(invalid(
+                    "old-style run length encoded HDR data is not supported",
+                ));
+            }
+            pixel.copy_from_slice(chunk);
+        }
+        return Ok(&data[width * 4..]);
+    }
+
+    if ((data[2] as usize) << 8 | data[3] as usize) != width {
+        return Err(invalid("bad HDR scanline width"));
+    }
+    data = &data[4..];
+    // Each of the four channels is stored separately as runs and literals.
+    for channel in 0..4 {
+        let mut x = 0;
+        while x < width {
+            let (&count, rest) = data.split_first().ok_or_else(truncated)?;
+            data = rest;
+            if count > 128 {
+                let count = (count - 128) as usize;
+                let (&value, rest) = data.split_first().ok_or_else(truncated)?;
+                data = rest;
+                if x + count > width {
+                    return Err(invalid("bad HDR run length"));
+                }
+                for pixel in &mut scanline[x..x + count] {
+                    pixel[channel] = value;
+                }
+                x += count;
+            } else {
+                let count = count as usize;
+                if count == 0 || x + count > width || data.len() < count {
+                    return Err(invalid("bad HDR run length"));
+                }
+                for (pixel, &value) in scanline[x..x + count].iter_mut().zip(data) {
+                    pixel[channel] = value;
+                }
+                data = &data[count..];
+                x += count;
+            }
+        }
+    }
+    Ok(data)
+}
+
+fn rgbe_to_color(rgbe: [u8; 4]) -> Color {
+    if rgbe[3] == 0 {
+        return Color::default();
+    }
+    let f = 2f64.powi(rgbe[3] as i32 - (128 + 8));
+    Color::new(
+        (rgbe[0] as f64 + 0.5) * f,
+        (rgbe[1] as f64 + 0.5) * f,
+        (rgbe[2] as f64 + 0.5) * f,
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn assert_close(color: Color, expected: [f64; 3]) {
+        for c in 0..3 {
+            assert!(
+                (color[c] - expected[c]).abs() < 1e-9,
+                "{color:?} != {expected:?}"
+            );
+        }
+    }
+
+    #[test]
+    fn parses_ascii_ppm() {
+        let image = parse_ppm(b"P3\n# a comment\n2 1\n255\n255 0 0  0 0 51\n").unwrap();
+        assert_eq!((image.width(), image.height()), (2, 1));
+        assert_close(image.pixel(0, 0), [1.0, 0.0, 0.0]);
+        assert_close(image.pixel(1, 0), [0.0, 0.0, 0.2]);
+    }
+
+    #[test]
+    fn parses_binary_ppm() {
+        let image = parse_ppm(b"P6 1 2 255\n\xff\x00\x00\x00\x33\x00").unwrap();
+        assert_eq!((image.width(), image.height()), (1, 2));
+        assert_close(image.pixel(0, 0), [1.0, 0.0, 0.0]);
+        assert_close(image.pixel(0, 1), [0.0, 0.2, 0.0]);
+
+        let image = parse_ppm(b"P6 1 1 65535\n\xff\xff\x00\x00\x00\x00").unwrap();
+        assert_close(image.pixel(0, 0), [1.0, 0.0, 0.0]);
+    }
+
+    #[test]
+    fn rejects_truncated_ppm() {
+        for bytes in [
+            &b"P6 2 1 255"[..],
+            b"P6 2 1 255\n",
+            b"P6 2 1 255\n\x00\x00\x00\x00\x00",
+            b"P6 1 1 65535\n\x00\x00\x00\x00\x00",
+            b"P3 1 1 255 0 0",
+            b"P3 1 1",
+        ] {
+            let err = parse_ppm(bytes).err().expect("truncated PPM should fail");
+            assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        }
+    }
+
+    #[test]
+    fn rejects_bad_ppm_headers() {
+        for bytes in [
+            &b"P6 0 1 255\n"[..],
+            b"P6 1 1 0\n",
+            b"P6 1 1 70000\n",
+            b"P6 x 1 255\n",
+            b"P6 18446744073709551615 18446744073709551615 255\n",
+        ] {
+            let err = parse_ppm(bytes).err().expect("bad PPM header should fail");
+            assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        }
+    }
+
+    fn hdr(width: usize, height: usize, pixels: &[u8]) -> Vec<u8> {
+        let mut bytes = b"#?RADIANCE\nFORMAT=32-bit_rle_rgbe\n\n".to_vec();
+        bytes.extend(format!("-Y {height} +X {width}\n").bytes());
+        bytes.extend(pixels);
+        bytes
+    }
+
+    #[test]
+    fn parses_flat_hdr() {
+        let image = parse_hdr(&hdr(2, 1, &[128, 64, 0, 129, 0, 0, 0, 0])).unwrap();
+        assert_eq!((image.width(), image.height()), (2, 1));
+        let f = 2f64.powi(129 - 136);
+        assert_close(image.pixel(0, 0), [128.5 * f, 64.5 * f, 0.5 * f]);
+        assert_close(image.pixel(1, 0), [0.0, 0.0, 0.0]);
+    }
+
+    #[test]
+    fn parses_run_length_encoded_hdr() {
+        // A run of eight for red, eight literals for green, and runs of four
+        // for blue and the exponent.
+        let mut pixels = vec![2, 2, 0, 8, 128 + 8, 200, 8];
+        pixels.extend(0..8);
+        pixels.extend([128 + 4, 10, 128 + 4, 20, 128 + 4, 130, 128 + 4, 131]);
+        let image = parse_hdr(&hdr(8, 1, &pixels)).unwrap();
+        assert_eq!((image.width(), image.height()), (8, 1));
+        for x in 0..8 {
+            let (blue, f) = if x < 4 {
+                (10.5, 2f64.powi(130 - 136))
+            } else {
+                (20.5, 2f64.powi(131 - 136))
+            };
+            assert_close(
+                image.pixel(x, 0),
+                [200.5 * f, (x as f64 + 0.5) * f, blue * f],
+            );
+        }
+    }
+
+    #[test]
+    fn rejects_bad_hdr_data() {
+        for bytes in [
+            // Truncated flat and run-length encoded scanlines.
+            hdr(2, 1, &[128, 64, 0, 129, 0, 0, 0]),
+            hdr(8, 1, &[2, 2, 0, 8, 128 + 8, 200, 128 + 8]),
+            hdr(8, 2, &[2, 2, 0, 8, 136, 1, 136, 2, 136, 3, 136, 4]),
+            // Runs past the end of the scanline, and a wrong scanline width.
+            hdr(8, 1, &[2, 2, 0, 8, 128 + 9, 200]),
+            hdr(8, 1, &[2, 2, 0, 9, 128 + 8, 200]),
+            // An old-style run, repeating the previous pixel.
+            hdr(2, 1, &[128, 64, 0, 129, 1, 1, 1, 3]),
+            // Missing the blank line ending the header.
+            b"#?RADIANCE\nFORMAT=32-bit_rle_rgbe\n".to_vec(),
+            hdr(0, 1, &[]),
+            b"#?RADIANCE\n\n+Y 1 +X 1\n\0\0\0\0".to_vec(),
+        ] {
+            let err = parse_hdr(&bytes).err().expect("bad HDR should fail");
+            assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        }
+    }
+}
@@ -0,0 +1,420 @@
+use crate::{
+    bvh,
+    camera::Camera,
+    color::{Color, heat},
+    hit::{Hit, HitRecord},
+    interval::Interval,
+    ray::Ray,
+    utils::random_f64,
+    vec3::{Vec3, unit_vector},
+};
+
+// An algorithm for computing the light arriving at the camera along a ray.
+// Integrators read the scene setup (lights, background, depth limits) from the
+// camera.
+pub trait Integrator: Send + Sync {
+    // Estimates the radiance arriving at the camera backwards along ray r.
+    fn li(&self, cam: &Camera, r: &Ray, world: &dyn Hit) -> Color;
+
+    // Like li, but split by how the light got to the camera. Integrators that
+    // can't tell report everything as indirect.
+    fn li_paths(&self, cam: &Camera, r: &Ray, world: &dyn Hit) -> LightPaths {
+        LightPaths {
+            indirect: self.li(cam, r, world),
+            ..Default::default()
+        }
+    }
+
+    // Called once before rendering starts, after the camera is initialized.
+    fn preprocess(&self, _cam: &Camera, _world: &dyn Hit) {}
+
+    // Returns light that was added straight to pixels rather than returned from
+    // li, summed over all samples, in image order. The camera divides it by the
+    // number of samples per pixel.
+    fn splats(&self) -> Option<Vec<Color>> {
+        None
+    }
+
+    // Adds light from an earlier render, as returned by splats, when resuming
+    // it. Called after preprocess.
+    fn add_splats(&self, _splats: &[Color]) {}
+
+    // Returns whether li returns display values, to be written to the image
+    // without exposure, tone mapping or encoding, rather than radiance.
+    fn outputs_display_values(&self) -> bool {
+        false
+    }
+
+    // Describes the integrator and its settings, such as `ambient_occlusion 10
+    // 4`, so that the processes of a distributed render can check they all use
+    // the same one.
+    fn describe(&self) -> String;
+}
+
+// Radiance along a camera ray, split by the number of surfaces the light
+// interacted with on its way.
+#[derive(Default, Clone, Copy)]
+pub struct LightPaths {
+    // Emitters and the background, seen directly.
+    pub emission: Color,
+    // Light reflected once, straight from a light by the first surface hit.
+    pub direct: Color,
+    // Everything else.
+    pub indirect: Color,
+}
+
+impl LightPaths {
+    pub fn total(&self) -> Color {
+        self.emission + self.direct + self.indirect
+    }
+
+    // Adds light that reached the camera after the given number of bounces.
+    pub fn add(&mut self, bounces: u32, color: Color) {
+        match bounces {
+            0 => self.emission += color,
+            1 => self.direct += color,
+            _ => self.indirect += color,
+        }
+    }
+}
+
+// A unidirectional path tracer. Paths start at the camera and bounce off
+// surfaces by sampling their materials, sampling lights directly at each bounce,
+// until they escape the scene, get absorbed or are terminated.
+pub struct PathTracer;
+
+impl Integrator for PathTracer {
+    fn li(&self, cam: &Camera, r: &Ray, world: &dyn Hit) -> Color {
+        self.li_paths(cam, r, world).total()
+    }
+
+    fn describe(&self) -> String {
+        "path_tracer".to_string()
+    }
+
+    fn li_paths(&self, cam: &Camera, r: &Ray, world: &dyn Hit) -> LightPaths {
+        let mut color = LightPaths::default();
+        // Fraction of the light arriving along `ray` that makes it to the camera.
+        let mut throughput = Color::new(1.0, 1.0, 1.0);
+        let mut ray = *r;
+        // Density with which the current ray was sampled, None for camera rays and
+        // specular bounces.
+        let mut scatter_pdf = None;
+
+        for bounce in 0..cam.max_depth {
+            let mut rec = HitRecord::default();
+            if !world.hit(&ray, Interval::new(0.001, f64::INFINITY), &mut rec) {
+                color.add(
+                    bounce,
+                    throughput * cam.background_color(&ray, bounce == 0, scatter_pdf),
+                );
+                break;
+            }
+
+            let emitted = rec.mat.emitted(&ray, &rec);
+            if emitted.max_component() > 0.0 {
+                color.add(
+                    bounce,
+                    throughput * emitted * cam.emission_weight(&ray, &rec, scatter_pdf),
+                );
+            }
+            let mut scattered = Ray::default();
+            let mut attenuation = Color::default();
+            if !rec
+                .mat
+                .scatter(&ray, &rec, &mut attenuation, &mut scattered)
+            {
+                break;
+            }
+            color.add(
+                bounce + 1,
+                throughput * cam.sample_lights(&ray, &rec, attenuation, world),
+            );
+            throughput = throughput * attenuation;
+
+            // Past the minimum depth, continue the path with probability
+            // proportional to its throughput, boosting survivors to keep the
+            // estimate unbiased.
+            if cam
+                .russian_roulette_depth
+                .is_some_and(|min| bounce + 1 >= min)
+            {
+                let survival = f64::min(throughput.max_component(), 0.95);
+                if random_f64() >= survival {
+                    break;
+                }
+                throughput /= survival;
+            }
+
+            let pdf = rec.mat.scattering_pdf(&ray, &rec, &scattered);
+            scatter_pdf = if pdf > 0.0 { Some(pdf) } else { None };
+            ray = scattered;
+        }
+        color
+    }
+}
+
+// Renders ambient occlusion: the fraction of the hemisphere above each visible
+// point that isn't blocked by geometry within `radius`, weighted by cosine.
+// Rays that hit nothing are fully unoccluded.
+pub struct AmbientOcclusion {
+    radius: f64,
+    samples: u32,
+}
+
+impl AmbientOcclusion {
+    pub fn new(radius: f64) -> Self {
+        Self { radius, samples: 1 }
+    }
+
+    // Sets the number of occlusion rays traced per camera sample.
+    pub fn with_samples(mut self, samples: u32) -> Self {
+        self.samples = samples.max(1);
+        self
+    }
+}
+
+impl Integrator for AmbientOcclusion {
+    fn li(&self, _cam: &Camera, r: &Ray, world: &dyn Hit) -> Color {
+        let mut rec = HitRecord::default();
+        if !world.hit(r, Interval::new(0.001, f64::INFINITY), &mut rec) {
+            return Color::new(1.0, 1.0, 1.0);
+        }
+        let mut open = 0;
+        for _ in 0..self.samples {
+            let mut direction = rec.normal + Vec3::random_unit_vector();
+            if direction.near_zero() {
+                direction = rec.normal;
+            }
+            let occlusion_ray = Ray::new(rec.p, unit_vector(direction)).with_time(r.time());
+            let mut occluder = HitRecord::default();
+            if !world.hit(
+                &occlusion_ray,
+                Interval::new(0.001, self.radius),
+                &mut occluder,
+            ) {
+                open += 1;
+            }
+        }
+        Color::new(1.0, 1.0, 1.0) * (open as f64 / self.samples as f64)
+    }
+
+    fn describe(&self) -> String {
+        format!("ambient_occlusion {} {}", self.radius, self.samples)
+    }
+}
+
+// Diagnostic views of the scene, showing a property of the first surface each
+// camera ray hits. Rays that hit nothing are black. The colors are written to
+// the image as they are, whatever the camera's exposure and tone mapping.
+pub enum DebugView {
+    // Outward facing surface normals, mapped from [-1,1] to [0,1].
+    Normals,
+    // Texture coordinates, as red (u) and green (v).
+    Uv,
+    // Distance from the camera, from white up close to black at the given
+    // distance.
+    Depth(f64),
+    // A distinct color for each material.
+    MaterialId,
+    // Number of BVH nodes visited to find the hit, from blue (none) through green
+    // to red (the given count or more).
+    BvhCost(u64),
+}
+
+impl Integrator for DebugView {
+    fn li(&self, _cam: &Camera, r: &Ray, world: &dyn Hit) -> Color {
+        let visited_before = bvh::nodes_visited();
+        let mut rec = HitRecord::default();
+        let hit = world.hit(r, Interval::new(0.001, f64::INFINITY), &mut rec);
+
+        match self {
+            DebugView::BvhCost(max) => {
+                let visited = bvh::nodes_visited() - visited_before;
+                heat(visited as f64 / (*max).max(1) as f64)
+            }
+            _ if !hit => Color::default(),
+            DebugView::Normals => {
+                let outward = if rec.front_face {
+                    rec.normal
+                } else {
+                    -rec.normal
+                };
+                (outward + Vec3::new(1.0, 1.0, 1.0)) * 0.5
+            }
+            DebugView::Uv => Color::new(rec.u.fract(), rec.v.fract(), 0.0),
+            DebugView::Depth(max) => {
+                let distance = rec.t * r.direction().length();
+                Color::new(1.0, 1.0, 1.0) * (1.0 - distance / max).clamp(0.0, 1.0)
+            }
+            DebugView::MaterialId => {
+                // Hash the material's id into a color.
+                let hash = (rec.mat.id() as u64 + 1).wrapping_mul(0x9e3779b97f4a7c15) >> 40;
+                let channel = |shift: u64| ((hash >> shift) & 0xff) as f64 / 255.0;
+                Color::new(channel(0), channel(8), channel(16))
+            }
+        }
+    }
+
+    fn outputs_display_values(&self) -> bool {
+        true
+    }
+
+    fn describe(&self) -> String {
+        match self {
+            DebugView::Normals => "debug normals".to_string(),
+            DebugView::Uv => "debug uv".to_string(),
+            DebugView::Depth(max) => format!("debug depth {max}"),
+            DebugView::MaterialId => "debug material".to_string(),
+            DebugView::BvhCost(max) => format!("debug bvh {max}"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use super::*;
+    use crate::{
+        hit::Hittables,
+        material::{DiffuseLight, Lambertian, Material},
+        quad::Quad,
+        sphere::Sphere,
+    };
+
+    // A camera under a uniform white sky. Only its scene settings matter here.
+    fn camera(max_depth: u32) -> Camera {
+        Camera::builder()
+            .with_aspect_ratio(1.0)
+            .with_image_width(1)
+            .with_max_depth(max_depth)
+            .with_background(Color::new(1.0, 1.0, 1.0))
+            .build()
+    }
+
+    // A closed box around the origin lit by its ceiling, with walls reflecting
+    // most of the light so that paths bounce many times.
+    fn lit_box() -> Hittables {
+        let wall: Arc<dyn Material> = Arc::new(Lambertian::new_color(Color::new(0.7, 0.7, 0.7)));
+        let light: Arc<dyn Material> = Arc::new(DiffuseLight::new_color(Color::new(1.0, 1.0, 1.0)));
+        let (x, y, z) = (
+            Vec3::new(2.0, 0.0, 0.0),
+            Vec3::new(0.0, 2.0, 0.0),
+            Vec3::new(0.0, 0.0, 2.0),
+        );
+        let low = Vec3::new(-1.0, -1.0, -1.0);
+        let high = Vec3::new(1.0, 1.0, 1.0);
+        let mut world = Hittables::default();
+        for (q, u, v, mat) in [
+            (low, x, z, &wall),
+            (low, y, z, &wall),
+            (low, x, y, &wall),
+            (high, -y, -z, &wall),
+            (high, -x, -y, &wall),
+            (high, -x, -z, &light),
+        ] {
+            world.add(Arc::new(Quad::new(q, u, v, mat.clone())));
+        }
+        world
+    }
+
+    // Returns the mean and standard error of the luminance seen from the origin,
+    // over uniformly random directions.
+    fn mean_radiance(cam: &Camera, world: &dyn Hit) -> (f64, f64) {
+        let n = 20_000;
+        let (mut sum, mut sum_squared) = (0.0, 0.0);
+        for _ in 0..n {
+            let r = Ray::new(Vec3::default(), Vec3::random_unit_vector());
+            let radiance = PathTracer.li(cam, &r, world).y();
+            sum += radiance;
+            sum_squared += radiance * radiance;
+        }
+        let mean = sum / n as f64;
+        let variance = sum_squared / n as f64 - mean * mean;
+        (mean, f64::sqrt(variance / n as f64))
+    }
+
+    #[test]
+    fn russian_roulette_is_unbiased() {
+        let world = lit_box();
+        let (full, full_error) = mean_radiance(&camera(40).without_russian_roulette(), &world);
+        let (roulette, roulette_error) =
+            mean_radiance(&camera(40).with_russian_roulette(2), &world);
+        let sigma = f64::sqrt(full_error * full_error + roulette_error * roulette_error);
+        assert!(
+            (full - roulette).abs() < 5.0 * sigma,
+            "{full} {roulette} {sigma}"
+        );
+        // Tight enough to notice survivors not being boosted, which would lose
+        // well over a tenth of the light.
+        assert!(5.0 * sigma < 0.05 * full);
+    }
+
+    #[test]
+    fn escaping_rays_see_the_background() {
+        let r = Ray::new(Vec3::default(), Vec3::new(0.0, 0.0, -1.0));
+        let paths = PathTracer.li_paths(&camera(5), &r, &Hittables::default());
+        assert_eq!(paths.emission.y(), 1.0);
+        assert_eq!(paths.direct.y() + paths.indirect.y(), 0.0);
+    }
+
+    #[test]
+    fn emitters_are_seen_directly() {
+        let mut world = Hittables::default();
+        world.add(Arc::new(Quad::new(
+            Vec3::new(-1.0, -1.0, -2.0),
+            Vec3::new(2.0, 0.0, 0.0),
+            Vec3::new(0.0, 2.0, 0.0),
+            Arc::new(DiffuseLight::new_color(Color::new(3.0, 2.0, 1.0))),
+        )));
+        let r = Ray::new(Vec3::default(), Vec3::new(0.1, 0.2, -1.0));
+        let paths = PathTracer.li_paths(&camera(5), &r, &world);
+        assert_eq!(paths.emission.x(), 3.0);
+        assert_eq!(paths.emission.z(), 1.0);
+    }
+
+    // Every ray leaving a convex diffuse object escapes to the sky, so the
+    // object reflects exactly its albedo times the sky's radiance.
+    #[test]
+    fn diffuse_sphere_reflects_the_sky() {
+        let albedo = Color::new(0.2, 0.5, 0.8);
+        let mut world = Hittables::default();
+        world.add(Arc::new(Sphere::new(
+            Vec3::new(0.0, 0.0, -3.0),
+            1.0,
+            Arc::new(Lambertian::new_color(albedo)),
+        )));
+        for _ in 0..100 {
+            let direction = Vec3::new(0.0, 0.0, -1.0) + Vec3::random_in_unit_disk() * 0.3;
+            let r = Ray::new(Vec3::default(), direction);
+            let paths = PathTracer.li_paths(&camera(5), &r, &world);
+            assert!((paths.direct - albedo).length() < 1e-12);
+            assert_eq!(paths.emission.length_squared(), 0.0);
+            assert_eq!(paths.indirect.length_squared(), 0.0);
+            // With a single bounce allowed, the reflected light is cut off.
+            assert_eq!(PathTracer.li(&camera(1), &r, &world).length_squared(), 0.0);
+        }
+    }
+
+    #[test]
+    fn material_view_colors_each_material() {
+        let first: Arc<dyn Material> = Arc::new(Lambertian::new_color(Color::default()));
+        let second: Arc<dyn Material> = Arc::new(Lambertian::new_color(Color::default()));
+        let mut world = Hittables::default();
+        for (x, mat) in [(-3.0, &first), (0.0, &first), (3.0, &second)] {
+            world.add(Arc::new(Sphere::new(
+                Vec3::new(x, 0.0, -5.0),
+                1.0,
+                mat.clone(),
+            )));
+        }
+        let color = |x: f64| {
+            let r = Ray::new(Vec3::new(x, 0.0, 0.0), Vec3::new(0.0, 0.0, -1.0));
+            DebugView::MaterialId.li(&camera(1), &r, &world)
+        };
+        assert_eq!((color(-3.0) - color(0.0)).length_squared(), 0.0);
+        assert!((color(0.0) - color(3.0)).length_squared() > 0.0);
+        assert_eq!(color(10.0).length_squared(), 0.0);
+    }
+}
@@ -0,0 +1,547 @@
+use std::f64::consts::PI;
+use std::sync::Arc;
+
+use crate::{
+    color::{Color, luminance},
+    hit::{Hit, HitRecord},
+    interval::Interval,
+    onb::Onb,
+    ray::Ray,
+    utils::{random_2d, random_f64},
+    vec3::{Vec3, dot, unit_vector},
+};
+
+// The result of sampling a light from a point in the scene.
+pub struct LightSample {
+    // Unit direction from the shading point towards the light.
+    pub direction: Vec3,
+    // Distance to the light along `direction`, infinite for distant lights.
+    pub distance: f64,
+    // Radiance arriving at the shading point, already divided by the sampling pdf.
+    pub radiance: Color,
+    // Solid angle density with which `direction` was sampled, for lights that
+    // scattered rays can also reach (by escaping the scene) and whose two
+    // estimates must be combined with multiple importance sampling. Infinite for
+    // lights that can only be reached through sampling.
+    pub pdf: f64,
+    // Surface normal at the sampled point, for lights with a surface.
+    pub normal: Option<Vec3>,
+}
+
+// The result of sampling a ray of light leaving a light, for tracing paths
+// that start at the lights.
+pub struct EmissionSample {
+    pub ray: Ray,
+    // Surface normal where the ray leaves, for lights with a surface.
+    pub normal: Option<Vec3>,
+    // Radiance carried by the ray, not divided by any pdf.
+    pub radiance: Color,
+    // Area density of the ray's origin (1 for lights at a single point) and
+    // solid angle density of its direction.
+    pub pdf_pos: f64,
+    pub pdf_dir: f64,
+}
+
+// A light source that is sampled explicitly at each bounce (next event
+// estimation).
+pub trait Light: Send + Sync {
+    // Samples the light as seen from point p. Returns None if the light cannot
+    // illuminate p at all.
+    fn sample(&self, p: Vec3) -> Option<LightSample>;
+
+    // Returns the solid angle density with which sampling the light from the
+    // origin of r would pick the point rec where r hit the scene, or zero if that
+    // point isn't part of the light.
+    fn hit_pdf(&self, _r: &Ray, _rec: &HitRecord) -> f64 {
+        0.0
+    }
+
+    // Returns whether the light is infinitely far away, like the sun or an
+    // environment. Paths can't be traced from such lights.
+    fn is_infinite(&self) -> bool {
+        false
+    }
+
+    // Returns whether all of the light leaves from a single point.
+    fn is_delta_position(&self) -> bool {
+        false
+    }
+
+    // Samples a ray leaving the light at the given time. Returns None for lights
+    // that paths can't start from.
+    fn sample_emission(&self, _time: f64) -> Option<EmissionSample> {
+        None
+    }
+
+    // Returns the densities (pdf_pos, pdf_dir) with which sample_emission would
+    // produce a ray leaving point p on the light, with surface normal n, in unit
+    // direction w.
+    fn emission_pdf(&self, _p: Vec3, _n: Vec3, _w: Vec3) -> (f64, f64) {
+        (0.0, 0.0)
+    }
+}
+
+// Returns the multiple importance sampling weight for a sample drawn with density
+// pdf_f, when the same light could also have been drawn with density pdf_g.
+pub fn power_heuristic(pdf_f: f64, pdf_g: f64) -> f64 {
+    if pdf_f.is_infinite() {
+        return 1.0;
+    }
+    let f = pdf_f * pdf_f;
+    let g = pdf_g * pdf_g;
+    if f + g <= 0.0 { 0.0 } else { f / (f + g) }
+}
+
+#[derive(Default)]
+pub struct Lights {
+    pub lights: Vec<Arc<dyn Light>>,
+}
+
+impl Lights {
+    pub fn add(&mut self, light: Arc<dyn Light>) {
+        self.lights.push(light);
+    }
+}
+
+// A light emitting equally in all directions from a single point.
+pub struct PointLight {
+    position: Vec3,
+    intensity: Color,
+}
+
+impl PointLight {
+    pub fn new(position: Vec3, intensity: Color) -> Self {
+        Self {
+            position,
+            intensity,
+        }
+    }
+}
+
+impl Light for PointLight {
+    fn sample(&self, p: Vec3) -> Option<LightSample> {
+        let to_light = self.position - p;
+        let distance = to_light.length();
+        if distance <= 0.0 {
+            return None;
+        }
+        Some(LightSample {
+            direction: to_light / distance,
+            distance,
+            radiance: self.intensity / (distance * distance),
+            pdf: f64::INFINITY,
+            normal: None,
+        })
+    }
+
+    fn is_delta_position(&self) -> bool {
+        true
+    }
+
+    fn sample_emission(&self, time: f64) -> Option<EmissionSample> {
+        Some(EmissionSample {
+            ray: Ray::new(self.position, Vec3::random_unit_vector()).with_time(time),
+            normal: None,
+            radiance: self.intensity,
+            pdf_pos: 1.0,
+            pdf_dir: 1.0 / (4.0 * PI),
+        })
+    }
+
+    fn emission_pdf(&self, _p: Vec3, _n: Vec3, _w: Vec3) -> (f64, f64) {
+        (1.0, 1.0 / (4.0 * PI))
+    }
+}
+
+// A point light restricted to a cone. Intensity is full inside the inner cone
+// angle and falls off smoothly to zero at the outer cone angle.
+pub struct SpotLight {
+    position: Vec3,
+    direction: Vec3,
+    intensity: Color,
+    cos_inner: f64,
+    cos_outer: f64,
+    // Optional intensity multipliers sampled at evenly spaced angles from the
+    // spot axis (0) to directly behind it (pi), like a measured IES profile.
+    profile: Option<Vec<f64>>,
+}
+
+impl SpotLight {
+    // Creates a spot light at position pointing along direction, with cone angles
+    // given in degrees.
+    pub fn new(
+        position: Vec3,
+        direction: Vec3,
+        intensity: Color,
+        inner_angle: f64,
+        outer_angle: f64,
+    ) -> Self {
+        let outer_angle = f64::max(outer_angle, inner_angle);
+        Self {
+            position,
+            direction: unit_vector(direction),
+            intensity,
+            cos_inner: f64::cos(f64::to_radians(inner_angle)),
+            cos_outer: f64::cos(f64::to_radians(outer_angle)),
+            profile: None,
+        }
+    }
+
+    pub fn with_profile(mut self, profile: Vec<f64>) -> Self {
+        self.profile = if profile.is_empty() {
+            None
+        } else {
+            Some(profile)
+        };
+        self
+    }
+
+    // Returns the fraction of the full intensity emitted in direction w.
+    fn falloff(&self, w: Vec3) -> f64 {
+        let cos_theta = dot(w, self.direction);
+        let t = ((cos_theta - self.cos_outer) / (self.cos_inner - self.cos_outer)).clamp(0.0, 1.0);
+        let cone = if self.cos_inner <= self.cos_outer {
+            if cos_theta >= self.cos_outer {
+                1.0
+            } else {
+                0.0
+            }
+        } else {
+            t * t * (3.0 - 2.0 * t)
+        };
+
+        let profile = match &self.profile {
+            Some(profile) if profile.len() > 1 => {
+                let x = f64::acos(cos_theta.clamp(-1.0, 1.0)) / PI * (profile.len() - 1) as f64;
+                let i = (x as usize).min(profile.len() - 2);
+                let frac = x - i as f64;
+                profile[i] * (1.0 - frac) + profile[i + 1] * frac
+            }
+            Some(profile) => profile[0],
+            None => 1.0,
+        };
+        cone * profile
+    }
+}
+
+impl Light for SpotLight {
+    fn sample(&self, p: Vec3) -> Option<LightSample> {
+        let to_light = self.position - p;
+        let distance = to_light.length();
+        if distance <= 0.0 {
+            return None;
+        }
+        let direction = to_light / distance;
+        let falloff = self.falloff(-direction);
+        if falloff <= 0.0 {
+            return None;
+        }
+        Some(LightSample {
+            direction,
+            distance,
+            radiance: self.intensity * falloff / (distance * distance),
+            pdf: f64::INFINITY,
+            normal: None,
+        })
+    }
+
+    fn is_delta_position(&self) -> bool {
+        true
+    }
+
+    // Samples directions uniformly within the outer cone.
+    fn sample_emission(&self, time: f64) -> Option<EmissionSample> {
+        if self.cos_outer >= 1.0 {
+            return None;
+        }
+        let (r1, r2) = random_2d();
+        let cos_theta = 1.0 - r1 * (1.0 - self.cos_outer);
+        let sin_theta = f64::sqrt(f64::max(0.0, 1.0 - cos_theta * cos_theta));
+        let phi = 2.0 * PI * r2;
+        let direction = Onb::new(self.direction).transform(Vec3::new(
+            f64::cos(phi) * sin_theta,
+            f64::sin(phi) * sin_theta,
+            cos_theta,
+        ));
+        Some(EmissionSample {
+            ray: Ray::new(self.position, direction).with_time(time),
+            normal: None,
+            radiance: self.intensity * self.falloff(direction),
+            pdf_pos: 1.0,
+            pdf_dir: 1.0 / (2.0 * PI * (1.0 - self.cos_outer)),
+        })
+    }
+
+    fn emission_pdf(&self, _p: Vec3, _n: Vec3, w: Vec3) -> (f64, f64) {
+        if self.cos_outer >= 1.0 || dot(w, self.direction) < self.cos_outer {
+            return (1.0, 0.0);
+        }
+        (1.0, 1.0 / (2.0 * PI * (1.0 - self.cos_outer)))
+    }
+}
+
+// A light infinitely far away, such as the sun. With a zero angular diameter all
+// of its light arrives from a single direction; otherwise it is a uniformly
+// bright disk of the given size in the sky.
+pub struct DirectionalLight {
+    // Unit direction pointing from the scene towards the light.
+    to_light: Vec3,
+    irradiance: Color,
+    cos_max: f64,
+}
+
+impl DirectionalLight {
+    // Creates a light shining along direction, delivering the given irradiance to
+    // surfaces facing it. The angular diameter is given in degrees.
+    pub fn new(direction: Vec3, irradiance: Color, angular_diameter: f64) -> Self {
+        Self {
+            to_light: -unit_vector(direction),
+            irradiance,
+            cos_max: f64::cos(f64::to_radians(angular_diameter / 2.0)),
+        }
+    }
+
+    // Returns whether the unit direction w (pointing away from the scene) falls
+    // within the light's disk.
+    pub fn contains(&self, w: Vec3) -> bool {
+        self.cos_max < 1.0 && dot(w, self.to_light) >= self.cos_max
+    }
+
+    // Returns the radiance of the light's disk, which is only defined when it has
+    // a non-zero angular diameter.
+    pub fn radiance(&self) -> Color {
+        self.irradiance / (2.0 * PI * (1.0 - self.cos_max))
+    }
+}
+
+impl Light for DirectionalLight {
+    fn sample(&self, _p: Vec3) -> Option<LightSample> {
+        let direction = if self.cos_max >= 1.0 {
+            self.to_light
+        } else {
+            // Uniformly sample a direction in the cone subtended by the disk.
+            let (r1, r2) = random_2d();
+            let cos_theta = 1.0 - r1 * (1.0 - self.cos_max);
+            let sin_theta = f64::sqrt(f64::max(0.0, 1.0 - cos_theta * cos_theta));
+            let phi = 2.0 * PI * r2;
+            Onb::new(self.to_light).transform(Vec3::new(
+                f64::cos(phi) * sin_theta,
+                f64::sin(phi) * sin_theta,
+                cos_theta,
+            ))
+        };
+        // Radiance over the uniform cone pdf gives back the irradiance.
+        Some(LightSample {
+            direction,
+            distance: f64::INFINITY,
+            radiance: self.irradiance,
+            pdf: f64::INFINITY,
+            normal: None,
+        })
+    }
+
+    fn is_infinite(&self) -> bool {
+        true
+    }
+}
+
+// Emissive geometry registered as a light, so that it can be sampled directly
+// instead of only being found by chance. The shape must also be added to the
+// world; its material determines the emitted radiance. Sampling assumes the
+// shape doesn't move.
+pub struct AreaLight {
+    shape: Arc<dyn Hit>,
+    area: f64,
+}
+
+impl AreaLight {
+    pub fn new(shape: Arc<dyn Hit>) -> Self {
+        let area = shape.area();
+        Self { shape, area }
+    }
+
+    // Returns the radiance leaving point p of the shape in unit direction w. The
+    // material is found by hitting p from just outside the surface.
+    fn emitted(&self, p: Vec3, w: Vec3, time: f64) -> Color {
+        let eps = 1e-4 * f64::max(1.0, p.length());
+        let r = Ray::new(p + w * eps, -w).with_time(time);
+        let mut rec = HitRecord::default();
+        if !self.shape.hit(&r, Interval::new(0.0, 2.0 * eps), &mut rec) {
+            return Color::default();
+        }
+        rec.mat.emitted(&r, &rec)
+    }
+
+    // Returns the probability of emitting from the side of the surface that w
+    // leaves from, choosing sides by how brightly they emit.
+    fn side_probability(&self, p: Vec3, n: Vec3, w: Vec3, time: f64) -> f64 {
+        let front = luminance(self.emitted(p, n, time)).max(0.0);
+        let back = luminance(self.emitted(p, -n, time)).max(0.0);
+        if front + back <= 0.0 {
+            return 0.0;
+        }
+        if dot(w, n) > 0.0 {
+            front / (front + back)
+        } else {
+            back / (front + back)
+        }
+    }
+}
+
+impl Light for AreaLight {
+    fn sample(&self, p: Vec3) -> Option<LightSample> {
+        let (q, n) = self.shape.sample_surface(0.0)?;
+        let to_light = q - p;
+        let distance = to_light.length();
+        if distance <= 0.0 || self.area <= 0.0 {
+            return None;
+        }
+        let direction = to_light / distance;
+        let cos_light = f64::abs(dot(n, direction));
+        if cos_light <= 0.0 {
+            return None;
+        }
+        let pdf = distance * distance / (cos_light * self.area);
+        Some(LightSample {
+            direction,
+            distance,
+            radiance: self.emitted(q, -direction, 0.0) / pdf,
+            pdf,
+            normal: Some(n),
+        })
+    }
+
+    fn hit_pdf(&self, r: &Ray, rec: &HitRecord) -> f64 {
+        let mut light_rec = HitRecord::default();
+        if self.area <= 0.0
+            || !self
+                .shape
+                .hit(r, Interval::new(0.001, f64::INFINITY), &mut light_rec)
+            || f64::abs(light_rec.t - rec.t) > 1e-6 * f64::max(1.0, rec.t)
+        {
+            return 0.0;
+        }
+        let distance = rec.t * r.direction().length();
+        let cos_light = f64::abs(dot(light_rec.normal, unit_vector(r.direction())));
+        if cos_light <= 0.0 {
+            return 0.0;
+        }
+        distance * distance / (cos_light * self.area)
+    }
+
+    // Picks a point uniformly over the shape, then a cosine weighted direction on
+    // one of its sides.
+    fn sample_emission(&self, time: f64) -> Option<EmissionSample> {
+        let (q, n) = self.shape.sample_surface(time)?;
+        if self.area <= 0.0 {
+            return None;
+        }
+        let front = self.side_probability(q, n, n, time);
+        let side = if random_f64() < front { n } else { -n };
+        let mut direction = side + Vec3::random_unit_vector();
+        if direction.near_zero() {
+            direction = side;
+        }
+        let direction = unit_vector(direction);
+        let (pdf_pos, pdf_dir) = self.emission_pdf(q, n, direction);
+        if pdf_dir <= 0.0 {
+            return None;
+        }
+        Some(EmissionSample {
+            ray: Ray::new(q, direction).with_time(time),
+            normal: Some(n),
+            radiance: self.emitted(q, direction, time),
+            pdf_pos,
+            pdf_dir,
+        })
+    }
+
+    fn emission_pdf(&self, p: Vec3, n: Vec3, w: Vec3) -> (f64, f64) {
+        if self.area <= 0.0 {
+            return (0.0, 0.0);
+        }
+        let side = self.side_probability(p, n, w, 0.0);
+        (1.0 / self.area, side * f64::abs(dot(n, w)) / PI)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{material::DiffuseLight, quad::Quad};
+
+    // Returns the radiance a spot light pointing down delivers to a point at the
+    // given angle (in degrees) from its axis, at unit distance.
+    fn spot_radiance(spot: &SpotLight, angle: f64) -> f64 {
+        let angle = f64::to_radians(angle);
+        let p = Vec3::new(f64::sin(angle), -f64::cos(angle), 0.0);
+        spot.sample(p).map_or(0.0, |sample| sample.radiance.x())
+    }
+
+    #[test]
+    fn spot_cone_falls_off_between_its_edges() {
+        let white = Color::new(1.0, 1.0, 1.0);
+        let spot = SpotLight::new(
+            Vec3::default(),
+            Vec3::new(0.0, -1.0, 0.0),
+            white,
+            20.0,
+            30.0,
+        );
+        for angle in [0.0, 10.0, 19.9] {
+            assert!((spot_radiance(&spot, angle) - 1.0).abs() < 1e-9, "{angle}");
+        }
+        assert!(spot_radiance(&spot, 20.1) < 1.0);
+        assert!(spot_radiance(&spot, 29.9) > 0.0);
+        for angle in [30.1, 45.0, 90.0, 180.0] {
+            assert_eq!(spot_radiance(&spot, angle), 0.0, "{angle}");
+        }
+        // Smoothly and steadily in between, reaching the edges flat. Halfway in
+        // angle is a little past halfway in cosine, where the falloff is 1/2.
+        let falloff: Vec<f64> = (0..=20)
+            .map(|k| spot_radiance(&spot, 20.0 + k as f64 * 0.5))
+            .collect();
+        assert!(falloff.windows(2).all(|w| w[1] < w[0]));
+        assert!((falloff[10] - 0.57).abs() < 0.01, "{}", falloff[10]);
+        assert!(falloff[1] > 0.99 && falloff[19] < 0.01);
+    }
+
+    #[test]
+    fn spot_with_equal_cones_has_a_hard_edge() {
+        let white = Color::new(1.0, 1.0, 1.0);
+        let spot = SpotLight::new(
+            Vec3::default(),
+            Vec3::new(0.0, -1.0, 0.0),
+            white,
+            25.0,
+            25.0,
+        );
+        assert_eq!(spot_radiance(&spot, 24.9), 1.0);
+        assert_eq!(spot_radiance(&spot, 25.1), 0.0);
+    }
+
+    #[test]
+    fn area_light_sample_pdf_matches_hit_pdf() {
+        let emitter = Arc::new(DiffuseLight::new_color(Color::new(4.0, 4.0, 4.0)));
+        let shape: Arc<dyn Hit> = Arc::new(Quad::new(
+            Vec3::new(-1.0, 2.0, -1.0),
+            Vec3::new(2.0, 0.0, 0.0),
+            Vec3::new(0.0, 0.5, 2.0),
+            emitter,
+        ));
+        let light = AreaLight::new(shape.clone());
+        let p = Vec3::new(0.3, 0.0, 0.2);
+        for _ in 0..100 {
+            let sample = light.sample(p).unwrap();
+            let r = Ray::new(p, sample.direction * 3.0);
+            let mut rec = HitRecord::default();
+            assert!(shape.hit(&r, Interval::new(0.001, f64::INFINITY), &mut rec));
+            assert!((rec.t * 3.0 - sample.distance).abs() < 1e-9);
+            let hit_pdf = light.hit_pdf(&r, &rec);
+            assert!(
+                (hit_pdf - sample.pdf).abs() < 1e-9 * sample.pdf,
+                "{hit_pdf}"
+            );
+        }
+    }
+}
@@ -1,15 +1,34 @@
 mod aabb;
+mod adaptive;
+mod aov;
+mod background;
+mod bdpt;
 mod bvh;
 mod camera;
+mod checkpoint;
 mod color;
+mod denoise;
+mod distributed;
+mod environment;
+mod film;
+mod filter;
 mod hit;
+mod image;
+mod integrator;
 mod interval;
+mod light;
 mod material;
+mod onb;
 mod perlin;
+mod photon;
 mod quad;
 mod ray;
+mod sampler;
+mod sky;
 mod sphere;
 mod texture;
+mod tile;
+mod tonemap;
 mod utils;
 mod vec3;
 
@@ -18,21 +37,272 @@ use color::Color;
 use hit::Hittables;
 use material::{Dielectric, Lambertian, Metal};
 use sphere::Sphere;
+use std::env;
+use std::process;
+use std::str::FromStr;
 use std::sync::Arc;
-use utils::random_f64;
-use vec3::Vec3;
+use std::time::Duration;
+use utils::{random_f64, seed_random};
+use vec3::{Vec3, unit_vector};
 
 use crate::{
+    background::{GradientBackground, TextureBackground},
+    bdpt::BidirectionalPathTracer,
     bvh::BVHNode,
-    hit::{Rotated, Translated},
-    material::DiffuseLight,
+    denoise::Denoiser,
+    distributed::Role,
+    environment::EnvironmentLight,
+    hit::{Hit, Rotated, Translated},
+    image::Image,
+    integrator::{AmbientOcclusion, DebugView, Integrator, PathTracer},
+    light::{AreaLight, DirectionalLight, Lights, PointLight, SpotLight},
+    material::{AlphaMasked, BumpMapped, DiffuseLight, NormalMapped},
+    photon::PhotonMapper,
     quad::{Quad, make_box},
-    texture::{CheckerTexture, NoiseTexture},
+    sampler::{HaltonSampler, IndependentSampler, SobolSampler, StratifiedSampler},
+    sky::PhysicalSky,
+    texture::{CheckerTexture, ImageTexture, NoiseTexture, SolidColor},
     utils::random_range_f64,
 };
 
+// Seed for building scenes, so random placements come out the same on every run.
+const SEED: u64 = 2024;
+
+// The scenes that can be rendered, picked with `--scene NAME`. The first one
+// is rendered by default.
+const SCENES: &[(&str, fn())] = &[
+    ("cornell_box", cornell_box),
+    ("bouncing_spheres", bouncing_spheres),
+    ("checkered_spheres", checkered_spheres),
+    ("perlin_spheres", perlin_spheres),
+    ("quads", quads),
+    ("simple_light", simple_light),
+    ("tiled_floor", tiled_floor),
+    ("alpha_masks", alpha_masks),
+    ("delta_lights", delta_lights),
+    ("environment_map", environment_map),
+    ("physical_sky", physical_sky),
+    ("bdpt_caustics", bdpt_caustics),
+    ("photon_caustics", photon_caustics),
+];
+
 fn main() {
-    cornell_box();
+    let name = arg_value("--scene");
+    let name = name.as_deref().unwrap_or(SCENES[0].0);
+    match SCENES.iter().find(|(scene, _)| *scene == name) {
+        Some((_, scene)) => {
+            // Build scenes the same way on every run, including random
+            // placements, colors and noise textures.
+            seed_random(SEED);
+            scene()
+        }
+        None => {
+            let names: Vec<&str> = SCENES.iter().map(|(scene, _)| *scene).collect();
+            eprintln!(
+                "Unknown scene {name}, expected one of {}.",
+                names.join(", ")
+            );
+            process::exit(1);
+        }
+    }
+}
+
+// Returns the value following the given flag on the command line.
+fn arg_value(flag: &str) -> Option<String> {
+    arg_values(flag).into_iter().next()
+}
+
+// Returns the values following every use of the given flag on the command line.
+fn arg_values(flag: &str) -> Vec<String> {
+    let args: Vec<String> = env::args().collect();
+    args.windows(2)
+        .filter(|pair| pair[0] == flag)
+        .map(|pair| pair[1].clone())
+        .collect()
+}
+
+// Parses the value given for a flag on the command line, exiting with an error
+// if it isn't valid.
+fn parse_arg<T: FromStr>(flag: &str, value: &str) -> T {
+    value.parse().unwrap_or_else(|_| {
+        eprintln!("Invalid value {value} for {flag}.");
+        process::exit(1);
+    })
+}
+
+// Parses a flag's value of two comma separated parts, exiting with an error if
+// it isn't valid.
+fn parse_pair<A: FromStr, B: FromStr>(flag: &str, value: &str) -> (A, B) {
+    let Some((a, b)) = value.split_once(',') else {
+        eprintln!("Invalid value {value} for {flag}, expected two values separated by a comma.");
+        process::exit(1);
+    };
+    (parse_arg(flag, a), parse_arg(flag, b))
+}
+
+// Renders a scene with the render settings given on the command line, such as
+// `--integrator bdpt` or `--debug normals`, which are listed in the README.
+fn render(mut cam: Camera, world: &dyn Hit) {
+    for aov in arg_values("--aov") {
+        let Some((pass, path)) = aov.split_once('=') else {
+            eprintln!("Invalid value {aov} for --aov, expected PASS=FILE.");
+            process::exit(1);
+        };
+        cam = cam.with_aov(parse_arg("--aov", pass), path);
+    }
+    if let Some(strength) = arg_value("--denoise") {
+        let mut denoiser = Denoiser::new().with_strength(parse_arg("--denoise", &strength));
+        if let Some(radius) = arg_value("--denoise-radius") {
+            denoiser = denoiser.with_radius(parse_arg("--denoise-radius", &radius));
+        }
+        if let Some(sigmas) = arg_value("--denoise-guides") {
+            let (albedo, normal) = parse_pair("--denoise-guides", &sigmas);
+            denoiser = denoiser.with_guide_sigmas(albedo, normal);
+        }
+        cam = cam.with_denoiser(denoiser);
+    }
+    if let Some(sampler) = arg_value("--sampler") {
+        cam = match sampler.as_str() {
+            "independent" => cam.with_sampler(IndependentSampler),
+            "stratified" => cam.with_sampler(StratifiedSampler),
+            "halton" => cam.with_sampler(HaltonSampler::default()),
+            "sobol" => cam.with_sampler(SobolSampler),
+            _ => {
+                eprintln!(
+                    "Unknown sampler {sampler}, expected one of independent, stratified, halton or sobol."
+                );
+                process::exit(1);
+            }
+        };
+    }
+    if let Some(seed) = arg_value("--seed") {
+        cam = cam.with_seed(parse_arg("--seed", &seed));
+    }
+    if let Some(adaptive) = arg_value("--adaptive") {
+        let (min_samples, threshold) = parse_pair("--adaptive", &adaptive);
+        cam = cam.with_adaptive_sampling(min_samples, threshold);
+    }
+    if let Some(progressive) = arg_value("--progressive") {
+        let (samples_per_pass, path): (_, String) = parse_pair("--progressive", &progressive);
+        cam = cam.with_progressive(samples_per_pass, path);
+    }
+    if let Some(checkpoint) = arg_value("--checkpoint") {
+        let (samples_per_pass, path): (_, String) = parse_pair("--checkpoint", &checkpoint);
+        cam = cam.with_checkpoint(samples_per_pass, path);
+    }
+    if let Some(path) = arg_value("--resume") {
+        cam = cam.with_resume(path);
+    }
+    if let Some(filter) = arg_value("--filter") {
+        let (filter, radius): (_, f64) = parse_pair("--filter", &filter);
+        if radius < 0.5 {
+            eprintln!("Invalid value {radius} for the --filter radius, it must be at least 0.5.");
+            process::exit(1);
+        }
+        cam = cam.with_filter(filter, radius);
+    }
+    if let Some(exposure) = arg_value("--exposure") {
+        cam = cam.with_exposure(parse_arg("--exposure", &exposure));
+    }
+    if let Some(tone_map) = arg_value("--tone-map") {
+        cam = cam.with_tone_map(parse_arg("--tone-map", &tone_map));
+    }
+    if let Some(transfer) = arg_value("--transfer") {
+        cam = cam.with_transfer_function(parse_arg("--transfer", &transfer));
+    }
+    if let Some(tiles) = arg_value("--tiles") {
+        let (size, order) = parse_pair("--tiles", &tiles);
+        cam = cam.with_tiles(size, order);
+    }
+    if let Some(crop) = arg_value("--crop") {
+        let values: Vec<usize> = crop.split(',').map(|v| parse_arg("--crop", v)).collect();
+        let &[x, y, width, height] = values.as_slice() else {
+            eprintln!("Invalid value {crop} for --crop, expected X,Y,WIDTH,HEIGHT.");
+            process::exit(1);
+        };
+        let fits = width > 0
+            && height > 0
+            && x.checked_add(width).is_some_and(|x1| x1 <= cam.image_width)
+            && y.checked_add(height)
+                .is_some_and(|y1| y1 <= cam.image_height());
+        if !fits {
+            eprintln!(
+                "Invalid value {crop} for --crop, expected a non-empty rectangle within the {}x{} image.",
+                cam.image_width,
+                cam.image_height()
+            );
+            process::exit(1);
+        }
+        cam = cam.with_crop(x, y, width, height);
+    }
+    if let Some(path) = arg_value("--composite") {
+        cam = cam.with_composite(path);
+    }
+    if let Some(seconds) = arg_value("--time-limit") {
+        let Ok(time_limit) = Duration::try_from_secs_f64(parse_arg("--time-limit", &seconds))
+        else {
+            eprintln!("Invalid value {seconds} for --time-limit.");
+            process::exit(1);
+        };
+        cam = cam.with_time_limit(time_limit);
+    }
+    if let Some(depth) = arg_value("--russian-roulette") {
+        cam = if depth == "off" {
+            cam.without_russian_roulette()
+        } else {
+            cam.with_russian_roulette(parse_arg("--russian-roulette", &depth))
+        };
+    }
+    let integrator_flags: Vec<&str> = ["--integrator", "--ao", "--debug"]
+        .into_iter()
+        .filter(|flag| arg_value(flag).is_some())
+        .collect();
+    if integrator_flags.len() > 1 {
+        eprintln!(
+            "Only one of --integrator, --ao and --debug can be given, but got {}.",
+            integrator_flags.join(" and ")
+        );
+        process::exit(1);
+    }
+    if let Some(integrator) = arg_value("--integrator") {
+        cam = match integrator.as_str() {
+            "path" => cam.with_integrator(PathTracer),
+            "bdpt" => cam.with_integrator(BidirectionalPathTracer::new()),
+            "photon" => cam.with_integrator(PhotonMapper::new(500_000).with_gather(100, 20.0)),
+            _ => {
+                eprintln!("Unknown integrator {integrator}, expected path, bdpt or photon.");
+                process::exit(1);
+            }
+        };
+    }
+    if let Some(radius) = arg_value("--ao") {
+        let radius = parse_arg("--ao", &radius);
+        cam = cam.with_integrator(AmbientOcclusion::new(radius).with_samples(4));
+    }
+    if let Some(address) = arg_value("--worker") {
+        cam = cam.with_distributed(Role::Worker { address });
+    } else if let Some(address) = arg_value("--coordinator") {
+        let workers = arg_value("--workers").map_or(0, |n| parse_arg("--workers", &n));
+        cam = cam.with_distributed(Role::Coordinator { address, workers });
+    }
+    if let Some(view) = arg_value("--debug") {
+        let (name, max) = view.split_once('=').unwrap_or((&view, ""));
+        let view = match name {
+            "normals" => DebugView::Normals,
+            "uv" => DebugView::Uv,
+            "depth" if !max.is_empty() => DebugView::Depth(parse_arg("--debug depth", max)),
+            "material" => DebugView::MaterialId,
+            "bvh" if !max.is_empty() => DebugView::BvhCost(parse_arg("--debug bvh", max)),
+            _ => {
+                eprintln!(
+                    "Unknown debug view {view}, expected one of normals, uv, depth=MAX_DISTANCE, material or bvh=MAX_NODES."
+                );
+                process::exit(1);
+            }
+        };
+        cam = cam.with_integrator(view);
+    }
+    cam.render(world);
 }
 
 fn cornell_box() {
@@ -41,7 +311,8 @@ fn cornell_box() {
     let red = Arc::new(Lambertian::new_color(Color::new(0.65, 0.05, 0.05)));
     let white = Arc::new(Lambertian::new_color(Color::new(0.73, 0.73, 0.73)));
     let green = Arc::new(Lambertian::new_color(Color::new(0.12, 0.45, 0.15)));
-    let light = Arc::new(DiffuseLight::new_color(Color::new(15.0, 15.0, 15.0)));
+    let light =
+        Arc::new(DiffuseLight::new_color(Color::new(15.0, 15.0, 15.0)).with_one_sided(true));
 
     // Make walls and light
     world.add(Arc::new(Quad::new(
@@ -56,12 +327,15 @@ fn cornell_box() {
         Vec3::new(0.0, 0.0, 555.0),
         red.clone(),
     )));
-    world.add(Arc::new(Quad::new(
+    let light_quad = Arc::new(Quad::new(
         Vec3::new(343.0, 554.0, 332.0),
         Vec3::new(-130.0, 0.0, 0.0),
         Vec3::new(0.0, 0.0, -105.0),
         light.clone(),
-    )));
+    ));
+    world.add(light_quad.clone());
+    let mut lights = Lights::default();
+    lights.add(Arc::new(AreaLight::new(light_quad)));
     world.add(Arc::new(Quad::new(
         Vec3::new(0.0, 0.0, 0.0),
         Vec3::new(555.0, 0.0, 0.0),
@@ -100,12 +374,106 @@ fn cornell_box() {
     let box2 = Arc::new(Translated::new(box2, Vec3::new(130.0, 0.0, 65.0)));
     world.add(box2);
 
-    let mut cam = Camera::builder()
+    let cam = Camera::builder()
         .with_aspect_ratio(1.0)
         .with_image_width(600)
         .with_samples_per_pixel(200)
-        .with_max_depth(50)
+        .with_max_depth(100)
+        .with_background(Color::default())
+        .with_lights(lights)
+        .with_vfov(40.0)
+        .with_lookfrom(Vec3::new(278.0, 278.0, -800.0))
+        .with_lookat(Vec3::new(278.0, 278.0, 0.0))
+        .with_vup(Vec3::new(0.0, 1.0, 0.0))
+        .with_defocus_angle(0.0)
+        .with_focus_dist(10.0)
+        .build();
+
+    render(cam, &world);
+}
+
+fn bdpt_caustics() {
+    cornell_caustics(BidirectionalPathTracer::new());
+}
+
+fn photon_caustics() {
+    cornell_caustics(PhotonMapper::new(500_000).with_gather(100, 20.0));
+}
+
+// A Cornell box with a glass sphere focusing the light into a caustic on the
+// floor, which path tracing finds poorly.
+fn cornell_caustics(integrator: impl Integrator + 'static) {
+    let mut world = Hittables::default();
+
+    let red = Arc::new(Lambertian::new_color(Color::new(0.65, 0.05, 0.05)));
+    let white = Arc::new(Lambertian::new_color(Color::new(0.73, 0.73, 0.73)));
+    let green = Arc::new(Lambertian::new_color(Color::new(0.12, 0.45, 0.15)));
+    let light =
+        Arc::new(DiffuseLight::new_color(Color::new(15.0, 15.0, 15.0)).with_one_sided(true));
+
+    world.add(Arc::new(Quad::new(
+        Vec3::new(555.0, 0.0, 0.0),
+        Vec3::new(0.0, 555.0, 0.0),
+        Vec3::new(0.0, 0.0, 555.0),
+        green,
+    )));
+    world.add(Arc::new(Quad::new(
+        Vec3::new(0.0, 0.0, 0.0),
+        Vec3::new(0.0, 555.0, 0.0),
+        Vec3::new(0.0, 0.0, 555.0),
+        red,
+    )));
+    let light_quad = Arc::new(Quad::new(
+        Vec3::new(343.0, 554.0, 332.0),
+        Vec3::new(-130.0, 0.0, 0.0),
+        Vec3::new(0.0, 0.0, -105.0),
+        light,
+    ));
+    world.add(light_quad.clone());
+    world.add(Arc::new(Quad::new(
+        Vec3::new(0.0, 0.0, 0.0),
+        Vec3::new(555.0, 0.0, 0.0),
+        Vec3::new(0.0, 0.0, 555.0),
+        white.clone(),
+    )));
+    world.add(Arc::new(Quad::new(
+        Vec3::new(555.0, 555.0, 555.0),
+        Vec3::new(-555.0, 0.0, 0.0),
+        Vec3::new(0.0, 0.0, -555.0),
+        white.clone(),
+    )));
+    world.add(Arc::new(Quad::new(
+        Vec3::new(0.0, 0.0, 555.0),
+        Vec3::new(555.0, 0.0, 0.0),
+        Vec3::new(0.0, 555.0, 0.0),
+        white.clone(),
+    )));
+
+    world.add(Arc::new(Sphere::new(
+        Vec3::new(190.0, 120.0, 190.0),
+        120.0,
+        Arc::new(Dielectric::new(1.5)),
+    )));
+    let box1 = make_box(
+        Vec3::new(0.0, 0.0, 0.0),
+        Vec3::new(165.0, 330.0, 165.0),
+        white,
+    );
+    let box1 = Arc::new(Rotated::new(box1, 15.0));
+    let box1 = Arc::new(Translated::new(box1, Vec3::new(265.0, 0.0, 295.0)));
+    world.add(box1);
+
+    let mut lights = Lights::default();
+    lights.add(Arc::new(AreaLight::new(light_quad)));
+
+    let cam = Camera::builder()
+        .with_aspect_ratio(1.0)
+        .with_image_width(600)
+        .with_samples_per_pixel(64)
+        .with_max_depth(100)
         .with_background(Color::default())
+        .with_lights(lights)
+        .with_integrator(integrator)
         .with_vfov(40.0)
         .with_lookfrom(Vec3::new(278.0, 278.0, -800.0))
         .with_lookat(Vec3::new(278.0, 278.0, 0.0))
@@ -114,7 +482,168 @@ fn cornell_box() {
         .with_focus_dist(10.0)
         .build();
 
-    cam.render(&world);
+    render(cam, &world);
+}
+
+// A floor of bevelled tiles drawn with a normal map, and a sphere with bumps
+// from a noise texture, lit by a warm blackbody light from a low angle to bring
+// out the relief.
+fn tiled_floor() {
+    let mut world = Hittables::default();
+
+    // A normal map given with `--normal-map FILE` replaces the bevels, keeping
+    // the tile colors.
+    let (albedo, mut normals) = tile_images(512, 4);
+    if let Some(path) = arg_value("--normal-map") {
+        normals = Image::load(&path).expect("failed to load normal map");
+    }
+    let tiles = Arc::new(Lambertian::new(Arc::new(ImageTexture::new(Arc::new(
+        albedo,
+    )))));
+    let floor = Arc::new(NormalMapped::new(
+        tiles,
+        Arc::new(ImageTexture::new_raw(Arc::new(normals))),
+    ));
+    world.add(Arc::new(Quad::new(
+        Vec3::new(-4.0, 0.0, 4.0),
+        Vec3::new(8.0, 0.0, 0.0),
+        Vec3::new(0.0, 0.0, -8.0),
+        floor,
+    )));
+
+    let bumpy = Arc::new(BumpMapped::new(
+        Arc::new(Lambertian::new_color(Color::new(0.8, 0.8, 0.8))),
+        Arc::new(NoiseTexture::new(4.0)),
+        0.1,
+    ));
+    world.add(Arc::new(Sphere::new(Vec3::new(0.0, 1.0, 0.0), 1.0, bumpy)));
+
+    let light = Arc::new(DiffuseLight::new_blackbody(3500.0).with_intensity(8.0));
+    world.add(Arc::new(Quad::new(
+        Vec3::new(-6.0, 0.5, -3.0),
+        Vec3::new(0.0, 0.0, 6.0),
+        Vec3::new(0.0, 2.0, 0.0),
+        light,
+    )));
+
+    let cam = Camera::builder()
+        .with_aspect_ratio(16.0 / 9.0)
+        .with_image_width(400)
+        .with_samples_per_pixel(100)
+        .with_max_depth(100)
+        .with_vfov(30.0)
+        .with_lookfrom(Vec3::new(0.0, 3.0, 7.0))
+        .with_lookat(Vec3::new(0.0, 0.5, 0.0))
+        .with_vup(Vec3::new(0.0, 1.0, 0.0))
+        .with_defocus_angle(0.0)
+        .with_focus_dist(10.0)
+        .with_background(Color::new(0.1, 0.1, 0.15))
+        .build();
+    render(cam, &world);
+}
+
+// Draws `count` by `count` square tiles separated by grout into a gamma encoded
+// color image and a matching tangent space normal map, `size` pixels across.
+// The edges of each tile are bevelled, tilting their normals towards the grout.
+fn tile_images(size: usize, count: usize) -> (Image, Image) {
+    let cell = size / count;
+    let grout = cell / 32 + 1;
+    let bevel = cell / 12 + 1;
+    let mut albedo = Vec::with_capacity(size * size);
+    let mut normals = Vec::with_capacity(size * size);
+    for y in 0..size {
+        for x in 0..size {
+            let (cx, cy) = (x % cell, y % cell);
+            // Distance to the nearest edge of the cell along each axis.
+            let dx = usize::min(cx, cell - 1 - cx);
+            let dy = usize::min(cy, cell - 1 - cy);
+            let edge = usize::min(dx, dy);
+
+            let color = if edge < grout {
+                Color::new(0.3, 0.3, 0.3)
+            } else if (x / cell + y / cell).is_multiple_of(2) {
+                Color::new(0.7, 0.35, 0.2)
+            } else {
+                Color::new(0.8, 0.75, 0.65)
+            };
+            albedo.push(Color::new(
+                color.x().sqrt(),
+                color.y().sqrt(),
+                color.z().sqrt(),
+            ));
+
+            // Image rows run down the texture, against the direction of v.
+            let normal = if edge < grout || edge >= grout + bevel {
+                Vec3::new(0.0, 0.0, 1.0)
+            } else if dx < dy {
+                let side = if cx < cell / 2 { -1.0 } else { 1.0 };
+                unit_vector(Vec3::new(side, 0.0, 1.0))
+            } else {
+                let side = if cy < cell / 2 { 1.0 } else { -1.0 };
+                unit_vector(Vec3::new(0.0, side, 1.0))
+            };
+            normals.push((normal + Vec3::new(1.0, 1.0, 1.0)) * 0.5);
+        }
+    }
+    (
+        Image::new(size, size, albedo),
+        Image::new(size, size, normals),
+    )
+}
+
+// A lattice cut out of a quad by a checker mask, in front of a sphere that's
+// only partly opaque. The scene is lit by a plain sky, but the camera sees
+// a marble pattern behind it.
+fn alpha_masks() {
+    let mut world = Hittables::default();
+    let ground = Arc::new(Lambertian::new_color(Color::new(0.5, 0.5, 0.5)));
+    world.add(Arc::new(Sphere::new(
+        Vec3::new(0.0, -1000.0, 0.0),
+        1000.0,
+        ground,
+    )));
+
+    let holes = Arc::new(CheckerTexture::new_solid(
+        0.25,
+        Color::new(1.0, 1.0, 1.0),
+        Color::new(0.0, 0.0, 0.0),
+    ));
+    let lattice = Arc::new(AlphaMasked::new(
+        Arc::new(Lambertian::new_color(Color::new(0.6, 0.4, 0.2))),
+        holes,
+        0.5,
+    ));
+    world.add(Arc::new(Quad::new(
+        Vec3::new(-2.0, 0.0, 1.5),
+        Vec3::new(4.0, 0.0, 0.0),
+        Vec3::new(0.0, 2.5, 0.0),
+        lattice,
+    )));
+
+    let ghost = Arc::new(AlphaMasked::new_stochastic(
+        Arc::new(Lambertian::new_color(Color::new(0.2, 0.4, 0.8))),
+        Arc::new(SolidColor::from(Color::new(0.4, 0.4, 0.4))),
+    ));
+    world.add(Arc::new(Sphere::new(Vec3::new(0.0, 1.0, -1.0), 1.0, ghost)));
+
+    let cam = Camera::builder()
+        .with_aspect_ratio(16.0 / 9.0)
+        .with_image_width(400)
+        .with_samples_per_pixel(100)
+        .with_max_depth(100)
+        .with_vfov(30.0)
+        .with_lookfrom(Vec3::new(3.0, 2.5, 8.0))
+        .with_lookat(Vec3::new(0.0, 1.0, 0.0))
+        .with_vup(Vec3::new(0.0, 1.0, 0.0))
+        .with_defocus_angle(0.0)
+        .with_focus_dist(10.0)
+        .with_background(GradientBackground::new(
+            Color::new(0.9, 0.9, 0.9),
+            Color::new(0.6, 0.75, 1.0),
+        ))
+        .with_camera_background(TextureBackground::new(Arc::new(NoiseTexture::new(3.0))))
+        .build();
+    render(cam, &world);
 }
 
 fn simple_light() {
@@ -144,11 +673,11 @@ fn simple_light() {
         difflight,
     )));
 
-    let mut cam = Camera::builder()
+    let cam = Camera::builder()
         .with_aspect_ratio(16.0 / 9.0)
         .with_image_width(400)
         .with_samples_per_pixel(100)
-        .with_max_depth(50)
+        .with_max_depth(100)
         .with_vfov(20.0)
         .with_lookfrom(Vec3::new(26.0, 3.0, 6.0))
         .with_lookat(Vec3::new(0.0, 2.0, 0.0))
@@ -157,7 +686,166 @@ fn simple_light() {
         .with_focus_dist(10.0)
         .with_background(Color::default())
         .build();
-    cam.render(&world);
+    render(cam, &world);
+}
+
+fn delta_lights() {
+    let mut world = Hittables::default();
+    let ground = Arc::new(Lambertian::new_color(Color::new(0.5, 0.5, 0.5)));
+    world.add(Arc::new(Sphere::new(
+        Vec3::new(0.0, -1000.0, 0.0),
+        1000.0,
+        ground,
+    )));
+    world.add(Arc::new(Sphere::new(
+        Vec3::new(0.0, 1.0, 0.0),
+        1.0,
+        Arc::new(Lambertian::new(Arc::new(NoiseTexture::new(4.0)))),
+    )));
+    world.add(Arc::new(Sphere::new(
+        Vec3::new(-2.5, 1.0, 0.5),
+        1.0,
+        Arc::new(Metal::new(Color::new(0.8, 0.8, 0.8), 0.1)),
+    )));
+
+    let mut lights = Lights::default();
+    lights.add(Arc::new(PointLight::new(
+        Vec3::new(3.0, 3.0, 2.0),
+        Color::new(10.0, 8.0, 6.0),
+    )));
+    lights.add(Arc::new(
+        SpotLight::new(
+            Vec3::new(0.0, 6.0, 0.0),
+            Vec3::new(0.0, -1.0, 0.0),
+            Color::new(40.0, 40.0, 60.0),
+            15.0,
+            25.0,
+        )
+        // Sampled every 10 degrees from the axis, with a brighter ring 10
+        // degrees out as from a reflector, and no light past 30 degrees.
+        .with_profile([0.6, 1.0, 0.7].into_iter().chain([0.0; 16]).collect()),
+    ));
+    lights.add(Arc::new(DirectionalLight::new(
+        Vec3::new(-1.0, -1.0, -0.5),
+        Color::new(0.8, 0.7, 0.6),
+        0.53,
+    )));
+
+    let cam = Camera::builder()
+        .with_aspect_ratio(16.0 / 9.0)
+        .with_image_width(400)
+        .with_samples_per_pixel(100)
+        .with_max_depth(100)
+        .with_vfov(20.0)
+        .with_lookfrom(Vec3::new(13.0, 4.0, 6.0))
+        .with_lookat(Vec3::new(0.0, 1.0, 0.0))
+        .with_vup(Vec3::new(0.0, 1.0, 0.0))
+        .with_defocus_angle(0.0)
+        .with_focus_dist(10.0)
+        .with_background(Color::new(0.05, 0.05, 0.08))
+        .with_lights(lights)
+        .build();
+    render(cam, &world);
+}
+
+// Spheres lit by an HDR environment map, given with `--environment FILE`.
+fn environment_map() {
+    let Some(hdr_path) = arg_value("--environment") else {
+        eprintln!("The environment_map scene needs an HDR image, given with --environment FILE.");
+        process::exit(1);
+    };
+    let mut world = Hittables::default();
+    let ground = Arc::new(Lambertian::new_color(Color::new(0.5, 0.5, 0.5)));
+    world.add(Arc::new(Sphere::new(
+        Vec3::new(0.0, -1000.0, 0.0),
+        1000.0,
+        ground,
+    )));
+    world.add(Arc::new(Sphere::new(
+        Vec3::new(0.0, 1.0, 0.0),
+        1.0,
+        Arc::new(Lambertian::new_color(Color::new(0.8, 0.3, 0.3))),
+    )));
+    world.add(Arc::new(Sphere::new(
+        Vec3::new(-2.5, 1.0, 0.5),
+        1.0,
+        Arc::new(Metal::new(Color::new(0.8, 0.8, 0.8), 0.0)),
+    )));
+    world.add(Arc::new(Sphere::new(
+        Vec3::new(2.5, 1.0, -0.5),
+        1.0,
+        Arc::new(Dielectric::new(1.5)),
+    )));
+
+    let image = Image::load(&hdr_path).expect("failed to load environment map");
+    let environment = EnvironmentLight::new(Arc::new(image))
+        .with_intensity(1.0)
+        .with_rotation(0.0);
+
+    let cam = Camera::builder()
+        .with_aspect_ratio(16.0 / 9.0)
+        .with_image_width(400)
+        .with_samples_per_pixel(100)
+        .with_max_depth(100)
+        .with_vfov(30.0)
+        .with_lookfrom(Vec3::new(13.0, 3.0, 6.0))
+        .with_lookat(Vec3::new(0.0, 1.0, 0.0))
+        .with_vup(Vec3::new(0.0, 1.0, 0.0))
+        .with_defocus_angle(0.0)
+        .with_focus_dist(10.0)
+        .with_environment(Arc::new(environment))
+        .build();
+    render(cam, &world);
+}
+
+fn physical_sky() {
+    let mut world = Hittables::default();
+    let ground = Arc::new(Lambertian::new(Arc::new(CheckerTexture::new_solid(
+        1.0,
+        Color::new(0.2, 0.3, 0.1),
+        Color::new(0.9, 0.9, 0.9),
+    ))));
+    world.add(Arc::new(Sphere::new(
+        Vec3::new(0.0, -1000.0, 0.0),
+        1000.0,
+        ground,
+    )));
+    world.add(Arc::new(Sphere::new(
+        Vec3::new(0.0, 1.0, 0.0),
+        1.0,
+        Arc::new(Dielectric::new(1.5)),
+    )));
+    world.add(Arc::new(Sphere::new(
+        Vec3::new(-4.0, 1.0, 0.0),
+        1.0,
+        Arc::new(Lambertian::new_color(Color::new(0.4, 0.2, 0.1))),
+    )));
+    world.add(Arc::new(Sphere::new(
+        Vec3::new(4.0, 1.0, 0.0),
+        1.0,
+        Arc::new(Metal::new(Color::new(0.7, 0.6, 0.5), 0.0)),
+    )));
+
+    let sky = PhysicalSky::new(Vec3::new(-1.0, 0.6, -0.6), 3.0, Color::new(0.3, 0.3, 0.3))
+        .with_intensity(1.0);
+    let mut lights = Lights::default();
+    lights.add(Arc::new(sky.sun()));
+
+    let cam = Camera::builder()
+        .with_aspect_ratio(16.0 / 9.0)
+        .with_image_width(400)
+        .with_samples_per_pixel(100)
+        .with_max_depth(100)
+        .with_vfov(20.0)
+        .with_lookfrom(Vec3::new(13.0, 2.0, 3.0))
+        .with_lookat(Vec3::new(0.0, 0.0, 0.0))
+        .with_vup(Vec3::new(0.0, 1.0, 0.0))
+        .with_defocus_angle(0.0)
+        .with_focus_dist(10.0)
+        .with_environment(Arc::new(sky))
+        .with_lights(lights)
+        .build();
+    render(cam, &world);
 }
 
 fn quads() {
@@ -183,11 +871,11 @@ fn quads() {
         quad_color.clone(),
     )));
 
-    let mut cam = Camera::builder()
+    let cam = Camera::builder()
         .with_aspect_ratio(1.0)
         .with_image_width(400)
         .with_samples_per_pixel(100)
-        .with_max_depth(50)
+        .with_max_depth(100)
         .with_vfov(80.0)
         .with_lookfrom(Vec3::new(0.0, 0.0, 9.0))
         .with_lookat(Vec3::new(0.0, 0.0, 0.0))
@@ -196,7 +884,7 @@ fn quads() {
         .with_focus_dist(10.0)
         .with_background(Color::new(0.75, 0.1, 0.75))
         .build();
-    cam.render(&world);
+    render(cam, &world);
 }
 
 fn perlin_spheres() {
@@ -213,11 +901,11 @@ fn perlin_spheres() {
         Arc::new(Lambertian::new(perlin.clone())),
     )));
 
-    let mut cam = Camera::builder()
+    let cam = Camera::builder()
         .with_aspect_ratio(16.0 / 9.0)
         .with_image_width(400)
         .with_samples_per_pixel(100)
-        .with_max_depth(50)
+        .with_max_depth(100)
         .with_vfov(20.0)
         .with_lookfrom(Vec3::new(13.0, 2.0, 3.0))
         .with_lookat(Vec3::new(0.0, 0.0, 0.0))
@@ -227,7 +915,7 @@ fn perlin_spheres() {
         .with_background(Color::new(0.75, 0.1, 0.75))
         .build();
 
-    cam.render(&world);
+    render(cam, &world);
 }
 
 fn checkered_spheres() {
@@ -248,11 +936,11 @@ fn checkered_spheres() {
         Arc::new(Lambertian::new(checker)),
     )));
 
-    let mut cam = Camera::builder()
+    let cam = Camera::builder()
         .with_aspect_ratio(16.0 / 9.0)
         .with_image_width(400)
         .with_samples_per_pixel(100)
-        .with_max_depth(50)
+        .with_max_depth(100)
         .with_vfov(20.0)
         .with_lookfrom(Vec3::new(13.0, 2.0, 3.0))
         .with_lookat(Vec3::new(0.0, 0.0, 0.0))
@@ -262,7 +950,7 @@ fn checkered_spheres() {
         .with_background(Color::new(0.75, 0.1, 0.75))
         .build();
 
-    cam.render(&world);
+    render(cam, &world);
 }
 
 fn bouncing_spheres() {
@@ -333,11 +1021,11 @@ fn bouncing_spheres() {
     let bvh = BVHNode::from(world);
     let world = Hittables::from(Arc::new(bvh));
 
-    let mut cam = Camera::builder()
+    let cam = Camera::builder()
         .with_aspect_ratio(16.0 / 9.0)
         .with_image_width(600)
         .with_samples_per_pixel(5)
-        .with_max_depth(50)
+        .with_max_depth(100)
         .with_vfov(20.0)
         .with_lookfrom(Vec3::new(13.0, 2.0, 3.0))
         .with_lookat(Vec3::new(0.0, 0.0, 0.0))
@@ -347,5 +1035,5 @@ fn bouncing_spheres() {
         .with_background(Color::new(1.0, 1.0, 1.0))
         .build();
 
-    cam.render(&world);
+    render(cam, &world);
 }
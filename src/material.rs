@@ -1,15 +1,31 @@
+use std::f64::consts::PI;
 use std::sync::Arc;
+use std::sync::atomic::{AtomicU32, Ordering};
 
 use crate::{
-    color::Color,
+    color::{Color, blackbody},
     hit::HitRecord,
     ray::Ray,
+    sampler::mix_bits,
     texture::{SolidColor, Texture},
     utils::random_f64,
-    vec3::{Vec3, dot, unit_vector},
+    vec3::{Vec3, cross, dot, unit_vector},
 };
 
+static NEXT_MATERIAL_ID: AtomicU32 = AtomicU32::new(0);
+
+// Returns a new unique id for a material. Ids are handed out in the order
+// materials are created, so a scene gets the same ids on every run, and in
+// every process rendering it.
+pub fn next_material_id() -> u32 {
+    NEXT_MATERIAL_ID.fetch_add(1, Ordering::Relaxed)
+}
+
 pub trait Material: Send + Sync {
+    // Returns the id the material was given when it was created, see
+    // next_material_id. Wrappers report the id of the material they wrap.
+    fn id(&self) -> u32;
+
     fn scatter(
         &self,
         r_in: &Ray,
@@ -20,28 +36,64 @@ pub trait Material: Send + Sync {
         false
     }
 
-    fn emitted(&self, u: f64, v: f64, p: Vec3) -> Color {
+    // Returns the density, per unit solid angle, with which scatter would produce
+    // the scattered ray. Lambertian-style materials, whose attenuation times this
+    // density gives the BSDF times the cosine term, can then be lit by sampling
+    // lights directly. Specular materials leave this at zero.
+    fn scattering_pdf(&self, _r_in: &Ray, _rec: &HitRecord, _scattered: &Ray) -> f64 {
+        0.0
+    }
+
+    // Returns the light emitted from the surface at the hit point towards the
+    // origin of the incoming ray r_in.
+    fn emitted(&self, _r_in: &Ray, _rec: &HitRecord) -> Color {
         Color::default()
     }
+
+    // Returns the fraction of light the surface reflects at the hit, for the
+    // albedo pass and the denoiser. Unlike scatter, this draws no random
+    // numbers, so looking it up doesn't change the rest of the sample.
+    fn albedo(&self, _rec: &HitRecord) -> Color {
+        Color::default()
+    }
+
+    // Returns the normal used for shading at the hit, facing the side the ray
+    // arrived from. Materials that perturb the normal override this.
+    fn shading_normal(&self, rec: &HitRecord) -> Vec3 {
+        rec.normal
+    }
+
+    // Determines whether the surface is cut out (fully transparent) at the given
+    // point. Primitives call this during intersection, so rays pass straight
+    // through cut-out regions as if there were no surface there.
+    fn cut_out(&self, _u: f64, _v: f64, _p: Vec3) -> bool {
+        false
+    }
 }
 
 pub struct Lambertian {
     tex: Arc<dyn Texture>,
+    id: u32,
 }
 
 impl Lambertian {
     pub fn new(tex: Arc<dyn Texture>) -> Self {
-        Self { tex }
+        Self {
+            tex,
+            id: next_material_id(),
+        }
     }
 
     pub fn new_color(albedo: Color) -> Self {
-        Self {
-            tex: Arc::new(SolidColor::from(albedo)),
-        }
+        Self::new(Arc::new(SolidColor::from(albedo)))
     }
 }
 
 impl Material for Lambertian {
+    fn id(&self) -> u32 {
+        self.id
+    }
+
     fn scatter(
         &self,
         r_in: &Ray,
@@ -59,20 +111,38 @@ impl Material for Lambertian {
         *attenuation = self.tex.value(rec.u, rec.v, rec.p);
         true
     }
+
+    fn albedo(&self, rec: &HitRecord) -> Color {
+        self.tex.value(rec.u, rec.v, rec.p)
+    }
+
+    fn scattering_pdf(&self, _r_in: &Ray, rec: &HitRecord, scattered: &Ray) -> f64 {
+        let cos_theta = dot(rec.normal, unit_vector(scattered.direction()));
+        f64::max(0.0, cos_theta / PI)
+    }
 }
 
 pub struct Metal {
     albedo: Color,
     fuzz: f64,
+    id: u32,
 }
 
 impl Metal {
     pub fn new(albedo: Color, fuzz: f64) -> Self {
-        Self { albedo, fuzz }
+        Self {
+            albedo,
+            fuzz,
+            id: next_material_id(),
+        }
     }
 }
 
 impl Material for Metal {
+    fn id(&self) -> u32 {
+        self.id
+    }
+
     fn scatter(
         &self,
         r_in: &Ray,
@@ -87,6 +157,10 @@ impl Material for Metal {
 
         return dot(scattered.direction(), rec.normal) > 0.0;
     }
+
+    fn albedo(&self, _rec: &HitRecord) -> Color {
+        self.albedo
+    }
 }
 
 pub struct Dielectric {
@@ -94,11 +168,15 @@ pub struct Dielectric {
     // of the material's refractive index over the refractive index
     // of its surrounding medium.
     refraction_index: f64,
+    id: u32,
 }
 
 impl Dielectric {
     pub fn new(refraction_index: f64) -> Self {
-        Self { refraction_index }
+        Self {
+            refraction_index,
+            id: next_material_id(),
+        }
     }
 
     pub fn reflectance(cosine: f64, refraction_index: f64) -> f64 {
@@ -109,6 +187,10 @@ impl Dielectric {
 }
 
 impl Material for Dielectric {
+    fn id(&self) -> u32 {
+        self.id
+    }
+
     fn scatter(
         &self,
         r_in: &Ray,
@@ -137,26 +219,435 @@ impl Material for Dielectric {
         *scattered = Ray::new(rec.p, direction).with_time(r_in.time());
         return true;
     }
+
+    fn albedo(&self, _rec: &HitRecord) -> Color {
+        Color::new(1.0, 1.0, 1.0)
+    }
 }
 
 pub struct DiffuseLight {
     tex: Arc<dyn Texture>,
+    // Scale applied to the texture value, so the same texture can drive lights
+    // of different power.
+    intensity: f64,
+    // One-sided lights only emit from the front face, i.e. along the direction of
+    // the primitive's outward normal.
+    one_sided: bool,
+    id: u32,
 }
 
 impl DiffuseLight {
     pub fn new(tex: Arc<dyn Texture>) -> Self {
-        Self { tex }
+        Self {
+            tex,
+            intensity: 1.0,
+            one_sided: false,
+            id: next_material_id(),
+        }
     }
 
     pub fn new_color(emit: Color) -> Self {
+        Self::new(Arc::new(SolidColor::from(emit)))
+    }
+
+    // Creates a light with the color of a blackbody radiator at the given
+    // temperature (in kelvin), normalized to unit luminance. Use with_intensity
+    // to set its brightness.
+    pub fn new_blackbody(kelvin: f64) -> Self {
+        Self::new_color(blackbody(kelvin))
+    }
+
+    pub fn with_intensity(mut self, intensity: f64) -> Self {
+        self.intensity = intensity;
+        self
+    }
+
+    pub fn with_one_sided(mut self, one_sided: bool) -> Self {
+        self.one_sided = one_sided;
+        self
+    }
+}
+
+impl Material for DiffuseLight {
+    fn id(&self) -> u32 {
+        self.id
+    }
+
+    fn emitted(&self, _r_in: &Ray, rec: &HitRecord) -> Color {
+        if self.one_sided && !rec.front_face {
+            return Color::default();
+        }
+        self.tex.value(rec.u, rec.v, rec.p) * self.intensity
+    }
+}
+
+// Returns the outward-facing (geometric side) unit normal of the hit, undoing the
+// flip applied by HitRecord::set_face_normal.
+fn outward_normal(rec: &HitRecord) -> Vec3 {
+    if rec.front_face {
+        rec.normal
+    } else {
+        -rec.normal
+    }
+}
+
+// Returns a copy of the hit record with its shading normal replaced by the given
+// outward-facing normal, flipped to the side the ray arrived from.
+fn with_shading_normal(rec: &HitRecord, outward: Vec3) -> HitRecord {
+    let mut shading = rec.clone();
+    shading.normal = if rec.front_face { outward } else { -outward };
+    shading
+}
+
+// Wraps a material, perturbing its shading normal with a tangent-space normal map.
+// The map's RGB channels in [0,1] encode the XYZ components of the normal in
+// [-1,1], with Z pointing away from the surface.
+pub struct NormalMapped {
+    inner: Arc<dyn Material>,
+    map: Arc<dyn Texture>,
+}
+
+impl NormalMapped {
+    pub fn new(inner: Arc<dyn Material>, map: Arc<dyn Texture>) -> Self {
+        Self { inner, map }
+    }
+
+    fn shading_record(&self, rec: &HitRecord) -> HitRecord {
+        let n = outward_normal(rec);
+        let t = rec.dpdu - n * dot(n, rec.dpdu);
+        if t.near_zero() {
+            return rec.clone();
+        }
+        let t = unit_vector(t);
+        let b = cross(n, t);
+
+        let m = self.map.value(rec.u, rec.v, rec.p) * 2.0 - Vec3::new(1.0, 1.0, 1.0);
+        let perturbed = t * m.x() + b * m.y() + n * m.z();
+        if perturbed.near_zero() {
+            return rec.clone();
+        }
+        with_shading_normal(rec, unit_vector(perturbed))
+    }
+}
+
+impl Material for NormalMapped {
+    fn id(&self) -> u32 {
+        self.inner.id()
+    }
+
+    fn scatter(
+        &self,
+        r_in: &Ray,
+        rec: &HitRecord,
+        attenuation: &mut Color,
+        scattered: &mut Ray,
+    ) -> bool {
+        self.inner
+            .scatter(r_in, &self.shading_record(rec), attenuation, scattered)
+    }
+
+    fn scattering_pdf(&self, r_in: &Ray, rec: &HitRecord, scattered: &Ray) -> f64 {
+        self.inner
+            .scattering_pdf(r_in, &self.shading_record(rec), scattered)
+    }
+
+    fn emitted(&self, r_in: &Ray, rec: &HitRecord) -> Color {
+        self.inner.emitted(r_in, rec)
+    }
+
+    fn albedo(&self, rec: &HitRecord) -> Color {
+        self.inner.albedo(rec)
+    }
+
+    fn shading_normal(&self, rec: &HitRecord) -> Vec3 {
+        self.inner.shading_normal(&self.shading_record(rec))
+    }
+
+    fn cut_out(&self, u: f64, v: f64, p: Vec3) -> bool {
+        self.inner.cut_out(u, v, p)
+    }
+}
+
+// Wraps a material, perturbing its shading normal as if the surface were displaced
+// along its normal by the given scalar texture (the average of its channels) times
+// `scale`.
+pub struct BumpMapped {
+    inner: Arc<dyn Material>,
+    bump: Arc<dyn Texture>,
+    scale: f64,
+}
+
+impl BumpMapped {
+    pub fn new(inner: Arc<dyn Material>, bump: Arc<dyn Texture>, scale: f64) -> Self {
+        Self { inner, bump, scale }
+    }
+
+    fn displacement(&self, u: f64, v: f64, p: Vec3) -> f64 {
+        let c = self.bump.value(u, v, p);
+        self.scale * (c.x() + c.y() + c.z()) / 3.0
+    }
+
+    fn shading_record(&self, rec: &HitRecord) -> HitRecord {
+        // Finite difference step, chosen to move the same small distance across
+        // the surface regardless of how the primitive is parametrized.
+        const STEP: f64 = 1e-3;
+        let dpdu_len = rec.dpdu.length();
+        let dpdv_len = rec.dpdv.length();
+        if dpdu_len <= 0.0 || dpdv_len <= 0.0 {
+            return rec.clone();
+        }
+        let du = STEP / dpdu_len;
+        let dv = STEP / dpdv_len;
+
+        let n = outward_normal(rec);
+        let d = self.displacement(rec.u, rec.v, rec.p);
+        let d_du = (self.displacement(rec.u + du, rec.v, rec.p + rec.dpdu * du) - d) / du;
+        let d_dv = (self.displacement(rec.u, rec.v + dv, rec.p + rec.dpdv * dv) - d) / dv;
+
+        let dpdu = rec.dpdu + n * d_du;
+        let dpdv = rec.dpdv + n * d_dv;
+        let perturbed = cross(dpdu, dpdv);
+        if perturbed.near_zero() {
+            return rec.clone();
+        }
+        // Keep the perturbed normal on the same side as the geometric one,
+        // whatever the handedness of the parametrization.
+        let perturbed = unit_vector(perturbed);
+        let perturbed = if dot(perturbed, n) < 0.0 {
+            -perturbed
+        } else {
+            perturbed
+        };
+        with_shading_normal(rec, perturbed)
+    }
+}
+
+impl Material for BumpMapped {
+    fn id(&self) -> u32 {
+        self.inner.id()
+    }
+
+    fn scatter(
+        &self,
+        r_in: &Ray,
+        rec: &HitRecord,
+        attenuation: &mut Color,
+        scattered: &mut Ray,
+    ) -> bool {
+        self.inner
+            .scatter(r_in, &self.shading_record(rec), attenuation, scattered)
+    }
+
+    fn scattering_pdf(&self, r_in: &Ray, rec: &HitRecord, scattered: &Ray) -> f64 {
+        self.inner
+            .scattering_pdf(r_in, &self.shading_record(rec), scattered)
+    }
+
+    fn emitted(&self, r_in: &Ray, rec: &HitRecord) -> Color {
+        self.inner.emitted(r_in, rec)
+    }
+
+    fn albedo(&self, rec: &HitRecord) -> Color {
+        self.inner.albedo(rec)
+    }
+
+    fn shading_normal(&self, rec: &HitRecord) -> Vec3 {
+        self.inner.shading_normal(&self.shading_record(rec))
+    }
+
+    fn cut_out(&self, u: f64, v: f64, p: Vec3) -> bool {
+        self.inner.cut_out(u, v, p)
+    }
+}
+
+// How an alpha mask decides whether a point is cut out.
+#[derive(Clone, Copy)]
+pub enum AlphaMode {
+    // Points with alpha below the threshold are cut out.
+    Threshold(f64),
+    // Points are kept with probability equal to their alpha, which renders
+    // partial transparency correctly once averaged over many samples. Whether
+    // a point is kept is decided by hashing it rather than by drawing a random
+    // number, so every ray reaching the same point agrees on it.
+    Stochastic,
+}
+
+// Wraps a material with an opacity texture (the average of its channels) so that
+// parts of the surface can be cut out, e.g. leaves or fences drawn on quads.
+pub struct AlphaMasked {
+    inner: Arc<dyn Material>,
+    alpha: Arc<dyn Texture>,
+    mode: AlphaMode,
+}
+
+impl AlphaMasked {
+    pub fn new(inner: Arc<dyn Material>, alpha: Arc<dyn Texture>, threshold: f64) -> Self {
+        Self {
+            inner,
+            alpha,
+            mode: AlphaMode::Threshold(threshold),
+        }
+    }
+
+    pub fn new_stochastic(inner: Arc<dyn Material>, alpha: Arc<dyn Texture>) -> Self {
         Self {
-            tex: Arc::new(SolidColor::from(emit)),
+            inner,
+            alpha,
+            mode: AlphaMode::Stochastic,
         }
     }
 }
 
-impl Material for DiffuseLight {
-    fn emitted(&self, u: f64, v: f64, p: Vec3) -> Color {
-        self.tex.value(u, v, p)
+impl Material for AlphaMasked {
+    fn id(&self) -> u32 {
+        self.inner.id()
+    }
+
+    fn scatter(
+        &self,
+        r_in: &Ray,
+        rec: &HitRecord,
+        attenuation: &mut Color,
+        scattered: &mut Ray,
+    ) -> bool {
+        self.inner.scatter(r_in, rec, attenuation, scattered)
+    }
+
+    fn scattering_pdf(&self, r_in: &Ray, rec: &HitRecord, scattered: &Ray) -> f64 {
+        self.inner.scattering_pdf(r_in, rec, scattered)
+    }
+
+    fn emitted(&self, r_in: &Ray, rec: &HitRecord) -> Color {
+        self.inner.emitted(r_in, rec)
+    }
+
+    fn albedo(&self, rec: &HitRecord) -> Color {
+        self.inner.albedo(rec)
+    }
+
+    fn shading_normal(&self, rec: &HitRecord) -> Vec3 {
+        self.inner.shading_normal(rec)
+    }
+
+    fn cut_out(&self, u: f64, v: f64, p: Vec3) -> bool {
+        let c = self.alpha.value(u, v, p);
+        let alpha = (c.x() + c.y() + c.z()) / 3.0;
+        let masked = match self.mode {
+            AlphaMode::Threshold(threshold) => alpha < threshold,
+            AlphaMode::Stochastic => point_hash(u, v, p) >= alpha,
+        };
+        masked || self.inner.cut_out(u, v, p)
+    }
+}
+
+// Returns a value in [0,1) that looks random but only depends on the surface
+// point. Coordinates are snapped to a fine grid first, so rays that hit the same
+// point from different directions get the same value despite rounding errors.
+fn point_hash(u: f64, v: f64, p: Vec3) -> f64 {
+    const GRID: f64 = (1u64 << 20) as f64;
+    let hash = [u, v, p.x(), p.y(), p.z()].into_iter().fold(0, |hash, x| {
+        mix_bits(hash ^ (x * GRID).floor() as i64 as u64)
+    });
+    (hash >> 11) as f64 / (1u64 << 53) as f64
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn stochastic_alpha_is_fixed_per_point() {
+        let mat = AlphaMasked::new_stochastic(
+            Arc::new(Lambertian::new_color(Color::new(0.5, 0.5, 0.5))),
+            Arc::new(SolidColor::from(Color::new(0.3, 0.3, 0.3))),
+        );
+        let n = 200;
+        let mut cut = 0;
+        for i in 0..n {
+            for j in 0..n {
+                let (u, v) = (i as f64 / n as f64, j as f64 / n as f64);
+                let p = Vec3::new(u * 10.0, 2.0, v * 10.0);
+                let first = mat.cut_out(u, v, p);
+                assert_eq!(first, mat.cut_out(u, v, p));
+                // Rounding errors from a different ray don't change the answer.
+                assert_eq!(first, mat.cut_out(u, v, p + Vec3::new(1e-12, 0.0, 0.0)));
+                cut += first as usize;
+            }
+        }
+        let fraction = cut as f64 / (n * n) as f64;
+        assert!((fraction - 0.7).abs() < 0.02, "cut out {fraction}");
+    }
+
+    // A height field rising along u at the given slope.
+    struct Ramp(f64);
+
+    impl Texture for Ramp {
+        fn value(&self, u: f64, _v: f64, _p: Vec3) -> Color {
+            Color::new(u, u, u) * self.0
+        }
+    }
+
+    fn flat_hit() -> HitRecord {
+        HitRecord {
+            p: Vec3::new(0.5, 0.5, 0.0),
+            normal: Vec3::new(0.0, 0.0, 1.0),
+            front_face: true,
+            u: 0.5,
+            v: 0.5,
+            dpdu: Vec3::new(1.0, 0.0, 0.0),
+            dpdv: Vec3::new(0.0, 1.0, 0.0),
+            ..Default::default()
+        }
+    }
+
+    fn assert_near(a: Vec3, b: Vec3) {
+        assert!((a - b).length() < 1e-6, "{a:?} != {b:?}");
+    }
+
+    #[test]
+    fn flat_maps_keep_the_geometric_normal() {
+        let base: Arc<dyn Material> = Arc::new(Lambertian::new_color(Color::new(0.5, 0.5, 0.5)));
+        let normal_mapped = NormalMapped::new(
+            base.clone(),
+            Arc::new(SolidColor::from(Color::new(0.5, 0.5, 1.0))),
+        );
+        let bump_mapped = BumpMapped::new(
+            base,
+            Arc::new(SolidColor::from(Color::new(0.7, 0.7, 0.7))),
+            1.0,
+        );
+        let mut rec = flat_hit();
+        for mat in [&normal_mapped as &dyn Material, &bump_mapped] {
+            assert_near(mat.shading_normal(&rec), rec.normal);
+        }
+        // Seen from behind, the normal still faces the ray.
+        rec.front_face = false;
+        rec.normal = -rec.normal;
+        for mat in [&normal_mapped as &dyn Material, &bump_mapped] {
+            assert_near(mat.shading_normal(&rec), rec.normal);
+        }
+    }
+
+    #[test]
+    fn tilted_maps_tilt_the_normal() {
+        let base: Arc<dyn Material> = Arc::new(Lambertian::new_color(Color::new(0.5, 0.5, 0.5)));
+        let rec = flat_hit();
+
+        // (0.75, 0.5, 1) encodes a normal of (0.5, 0, 1), leaning towards dpdu.
+        let normal_mapped = NormalMapped::new(
+            base.clone(),
+            Arc::new(SolidColor::from(Color::new(0.75, 0.5, 1.0))),
+        );
+        assert_near(
+            normal_mapped.shading_normal(&rec),
+            unit_vector(Vec3::new(0.5, 0.0, 1.0)),
+        );
+
+        // A surface rising along u at slope 0.5 faces back down the slope.
+        let bump_mapped = BumpMapped::new(base, Arc::new(Ramp(0.5)), 1.0);
+        assert_near(
+            bump_mapped.shading_normal(&rec),
+            unit_vector(Vec3::new(-0.5, 0.0, 1.0)),
+        );
     }
 }
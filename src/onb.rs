@@ -0,0 +1,28 @@
+use crate::vec3::{Vec3, cross, unit_vector};
+
+// An orthonormal basis whose w axis is aligned with a given direction.
+#[derive(Clone, Copy)]
+pub struct Onb {
+    u: Vec3,
+    v: Vec3,
+    w: Vec3,
+}
+
+impl Onb {
+    pub fn new(n: Vec3) -> Self {
+        let w = unit_vector(n);
+        let a = if f64::abs(w.x()) > 0.9 {
+            Vec3::new(0.0, 1.0, 0.0)
+        } else {
+            Vec3::new(1.0, 0.0, 0.0)
+        };
+        let v = unit_vector(cross(w, a));
+        let u = cross(w, v);
+        Self { u, v, w }
+    }
+
+    // Transforms a vector from basis coordinates to world coordinates.
+    pub fn transform(&self, v: Vec3) -> Vec3 {
+        self.u * v.x() + self.v * v.y() + self.w * v.z()
+    }
+}
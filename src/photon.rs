@@ -0,0 +1,529 @@
+use std::cmp::Ordering;
+use std::collections::BinaryHeap;
+use std::f64::consts::PI;
+use std::sync::RwLock;
+
+use rayon::iter::{IntoParallelIterator, ParallelIterator};
+
+use crate::{
+    camera::Camera,
+    color::Color,
+    hit::{Hit, HitRecord},
+    integrator::{Integrator, LightPaths},
+    interval::Interval,
+    light::Light,
+    ray::Ray,
+    utils::random_f64,
+    vec3::{Vec3, dot, unit_vector},
+};
+
+// A packet of light stored where it landed on a diffuse surface.
+struct Photon {
+    p: Vec3,
+    // Unit direction the photon was travelling in.
+    direction: Vec3,
+    // Surface normal at p, facing the side the photon arrived from.
+    normal: Vec3,
+    power: Color,
+}
+
+// Candidate photon in a nearest neighbour search, ordered by distance.
+struct Neighbor {
+    dist2: f64,
+    index: usize,
+}
+
+impl PartialEq for Neighbor {
+    fn eq(&self, other: &Self) -> bool {
+        self.dist2 == other.dist2
+    }
+}
+
+impl Eq for Neighbor {}
+
+impl PartialOrd for Neighbor {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for Neighbor {
+    fn cmp(&self, other: &Self) -> Ordering {
+        self.dist2.total_cmp(&other.dist2)
+    }
+}
+
+// Photons arranged as a balanced kd-tree stored in an array: the median of each
+// range is its node, splitting the photons before and after it along `axes`.
+#[derive(Default)]
+struct PhotonMap {
+    photons: Vec<Photon>,
+    // Split axis of the node at each index.
+    axes: Vec<usize>,
+}
+
+impl PhotonMap {
+    fn new(mut photons: Vec<Photon>) -> Self {
+        let mut axes = vec![0; photons.len()];
+        Self::build(&mut photons, &mut axes);
+        Self { photons, axes }
+    }
+
+    fn build(photons: &mut [Photon], axes: &mut [usize]) {
+        if photons.len() <= 1 {
+            return;
+        }
+
+        // Split along the axis with the largest extent.
+        let mut min = Vec3::new(f64::INFINITY, f64::INFINITY, f64::INFINITY);
+        let mut max = -min;
+        for photon in photons.iter() {
+            for a in 0..3 {
+                min[a] = f64::min(min[a], photon.p[a]);
+                max[a] = f64::max(max[a], photon.p[a]);
+            }
+        }
+        let extent = max - min;
+        let axis = (0..3)
+            .max_by(|&a, &b| extent[a].total_cmp(&extent[b]))
+            .unwrap();
+
+        let mid = photons.len() / 2;
+        photons.select_nth_unstable_by(mid, |a, b| a.p[axis].total_cmp(&b.p[axis]));
+        axes[mid] = axis;
+        let (left, right) = photons.split_at_mut(mid);
+        let (left_axes, right_axes) = axes.split_at_mut(mid);
+        Self::build(left, left_axes);
+        Self::build(&mut right[1..], &mut right_axes[1..]);
+    }
+
+    // Finds up to k photons closest to p within squared distance max_dist2.
+    fn nearest(&self, p: Vec3, k: usize, max_dist2: f64) -> Vec<Neighbor> {
+        let mut heap = BinaryHeap::with_capacity(k + 1);
+        self.search(0, self.photons.len(), p, k, max_dist2, &mut heap);
+        heap.into_vec()
+    }
+
+    fn search(
+        &self,
+        start: usize,
+        end: usize,
+        p: Vec3,
+        k: usize,
+        max_dist2: f64,
+        heap: &mut BinaryHeap<Neighbor>,
+    ) {
+        if start >= end {
+            return;
+        }
+        let mid = start + (end - start) / 2;
+        let photon = &self.photons[mid];
+        let axis = self.axes[mid];
+        let delta = p[axis] - photon.p[axis];
+
+        // Visit the side containing p first, then the other side if it could
+        // still hold closer photons.
+        let (near, far) = if delta < 0.0 {
+            ((start, mid), (mid + 1, end))
+        } else {
+            ((mid + 1, end), (start, mid))
+        };
+        self.search(near.0, near.1, p, k, max_dist2, heap);
+
+        let dist2 = (photon.p - p).length_squared();
+        if dist2 < max_dist2 {
+            heap.push(Neighbor { dist2, index: mid });
+            if heap.len() > k {
+                heap.pop();
+            }
+        }
+
+        let bound = if heap.len() == k {
+            heap.peek().map_or(max_dist2, |n| n.dist2)
+        } else {
+            max_dist2
+        };
+        if delta * delta < bound {
+            self.search(far.0, far.1, p, k, max_dist2, heap);
+        }
+    }
+}
+
+// A path tracer that renders caustics (light focused onto diffuse surfaces by
+// specular ones) from a photon map. Before rendering, photons are shot from the
+// camera's lights that are in the scene (area, point and spot lights), and
+// those reaching a diffuse surface through one or more specular bounces are
+// stored. Camera paths estimate caustics from the nearest photons at every
+// diffuse hit, and ignore the same light when they find it by chance.
+//
+// Lights at infinity and the background don't shoot photons, so their caustics
+// are still path traced.
+pub struct PhotonMapper {
+    photon_count: usize,
+    // Number of photons gathered for each estimate, and the largest radius to
+    // gather them from.
+    gather_count: usize,
+    gather_radius: f64,
+    map: RwLock<PhotonMap>,
+}
+
+impl PhotonMapper {
+    // Creates a photon mapper shooting the given number of photons.
+    pub fn new(photon_count: usize) -> Self {
+        Self {
+            photon_count,
+            gather_count: 50,
+            gather_radius: f64::INFINITY,
+            map: RwLock::default(),
+        }
+    }
+
+    // Sets how many photons each estimate uses, and the radius (in world units)
+    // to look for them in. Larger values blur caustics but reduce noise.
+    pub fn with_gather(mut self, count: usize, radius: f64) -> Self {
+        self.gather_count = count.max(1);
+        self.gather_radius = radius;
+        self
+    }
+
+    // Traces one photon from a randomly picked light, returning the photon it
+    // deposits, if any.
+    fn trace_photon(&self, cam: &Camera, world: &dyn Hit, lights: &[&dyn Light]) -> Option<Photon> {
+        let index = ((random_f64() * lights.len() as f64) as usize).min(lights.len() - 1);
+        let light_pdf = 1.0 / lights.len() as f64;
+        let emission = lights[index].sample_emission(random_f64())?;
+        if emission.pdf_pos <= 0.0 || emission.pdf_dir <= 0.0 {
+            return None;
+        }
+        let cos_theta = match emission.normal {
+            Some(n) => f64::abs(dot(n, emission.ray.direction())),
+            None => 1.0,
+        };
+        let mut power = emission.radiance * cos_theta
+            / (light_pdf * emission.pdf_pos * emission.pdf_dir * self.photon_count as f64);
+        let mut ray = emission.ray;
+
+        for bounce in 0..cam.max_depth {
+            let mut rec = HitRecord::default();
+            if !world.hit(&ray, Interval::new(0.001, f64::INFINITY), &mut rec) {
+                return None;
+            }
+            let mut scattered = Ray::default();
+            let mut attenuation = Color::default();
+            if !rec
+                .mat
+                .scatter(&ray, &rec, &mut attenuation, &mut scattered)
+            {
+                return None;
+            }
+            if rec.mat.scattering_pdf(&ray, &rec, &scattered) > 0.0 {
+                // Only light arriving through specular bounces is a caustic.
+                return (bounce > 0).then(|| Photon {
+                    p: rec.p,
+                    direction: unit_vector(ray.direction()),
+                    normal: rec.normal,
+                    power,
+                });
+            }
+            power = power * attenuation;
+            ray = scattered;
+        }
+        None
+    }
+
+    // Estimates the caustic light leaving the diffuse hit rec back along r_in,
+    // from the photons around it.
+    fn caustics(&self, map: &PhotonMap, r_in: &Ray, rec: &HitRecord, attenuation: Color) -> Color {
+        let max_dist2 = self.gather_radius * self.gather_radius;
+        let neighbors = map.nearest(rec.p, self.gather_count, max_dist2);
+        let Some(farthest) = neighbors.iter().map(|n| n.dist2).max_by(f64::total_cmp) else {
+            return Color::default();
+        };
+        // The estimate covers the disk holding the photons found, or the whole
+        // gather radius if there weren't enough of them.
+        let area_dist2 = if neighbors.len() < self.gather_count && max_dist2.is_finite() {
+            max_dist2
+        } else {
+            farthest
+        };
+        if area_dist2 <= 0.0 {
+            return Color::default();
+        }
+
+        let mut flux = Color::default();
+        for neighbor in neighbors.iter() {
+            let photon = &map.photons[neighbor.index];
+            // Skip photons on the other side of the surface.
+            let cos_theta = -dot(photon.direction, rec.normal);
+            if dot(photon.normal, rec.normal) <= 0.0 || cos_theta <= 0.0 {
+                continue;
+            }
+            let incoming = Ray::new(rec.p, -photon.direction);
+            let pdf = rec.mat.scattering_pdf(r_in, rec, &incoming);
+            flux += photon.power * (pdf / cos_theta);
+        }
+        attenuation * flux / (PI * area_dist2)
+    }
+
+    // Returns whether the surface rec that r hit is one of the lights that shot
+    // photons.
+    fn is_photon_light(&self, cam: &Camera, r: &Ray, rec: &HitRecord) -> bool {
+        cam.all_lights()
+            .any(|light| !light.is_infinite() && light.hit_pdf(r, rec) > 0.0)
+    }
+}
+
+impl Integrator for PhotonMapper {
+    fn preprocess(&self, cam: &Camera, world: &dyn Hit) {
+        let lights: Vec<&dyn Light> = cam.all_lights().filter(|l| !l.is_infinite()).collect();
+        let photons: Vec<Photon> = if lights.is_empty() {
+            Vec::new()
+        } else {
+            eprintln!("Tracing photons...");
+            (0..self.photon_count)
+                .into_par_iter()
+                .filter_map(|n| {
+                    cam.seed_random(&[n as u64]);
+                    self.trace_photon(cam, world, &lights)
+                })
+                .collect()
+        };
+        eprintln!("Stored {} caustic photons.", photons.len());
+        *self.map.write().unwrap() = PhotonMap::new(photons);
+    }
+
+    fn li(&self, cam: &Camera, r: &Ray, world: &dyn Hit) -> Color {
+        self.li_paths(cam, r, world).total()
+    }
+
+    fn li_paths(&self, cam: &Camera, r: &Ray, world: &dyn Hit) -> LightPaths {
+        let map = self.map.read().unwrap();
+        let mut color = LightPaths::default();
+        let mut throughput = Color::new(1.0, 1.0, 1.0);
+        let mut ray = *r;
+        let mut scatter_pdf = None;
+        // Whether the path has bounced off a diffuse surface and only specular
+        // ones since, so any light it finds now was already shot as a photon.
+        let mut after_diffuse = false;
+        let mut caustic = false;
+
+        for bounce in 0..cam.max_depth {
+            let mut rec = HitRecord::default();
+            if !world.hit(&ray, Interval::new(0.001, f64::INFINITY), &mut rec) {
+                color.add(
+                    bounce,
+                    throughput * cam.background_color(&ray, bounce == 0, scatter_pdf),
+                );
+                break;
+            }
+
+            let emitted = rec.mat.emitted(&ray, &rec);
+            if emitted.max_component() > 0.0 && !(caustic && self.is_photon_light(cam, &ray, &rec))
+            {
+                color.add(
+                    bounce,
+                    throughput * emitted * cam.emission_weight(&ray, &rec, scatter_pdf),
+                );
+            }
+            let mut scattered = Ray::default();
+            let mut attenuation = Color::default();
+            if !rec
+                .mat
+                .scatter(&ray, &rec, &mut attenuation, &mut scattered)
+            {
+                break;
+            }
+
+            let pdf = rec.mat.scattering_pdf(&ray, &rec, &scattered);
+            if pdf > 0.0 {
+                color.add(
+                    bounce + 1,
+                    throughput * cam.sample_lights(&ray, &rec, attenuation, world),
+                );
+                // Caustics have bounced off at least one more surface.
+                color.add(
+                    bounce + 2,
+                    throughput * self.caustics(&map, &ray, &rec, attenuation),
+                );
+                after_diffuse = true;
+                caustic = false;
+            } else {
+                caustic = after_diffuse;
+            }
+            throughput = throughput * attenuation;
+
+            if cam
+                .russian_roulette_depth
+                .is_some_and(|min| bounce + 1 >= min)
+            {
+                let survival = f64::min(throughput.max_component(), 0.95);
+                if random_f64() >= survival {
+                    break;
+                }
+                throughput /= survival;
+            }
+
+            scatter_pdf = if pdf > 0.0 { Some(pdf) } else { None };
+            ray = scattered;
+        }
+        color
+    }
+
+    fn describe(&self) -> String {
+        format!(
+            "photon_mapper {} {} {}",
+            self.photon_count, self.gather_count, self.gather_radius
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use super::*;
+    use crate::{
+        hit::Hittables,
+        integrator::PathTracer,
+        light::{AreaLight, Lights},
+        material::{DiffuseLight, Lambertian, Material, Metal},
+        quad::Quad,
+        utils::{random_range_f64, seed_random},
+    };
+
+    fn random_point() -> Vec3 {
+        Vec3::new(random_f64(), random_f64(), random_f64()) * 10.0
+    }
+
+    #[test]
+    fn nearest_matches_brute_force() {
+        seed_random(1);
+        let photons = (0..2000)
+            .map(|_| Photon {
+                p: random_point(),
+                direction: Vec3::new(0.0, -1.0, 0.0),
+                normal: Vec3::new(0.0, 1.0, 0.0),
+                power: Color::new(1.0, 1.0, 1.0),
+            })
+            .collect();
+        let map = PhotonMap::new(photons);
+
+        for (k, max_dist2) in [(1, f64::INFINITY), (10, 4.0), (50, 1.0), (3000, 2.0)] {
+            for _ in 0..50 {
+                let p = random_point();
+                let mut found: Vec<f64> = map
+                    .nearest(p, k, max_dist2)
+                    .iter()
+                    .map(|n| {
+                        assert_eq!(n.dist2, (map.photons[n.index].p - p).length_squared());
+                        n.dist2
+                    })
+                    .collect();
+                found.sort_by(f64::total_cmp);
+
+                let mut expected: Vec<f64> = map
+                    .photons
+                    .iter()
+                    .map(|photon| (photon.p - p).length_squared())
+                    .filter(|&dist2| dist2 < max_dist2)
+                    .collect();
+                expected.sort_by(f64::total_cmp);
+                expected.truncate(k);
+                assert_eq!(found, expected);
+            }
+        }
+    }
+
+    #[test]
+    fn nearest_in_empty_map() {
+        let map = PhotonMap::new(Vec::new());
+        assert!(map.nearest(Vec3::new(0.0, 0.0, 0.0), 5, 1.0).is_empty());
+    }
+
+    // A diffuse floor under a mirror ceiling, lit only by a light facing the
+    // mirror, so all the light reaching the floor directly from it is a caustic.
+    // Returns a camera with the scene's settings and the scene.
+    fn mirrored_light() -> (Camera, Hittables) {
+        let floor: Arc<dyn Material> = Arc::new(Lambertian::new_color(Color::new(0.5, 0.5, 0.5)));
+        let mirror: Arc<dyn Material> = Arc::new(Metal::new(Color::new(0.9, 0.9, 0.9), 0.0));
+        let light =
+            Arc::new(DiffuseLight::new_color(Color::new(4.0, 4.0, 4.0)).with_one_sided(true));
+
+        let (x, z) = (Vec3::new(1.0, 0.0, 0.0), Vec3::new(0.0, 0.0, 1.0));
+        let mut world = Hittables::default();
+        world.add(Arc::new(Quad::new(
+            Vec3::new(-3.0, 0.0, -3.0),
+            x * 6.0,
+            z * 6.0,
+            floor,
+        )));
+        world.add(Arc::new(Quad::new(
+            Vec3::new(-3.0, 2.0, -3.0),
+            x * 6.0,
+            z * 6.0,
+            mirror,
+        )));
+        // Facing up, towards the mirror.
+        let light_quad = Arc::new(Quad::new(
+            Vec3::new(-1.0, 1.0, 1.0),
+            x * 2.0,
+            z * -2.0,
+            light,
+        ));
+        world.add(light_quad.clone());
+        let mut lights = Lights::default();
+        lights.add(Arc::new(AreaLight::new(light_quad)));
+
+        let cam = Camera::builder()
+            .with_aspect_ratio(1.0)
+            .with_image_width(1)
+            .with_max_depth(30)
+            .with_background(Color::default())
+            .with_lights(lights)
+            .build();
+        (cam, world)
+    }
+
+    // Returns the mean luminance and its standard error of a patch of the floor
+    // beside the light's shadow, seen from straight above with n rays.
+    fn floor_mean(
+        cam: &Camera,
+        integrator: &dyn Integrator,
+        world: &dyn Hit,
+        n: usize,
+    ) -> (f64, f64) {
+        integrator.preprocess(cam, world);
+        let (mut sum, mut sum_squared) = (0.0, 0.0);
+        for _ in 0..n {
+            let origin = Vec3::new(
+                random_range_f64(1.75, 2.5),
+                1.5,
+                random_range_f64(-0.5, 0.5),
+            );
+            let r = Ray::new(origin, Vec3::new(0.0, -1.0, 0.0));
+            let radiance = integrator.li(cam, &r, world).y();
+            sum += radiance;
+            sum_squared += radiance * radiance;
+        }
+        let mean = sum / n as f64;
+        let variance = sum_squared / n as f64 - mean * mean;
+        (mean, f64::sqrt(variance / n as f64))
+    }
+
+    #[test]
+    fn matches_the_path_tracer() {
+        let (cam, world) = mirrored_light();
+        let (expected, expected_error) = floor_mean(&cam, &PathTracer, &world, 100_000);
+        let mapper = PhotonMapper::new(300_000).with_gather(100, 0.2);
+        let (mean, error) = floor_mean(&cam, &mapper, &world, 10_000);
+        // The photon map's own noise and blur aren't counted, so allow a little
+        // more.
+        let sigma = f64::sqrt(expected_error * expected_error + error * error);
+        assert!(
+            (mean - expected).abs() < 6.0 * sigma,
+            "{mean} {expected} {sigma}"
+        );
+        // Tight enough to notice the caustics' scale being off by even a tenth.
+        assert!(6.0 * sigma < 0.1 * expected, "{mean} {expected} {sigma}");
+    }
+}
@@ -2,9 +2,10 @@ use std::sync::Arc;
 
 use crate::{
     aabb::AABB,
-    hit::{Hit, HitRecord, Hittables},
+    hit::{Hit, Hittables, next_object_id},
     interval::Interval,
     material::Material,
+    utils::random_2d,
     vec3::{Vec3, cross, dot, unit_vector},
 };
 
@@ -17,6 +18,7 @@ pub struct Quad {
     normal: Vec3,
     d: f64,
     w: Vec3,
+    id: u32,
 }
 
 impl Quad {
@@ -34,11 +36,19 @@ impl Quad {
             w,
             mat,
             bbox: AABB::default(),
+            id: next_object_id(),
         };
         quad.set_bounding_box();
         quad
     }
 
+    // Reports the given object id in hits instead of the quad's own, for quads
+    // that are part of a larger object.
+    pub fn with_id(mut self, id: u32) -> Self {
+        self.id = id;
+        self
+    }
+
     fn set_bounding_box(&mut self) {
         let diag1 = AABB::from((self.q, self.q + self.u + self.v));
         let diag2 = AABB::from((self.q + self.u, self.q + self.v));
@@ -68,27 +78,43 @@ impl Hit for Quad {
         let alpha = dot(self.w, cross(planar_hitpt_vector, self.v));
         let beta = dot(self.w, cross(self.u, planar_hitpt_vector));
 
-        if !is_interior(alpha, beta, rec) {
+        if !is_interior(alpha, beta) || self.mat.cut_out(alpha, beta, intersection) {
             return false;
         }
 
         // we have a hit, fill in hitrec
         rec.t = t;
+        rec.u = alpha;
+        rec.v = beta;
         rec.p = intersection;
         rec.mat = self.mat.clone();
         rec.set_face_normal(r, self.normal);
+        rec.dpdu = self.u;
+        rec.dpdv = self.v;
+        rec.object_id = self.id;
         true
     }
 
     fn bounding_box(&self) -> AABB {
         self.bbox
     }
+
+    fn area(&self) -> f64 {
+        cross(self.u, self.v).length()
+    }
+
+    fn sample_surface(&self, _time: f64) -> Option<(Vec3, Vec3)> {
+        let (r1, r2) = random_2d();
+        let p = self.q + self.u * r1 + self.v * r2;
+        Some((p, self.normal))
+    }
 }
 
 // Makes an instance of a box with the given corners and material, represented as
 // a hittable list of quads.
 pub fn make_box(a: Vec3, b: Vec3, mat: Arc<dyn Material>) -> Arc<Hittables> {
     let mut sides = Hittables::default();
+    let id = next_object_id();
     let mn = Vec3::new(a.x().min(b.x()), a.y().min(b.y()), a.z().min(b.z()));
     let mx = Vec3::new(a.x().max(b.x()), a.y().max(b.y()), a.z().max(b.z()));
 
@@ -96,51 +122,28 @@ pub fn make_box(a: Vec3, b: Vec3, mat: Arc<dyn Material>) -> Arc<Hittables> {
     let dy = Vec3::new(0.0, mx.y() - mn.y(), 0.0);
     let dz = Vec3::new(0.0, 0.0, mx.z() - mn.z());
 
-    sides.add(Arc::new(Quad::new(
-        Vec3::new(mn.x(), mn.y(), mx.z()),
-        dx,
-        dy,
-        mat.clone(),
-    )));
-    sides.add(Arc::new(Quad::new(
-        Vec3::new(mx.x(), mn.y(), mx.z()),
-        -dz,
-        dy,
-        mat.clone(),
-    )));
-    sides.add(Arc::new(Quad::new(
-        Vec3::new(mx.x(), mn.y(), mn.z()),
-        -dx,
-        dy,
-        mat.clone(),
-    )));
-    sides.add(Arc::new(Quad::new(
-        Vec3::new(mn.x(), mn.y(), mn.z()),
-        dz,
-        dy,
-        mat.clone(),
-    )));
-    sides.add(Arc::new(Quad::new(
-        Vec3::new(mn.x(), mx.y(), mx.z()),
-        dx,
-        -dz,
-        mat.clone(),
-    )));
-    sides.add(Arc::new(Quad::new(
-        Vec3::new(mn.x(), mn.y(), mn.z()),
-        dx,
-        dz,
-        mat.clone(),
-    )));
+    sides.add(Arc::new(
+        Quad::new(Vec3::new(mn.x(), mn.y(), mx.z()), dx, dy, mat.clone()).with_id(id),
+    ));
+    sides.add(Arc::new(
+        Quad::new(Vec3::new(mx.x(), mn.y(), mx.z()), -dz, dy, mat.clone()).with_id(id),
+    ));
+    sides.add(Arc::new(
+        Quad::new(Vec3::new(mx.x(), mn.y(), mn.z()), -dx, dy, mat.clone()).with_id(id),
+    ));
+    sides.add(Arc::new(
+        Quad::new(Vec3::new(mn.x(), mn.y(), mn.z()), dz, dy, mat.clone()).with_id(id),
+    ));
+    sides.add(Arc::new(
+        Quad::new(Vec3::new(mn.x(), mx.y(), mx.z()), dx, -dz, mat.clone()).with_id(id),
+    ));
+    sides.add(Arc::new(
+        Quad::new(Vec3::new(mn.x(), mn.y(), mn.z()), dx, dz, mat.clone()).with_id(id),
+    ));
     Arc::new(sides)
 }
 
-fn is_interior(a: f64, b: f64, rec: &mut HitRecord) -> bool {
+fn is_interior(a: f64, b: f64) -> bool {
     let unit_interval = Interval::new(0.0, 1.0);
-    if !unit_interval.contains(a) || !unit_interval.contains(b) {
-        return false;
-    }
-    rec.u = a;
-    rec.v = b;
-    true
+    unit_interval.contains(a) && unit_interval.contains(b)
 }
@@ -0,0 +1,405 @@
+use std::cell::RefCell;
+use std::sync::Arc;
+
+// Largest f64 below one, to keep sample values in [0,1).
+const ONE_MINUS_EPSILON: f64 = 1.0 - f64::EPSILON / 2.0;
+
+// A source of sample values for rendering. Each sample of a pixel is a point in
+// a high dimensional unit cube, with a dimension for every random decision made
+// along its path: where in the pixel the ray starts, where on the lens, at what
+// time, how the first surface scatters it, and so on. Samplers spread a pixel's
+// samples out over the cube more evenly than independent random numbers do,
+// which reduces noise.
+//
+// Samplers are stateless: values only depend on the arguments, so pixels can be
+// rendered in any order and on any thread.
+pub trait Sampler: Send + Sync {
+    // Returns dimensions 2 * pair and 2 * pair + 1 of sample `index` of a pixel,
+    // where the pixel takes `count` samples and `seed` is different for every
+    // pixel. Returns None for dimensions past the ones the sampler supports.
+    fn get_2d(&self, seed: u64, index: u32, count: u32, pair: u32) -> Option<(f64, f64)>;
+
+    // Describes the sampler, such as `halton 64`, as Integrator::describe does
+    // for integrators.
+    fn describe(&self) -> String;
+}
+
+// Independent uniform random values, as if no sampler was used, but repeatable.
+pub struct IndependentSampler;
+
+impl Sampler for IndependentSampler {
+    fn get_2d(&self, seed: u64, index: u32, _count: u32, pair: u32) -> Option<(f64, f64)> {
+        let h = hash(&[seed, index as u64, pair as u64]);
+        Some((to_unit(h), to_unit(mix_bits(h))))
+    }
+
+    fn describe(&self) -> String {
+        "independent".to_string()
+    }
+}
+
+// Jittered stratification: each pair of dimensions is divided into a grid with
+// about one cell per sample, and each sample takes a random point in a different
+// cell. Cells are assigned to samples in a different random order for each pair.
+// Samples past the pixel's count are independent random values.
+pub struct StratifiedSampler;
+
+impl Sampler for StratifiedSampler {
+    fn get_2d(&self, seed: u64, index: u32, count: u32, pair: u32) -> Option<(f64, f64)> {
+        if index >= count {
+            return IndependentSampler.get_2d(seed, index, count, pair);
+        }
+        let x_cells = ((count as f64).sqrt() as u32).max(1);
+        let y_cells = count.div_ceil(x_cells);
+        let h = hash(&[seed, pair as u64]);
+        let cell = permutation_element(index, x_cells * y_cells, h as u32);
+
+        let jitter = hash(&[h, index as u64]);
+        Some((
+            ((cell % x_cells) as f64 + to_unit(jitter)) / x_cells as f64,
+            ((cell / x_cells) as f64 + to_unit(mix_bits(jitter))) / y_cells as f64,
+        ))
+    }
+
+    fn describe(&self) -> String {
+        "stratified".to_string()
+    }
+}
+
+// The Halton sequence, which gives each dimension the radical inverse of the
+// sample index in a different prime base. The digits are randomly permuted
+// (Owen scrambling) differently for each pixel, which keeps pixels from sharing
+// the same pattern and spreads out the first few values of high bases. Only the
+// first `dimensions` dimensions are supported, as each needs another prime.
+pub struct HaltonSampler {
+    primes: Vec<u32>,
+}
+
+impl HaltonSampler {
+    pub fn new(dimensions: usize) -> Self {
+        let mut primes: Vec<u32> = Vec::with_capacity(dimensions);
+        let mut n = 2;
+        while primes.len() < dimensions {
+            if primes
+                .iter()
+                .take_while(|&&p| p * p <= n)
+                .all(|&p| n % p != 0)
+            {
+                primes.push(n);
+            }
+            n += 1;
+        }
+        Self { primes }
+    }
+
+    fn get_1d(&self, seed: u64, index: u32, dimension: usize) -> Option<f64> {
+        let base = *self.primes.get(dimension)?;
+        let value = scrambled_radical_inverse(base, index, hash(&[seed, dimension as u64]));
+        Some(value.min(ONE_MINUS_EPSILON))
+    }
+}
+
+impl Default for HaltonSampler {
+    fn default() -> Self {
+        Self::new(64)
+    }
+}
+
+impl Sampler for HaltonSampler {
+    fn get_2d(&self, seed: u64, index: u32, _count: u32, pair: u32) -> Option<(f64, f64)> {
+        let dimension = 2 * pair as usize;
+        Some((
+            self.get_1d(seed, index, dimension)?,
+            self.get_1d(seed, index, dimension + 1)?,
+        ))
+    }
+
+    fn describe(&self) -> String {
+        format!("halton {}", self.primes.len())
+    }
+}
+
+// The first two dimensions of the Sobol sequence, reused for every pair of
+// dimensions with a different random shuffle of the sample order ("padding"),
+// and randomized by hash based Owen scrambling (Burley, "Practical Hash-based
+// Owen Scrambling"). Best with power of two sample counts.
+pub struct SobolSampler;
+
+impl Sampler for SobolSampler {
+    fn get_2d(&self, seed: u64, index: u32, _count: u32, pair: u32) -> Option<(f64, f64)> {
+        let h = hash(&[seed, pair as u64]);
+        let index = nested_uniform_scramble(index, h as u32);
+        let x = nested_uniform_scramble(index.reverse_bits(), (h >> 32) as u32);
+        let y = nested_uniform_scramble(sobol_second_dimension(index), mix_bits(h) as u32);
+        Some((
+            (x as f64 / 4294967296.0).min(ONE_MINUS_EPSILON),
+            (y as f64 / 4294967296.0).min(ONE_MINUS_EPSILON),
+        ))
+    }
+
+    fn describe(&self) -> String {
+        "sobol".to_string()
+    }
+}
+
+// The sample whose random numbers are currently being drawn on this thread.
+struct SampleStream {
+    sampler: Arc<dyn Sampler>,
+    seed: u64,
+    index: u32,
+    count: u32,
+    // Next pair of dimensions to use, and the second value of the last pair if
+    // only the first was used.
+    pair: u32,
+    pending: Option<f64>,
+}
+
+thread_local! {
+    static STREAM: RefCell<Option<SampleStream>> = const { RefCell::new(None) };
+}
+
+// Ends the current sample when dropped.
+pub struct SampleGuard;
+
+impl Drop for SampleGuard {
+    fn drop(&mut self) {
+        STREAM.with_borrow_mut(|stream| *stream = None);
+    }
+}
+
+// Makes the random numbers drawn on this thread (through utils::random_f64 and
+// utils::random_2d) come from the sampler, as sample `index` of the pixel with
+// the given seed, until the returned guard is dropped.
+pub fn begin_sample(sampler: Arc<dyn Sampler>, seed: u64, index: u32, count: u32) -> SampleGuard {
+    STREAM.with_borrow_mut(|stream| {
+        *stream = Some(SampleStream {
+            sampler,
+            seed,
+            index,
+            count,
+            pair: 0,
+            pending: None,
+        })
+    });
+    SampleGuard
+}
+
+// Returns the next dimension of the current sample, or None if there is no
+// current sample or the sampler has run out of dimensions.
+pub fn next_1d() -> Option<f64> {
+    STREAM.with_borrow_mut(|stream| {
+        let stream = stream.as_mut()?;
+        if let Some(value) = stream.pending.take() {
+            return Some(value);
+        }
+        let (x, y) = stream.next_pair()?;
+        stream.pending = Some(y);
+        Some(x)
+    })
+}
+
+// Returns the next pair of dimensions of the current sample, which are
+// distributed well together.
+pub fn next_2d() -> Option<(f64, f64)> {
+    STREAM.with_borrow_mut(|stream| {
+        let stream = stream.as_mut()?;
+        stream.pending = None;
+        stream.next_pair()
+    })
+}
+
+impl SampleStream {
+    fn next_pair(&mut self) -> Option<(f64, f64)> {
+        let pair = self.pair;
+        self.pair += 1;
+        self.sampler.get_2d(self.seed, self.index, self.count, pair)
+    }
+}
+
+// Mixes the bits of a 64-bit value (the MurmurHash3 finalizer).
+pub fn mix_bits(mut v: u64) -> u64 {
+    v ^= v >> 33;
+    v = v.wrapping_mul(0xff51afd7ed558ccd);
+    v ^= v >> 33;
+    v = v.wrapping_mul(0xc4ceb9fe1a85ec53);
+    v ^= v >> 33;
+    v
+}
+
+// Hashes a list of values into 64 bits.
+pub fn hash(values: &[u64]) -> u64 {
+    values.iter().fold(0x9e3779b97f4a7c15, |h, &v| {
+        mix_bits(h ^ v.wrapping_add(0x9e3779b97f4a7c15).wrapping_add(h << 6))
+    })
+}
+
+// Converts the high bits of a hash to a value in [0,1).
+fn to_unit(h: u64) -> f64 {
+    (h >> 11) as f64 / (1u64 << 53) as f64
+}
+
+// Returns the radical inverse of a in the given base, its digits mirrored about
+// the point, with each digit permuted by a random permutation chosen by `seed`
+// and the digits before it.
+fn scrambled_radical_inverse(base: u32, mut a: u32, seed: u64) -> f64 {
+    let inv_base = 1.0 / base as f64;
+    let mut result = 0.0;
+    let mut inv_base_n = inv_base;
+    let mut prefix = 0u64;
+    // Leading zero digits are permuted too, down to about 32 bits of precision.
+    while inv_base_n > 1.0 / 4294967296.0 {
+        let next = a / base;
+        let digit = a - next * base;
+        let digit = permutation_element(digit, base, mix_bits(seed ^ prefix) as u32);
+        result += digit as f64 * inv_base_n;
+        prefix = prefix
+            .wrapping_mul(base as u64)
+            .wrapping_add(digit as u64 + 1);
+        inv_base_n *= inv_base;
+        a = next;
+    }
+    result
+}
+
+// Returns element i of a random permutation of [0, n) chosen by `seed`
+// (Kensler, "Correlated Multi-Jittered Sampling").
+fn permutation_element(mut i: u32, n: u32, seed: u32) -> u32 {
+    let mut w = n - 1;
+    w |= w >> 1;
+    w |= w >> 2;
+    w |= w >> 4;
+    w |= w >> 8;
+    w |= w >> 16;
+    // Permute within the next power of two, repeating until the result lands
+    // in range.
+    loop {
+        i ^= seed;
+        i = i.wrapping_mul(0xe170893d);
+        i ^= seed >> 16;
+        i ^= (i & w) >> 4;
+        i ^= seed >> 8;
+        i = i.wrapping_mul(0x0929eb3f);
+        i ^= seed >> 23;
+        i ^= (i & w) >> 1;
+        i = i.wrapping_mul(1 | seed >> 27);
+        i = i.wrapping_mul(0x6935fa69);
+        i ^= (i & w) >> 11;
+        i = i.wrapping_mul(0x74dcb303);
+        i ^= (i & w) >> 2;
+        i = i.wrapping_mul(0x9e501cc3);
+        i ^= (i & w) >> 2;
+        i = i.wrapping_mul(0xc860a3df);
+        i &= w;
+        i ^= i >> 5;
+        if i < n {
+            return ((i as u64 + seed as u64) % n as u64) as u32;
+        }
+    }
+}
+
+// Returns the second dimension of the Sobol sequence, whose generator matrix is
+// Pascal's triangle mod 2. The first is the bit reversed index.
+fn sobol_second_dimension(mut index: u32) -> u32 {
+    let mut v = 1 << 31;
+    let mut result = 0;
+    while index != 0 {
+        if index & 1 != 0 {
+            result ^= v;
+        }
+        index >>= 1;
+        v ^= v >> 1;
+    }
+    result
+}
+
+// Randomly permutes the bits of x so that each bit only depends on the bits
+// above it, an Owen scramble of x as a binary fraction.
+fn nested_uniform_scramble(x: u32, seed: u32) -> u32 {
+    let mut x = x.reverse_bits();
+    // Each step only lets bits affect higher bits (Vegdahl's variant of the
+    // Laine-Karras permutation).
+    x ^= x.wrapping_mul(0x3d20adea);
+    x = x.wrapping_add(seed);
+    x = x.wrapping_mul((seed >> 16) | 1);
+    x ^= x.wrapping_mul(0x05526c56);
+    x ^= x.wrapping_mul(0x53a22864);
+    x.reverse_bits()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn samplers() -> Vec<(&'static str, Box<dyn Sampler>)> {
+        vec![
+            ("independent", Box::new(IndependentSampler)),
+            ("stratified", Box::new(StratifiedSampler)),
+            ("halton", Box::new(HaltonSampler::default())),
+            ("sobol", Box::new(SobolSampler)),
+        ]
+    }
+
+    #[test]
+    fn values_are_in_unit_interval() {
+        for (name, sampler) in samplers() {
+            for seed in [0, 1, u64::MAX] {
+                for index in (0..64).chain([u32::MAX - 1, u32::MAX]) {
+                    for pair in 0..32 {
+                        let (x, y) = sampler.get_2d(seed, index, 64, pair).unwrap();
+                        assert!(
+                            (0.0..1.0).contains(&x) && (0.0..1.0).contains(&y),
+                            "{name} gave ({x}, {y}) for sample {index} pair {pair}"
+                        );
+                    }
+                }
+            }
+        }
+    }
+
+    // With 16 samples, each cell of a 4x4 grid gets exactly one.
+    #[test]
+    fn stratified_samples_fill_every_cell() {
+        for pair in 0..8 {
+            let mut cells = [0; 16];
+            for index in 0..16 {
+                let (x, y) = StratifiedSampler.get_2d(7, index, 16, pair).unwrap();
+                cells[(y * 4.0) as usize * 4 + (x * 4.0) as usize] += 1;
+            }
+            assert_eq!(cells, [1; 16], "pair {pair}");
+        }
+    }
+
+    // With 16 samples, each sixteenth of either dimension gets exactly one.
+    #[test]
+    fn sobol_samples_are_stratified() {
+        for pair in 0..8 {
+            let (mut xs, mut ys) = ([0; 16], [0; 16]);
+            for index in 0..16 {
+                let (x, y) = SobolSampler.get_2d(7, index, 16, pair).unwrap();
+                xs[(x * 16.0) as usize] += 1;
+                ys[(y * 16.0) as usize] += 1;
+            }
+            assert_eq!(xs, [1; 16], "pair {pair}");
+            assert_eq!(ys, [1; 16], "pair {pair}");
+        }
+    }
+
+    #[test]
+    fn halton_runs_out_of_dimensions() {
+        let sampler = HaltonSampler::new(4);
+        assert_eq!(sampler.primes, [2, 3, 5, 7]);
+        assert!(sampler.get_2d(0, 0, 1, 1).is_some());
+        assert!(sampler.get_2d(0, 0, 1, 2).is_none());
+    }
+
+    #[test]
+    fn permutation_elements_are_a_permutation() {
+        for n in [1, 2, 3, 10, 64, 100] {
+            let mut seen = vec![false; n as usize];
+            for i in 0..n {
+                seen[permutation_element(i, n, 0x1234_5678) as usize] = true;
+            }
+            assert!(seen.iter().all(|&s| s), "not a permutation of {n}");
+        }
+    }
+}
@@ -0,0 +1,332 @@
+use std::f64::consts::PI;
+use std::sync::Arc;
+
+use crate::{
+    color::{Color, blackbody},
+    environment::{Environment, EnvironmentLight},
+    image::Image,
+    light::{DirectionalLight, Light, LightSample},
+    vec3::{Vec3, dot, unit_vector},
+};
+
+// Angular diameter of the sun as seen from earth, in degrees.
+const SUN_ANGULAR_DIAMETER: f64 = 0.53;
+// Illuminance of the sun above the atmosphere, in kilolux.
+const SUN_ILLUMINANCE: f64 = 128.0;
+// Converts the model's luminances (in kcd/m^2) to scene radiance.
+const LUMINANCE_SCALE: f64 = 0.025;
+
+// Coefficients of the Perez sky luminance distribution.
+struct Perez {
+    a: f64,
+    b: f64,
+    c: f64,
+    d: f64,
+    e: f64,
+}
+
+impl Perez {
+    // Evaluates the distribution for a view direction at zenith angle theta and
+    // angle gamma away from the sun.
+    fn eval(&self, theta: f64, gamma: f64) -> f64 {
+        let cos_theta = f64::max(f64::cos(theta), 0.01);
+        let cos_gamma = f64::cos(gamma);
+        (1.0 + self.a * f64::exp(self.b / cos_theta))
+            * (1.0 + self.c * f64::exp(self.d * gamma) + self.e * cos_gamma * cos_gamma)
+    }
+}
+
+// An analytic daylight sky following Preetham, Shirley and Smits, "A Practical
+// Analytic Model for Daylight". Below the horizon is a diffuse ground lit by the
+// sky and sun.
+pub struct PhysicalSky {
+    // Unit direction pointing towards the sun.
+    sun_direction: Vec3,
+    // Zenith angle of the sun, clamped to the horizon.
+    theta_sun: f64,
+    perez_y: Perez,
+    perez_x: Perez,
+    perez_yy: Perez,
+    // Zenith luminance and chromaticity.
+    zenith_y: f64,
+    zenith_x: f64,
+    zenith_yy: f64,
+    ground: Color,
+    sun_irradiance: Color,
+    intensity: f64,
+    // Tabulated copy of the sky, used to importance sample it.
+    table: EnvironmentLight,
+}
+
+impl PhysicalSky {
+    // Creates a sky lit by a sun in the given direction. Turbidity describes the
+    // haziness of the atmosphere, from about 2 (very clear) to 10 (hazy).
+    pub fn new(sun_direction: Vec3, turbidity: f64, ground_albedo: Color) -> Self {
+        let sun_direction = unit_vector(sun_direction);
+        let t = turbidity.clamp(1.7, 10.0);
+        let theta_sun = f64::acos(sun_direction.y().clamp(0.0, 1.0));
+
+        let chi = (4.0 / 9.0 - t / 120.0) * (PI - 2.0 * theta_sun);
+        let zenith_y = (4.0453 * t - 4.9710) * f64::tan(chi) - 0.2155 * t + 2.4192;
+        let cubic = |c: [f64; 4]| {
+            c[0] * theta_sun.powi(3) + c[1] * theta_sun.powi(2) + c[2] * theta_sun + c[3]
+        };
+        let zenith_x = t * t * cubic([0.00166, -0.00375, 0.00209, 0.0])
+            + t * cubic([-0.02903, 0.06377, -0.03202, 0.00394])
+            + cubic([0.11693, -0.21196, 0.06052, 0.25886]);
+        let zenith_yy = t * t * cubic([0.00275, -0.00610, 0.00317, 0.0])
+            + t * cubic([-0.04214, 0.08970, -0.04153, 0.00516])
+            + cubic([0.15346, -0.26756, 0.06670, 0.26688]);
+
+        let mut sky = Self {
+            sun_direction,
+            theta_sun,
+            perez_y: Perez {
+                a: 0.1787 * t - 1.4630,
+                b: -0.3554 * t + 0.4275,
+                c: -0.0227 * t + 5.3251,
+                d: 0.1206 * t - 2.5771,
+                e: -0.0670 * t + 0.3703,
+            },
+            perez_x: Perez {
+                a: -0.0193 * t - 0.2592,
+                b: -0.0665 * t + 0.0008,
+                c: -0.0004 * t + 0.2125,
+                d: -0.0641 * t - 0.8989,
+                e: -0.0033 * t + 0.0452,
+            },
+            perez_yy: Perez {
+                a: -0.0167 * t - 0.2608,
+                b: -0.0950 * t + 0.0092,
+                c: -0.0079 * t + 0.2102,
+                d: -0.0441 * t - 1.6537,
+                e: -0.0109 * t + 0.0529,
+            },
+            zenith_y: f64::max(zenith_y, 0.0),
+            zenith_x,
+            zenith_yy,
+            ground: Color::default(),
+            sun_irradiance: if sun_direction.y() > 0.0 {
+                Self::sun_color(theta_sun, t) * (SUN_ILLUMINANCE * LUMINANCE_SCALE)
+            } else {
+                Color::default()
+            },
+            intensity: 1.0,
+            table: EnvironmentLight::new(Arc::new(Image::new(1, 1, vec![Color::default()]))),
+        };
+
+        // Light the ground with the sky and sun, assuming it is a diffuse plane.
+        let sky_irradiance = sky.sky_irradiance();
+        let sun_irradiance = sky.sun_irradiance * f64::cos(theta_sun);
+        sky.ground = ground_albedo * (sky_irradiance + sun_irradiance) / PI;
+        sky.table = sky.tabulate(128, 64);
+        sky
+    }
+
+    pub fn with_intensity(mut self, intensity: f64) -> Self {
+        self.intensity = intensity;
+        self
+    }
+
+    // Returns a light for the sun matching this sky. It should be added to the
+    // camera's lights, since the sky itself leaves the sun out.
+    pub fn sun(&self) -> DirectionalLight {
+        DirectionalLight::new(
+            -self.sun_direction,
+            self.sun_irradiance * self.intensity,
+            SUN_ANGULAR_DIAMETER,
+        )
+    }
+
+    // Returns the relative color of sunlight after passing through the
+    // atmosphere, from Rayleigh and aerosol (Angstrom) extinction at the red,
+    // green and blue wavelengths.
+    fn sun_color(theta_sun: f64, turbidity: f64) -> Color {
+        let degrees = f64::to_degrees(theta_sun);
+        let air_mass = 1.0 / (f64::cos(theta_sun) + 0.15 * f64::powf(93.885 - degrees, -1.253));
+        let beta = 0.04608 * turbidity - 0.04586;
+        let transmittance = |micrometers: f64| {
+            let rayleigh = 0.008735 * f64::powf(micrometers, -4.08);
+            let aerosol = beta * f64::powf(micrometers, -1.3);
+            f64::exp(-air_mass * (rayleigh + aerosol))
+        };
+        blackbody(5778.0)
+            * Color::new(
+                transmittance(0.68),
+                transmittance(0.55),
+                transmittance(0.44),
+            )
+    }
+
+    // Evaluates the sky (without the sun disk or ground) in the given unit
+    // direction.
+    fn sky_radiance(&self, direction: Vec3) -> Color {
+        let theta = f64::acos(direction.y().clamp(0.0, 1.0));
+        let gamma = f64::acos(dot(direction, self.sun_direction).clamp(-1.0, 1.0));
+
+        let relative = |perez: &Perez, zenith: f64| {
+            zenith * perez.eval(theta, gamma) / perez.eval(0.0, self.theta_sun)
+        };
+        let y = relative(&self.perez_y, self.zenith_y) * LUMINANCE_SCALE;
+        let x = relative(&self.perez_x, self.zenith_x);
+        let yy = relative(&self.perez_yy, self.zenith_yy);
+        if yy <= 0.0 {
+            return Color::default();
+        }
+
+        // xyY to XYZ to linear sRGB
+        let big_x = x / yy * y;
+        let big_z = (1.0 - x - yy) / yy * y;
+        Color::new(
+            f64::max(0.0, 3.2406 * big_x - 1.5372 * y - 0.4986 * big_z),
+            f64::max(0.0, -0.9689 * big_x + 1.8758 * y + 0.0415 * big_z),
+            f64::max(0.0, 0.0557 * big_x - 0.2040 * y + 1.0570 * big_z),
+        )
+    }
+
+    // Integrates the sky over the upper hemisphere to find the irradiance it
+    // delivers to a horizontal surface.
+    fn sky_irradiance(&self) -> Color {
+        const THETA_STEPS: usize = 32;
+        const PHI_STEPS: usize = 64;
+        let d_theta = PI / 2.0 / THETA_STEPS as f64;
+        let d_phi = 2.0 * PI / PHI_STEPS as f64;
+        let mut irradiance = Color::default();
+        for i in 0..THETA_STEPS {
+            let theta = (i as f64 + 0.5) * d_theta;
+            for j in 0..PHI_STEPS {
+                let phi = (j as f64 + 0.5) * d_phi;
+                let direction = Vec3::new(
+                    f64::sin(theta) * f64::cos(phi),
+                    f64::cos(theta),
+                    f64::sin(theta) * f64::sin(phi),
+                );
+                irradiance += self.sky_radiance(direction)
+                    * (f64::cos(theta) * f64::sin(theta) * d_theta * d_phi);
+            }
+        }
+        irradiance
+    }
+
+    // Bakes the sky into an equirectangular table for importance sampling.
+    fn tabulate(&self, width: usize, height: usize) -> EnvironmentLight {
+        let mut data = Vec::with_capacity(width * height);
+        for y in 0..height {
+            let theta = PI * (y as f64 + 0.5) / height as f64;
+            for x in 0..width {
+                let phi = 2.0 * PI * (x as f64 + 0.5) / width as f64 - PI;
+                let direction = Vec3::new(
+                    f64::sin(theta) * f64::cos(phi),
+                    f64::cos(theta),
+                    f64::sin(theta) * f64::sin(phi),
+                );
+                data.push(self.radiance(direction));
+            }
+        }
+        EnvironmentLight::new(Arc::new(Image::new(width, height, data)))
+    }
+}
+
+impl Environment for PhysicalSky {
+    fn radiance(&self, direction: Vec3) -> Color {
+        let direction = unit_vector(direction);
+        let radiance = if direction.y() < 0.0 {
+            self.ground
+        } else {
+            self.sky_radiance(direction)
+        };
+        radiance * self.intensity
+    }
+
+    fn pdf(&self, direction: Vec3) -> f64 {
+        self.table.pdf(direction)
+    }
+
+    fn unsampled_radiance(&self, direction: Vec3) -> Color {
+        let sun = self.sun();
+        if sun.contains(unit_vector(direction)) {
+            sun.radiance()
+        } else {
+            Color::default()
+        }
+    }
+}
+
+impl Light for PhysicalSky {
+    fn sample(&self, _p: Vec3) -> Option<LightSample> {
+        let (direction, pdf) = self.table.sample_direction()?;
+        Some(LightSample {
+            direction,
+            distance: f64::INFINITY,
+            radiance: self.radiance(direction) / pdf,
+            pdf,
+            normal: None,
+        })
+    }
+
+    fn is_infinite(&self) -> bool {
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::color::luminance;
+
+    // Returns the direction at the given elevation above the horizon and
+    // azimuth, in degrees.
+    fn direction(elevation: f64, azimuth: f64) -> Vec3 {
+        let (elevation, azimuth) = (elevation.to_radians(), azimuth.to_radians());
+        Vec3::new(
+            elevation.cos() * azimuth.cos(),
+            elevation.sin(),
+            elevation.cos() * azimuth.sin(),
+        )
+    }
+
+    #[test]
+    fn radiance_is_finite_and_non_negative() {
+        let ground = Color::new(0.3, 0.3, 0.3);
+        for turbidity in [1.0, 2.0, 4.0, 7.0, 10.0, 12.0] {
+            for sun_elevation in [-10.0, 0.0, 1.0, 15.0, 45.0, 89.0, 90.0] {
+                let sky = PhysicalSky::new(direction(sun_elevation, 40.0), turbidity, ground);
+                for elevation in (-90..=90).step_by(5) {
+                    for azimuth in (0..360).step_by(15) {
+                        let d = direction(elevation as f64, azimuth as f64);
+                        let radiance = sky.radiance(d) + sky.unsampled_radiance(d);
+                        for c in 0..3 {
+                            assert!(
+                                radiance[c].is_finite() && radiance[c] >= 0.0,
+                                "{turbidity} {sun_elevation} {elevation} {azimuth}: {radiance:?}"
+                            );
+                        }
+                    }
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn zenith_luminance_is_plausible() {
+        // Preetham et al. give zenith luminances of a few thousand cd/m^2 for
+        // clear skies, rising as the sun climbs and the air gets hazier.
+        let zenith = |turbidity: f64, sun_elevation: f64| {
+            let sky = PhysicalSky::new(direction(sun_elevation, 0.0), turbidity, Color::default());
+            luminance(sky.radiance(Vec3::new(0.0, 1.0, 0.0))) / LUMINANCE_SCALE
+        };
+        let clear = zenith(3.0, 45.0);
+        assert!((clear - 7.3).abs() < 0.1, "{clear}");
+        assert!(zenith(3.0, 20.0) < clear && clear < zenith(3.0, 70.0));
+        assert!(clear < zenith(6.0, 45.0));
+        for turbidity in [2.0, 3.0, 5.0] {
+            for sun_elevation in [20.0, 45.0, 70.0] {
+                let luminance = zenith(turbidity, sun_elevation);
+                assert!(
+                    (1.0..25.0).contains(&luminance),
+                    "{turbidity} {sun_elevation}"
+                );
+            }
+        }
+    }
+}
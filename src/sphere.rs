@@ -2,7 +2,7 @@ use std::f64::consts::PI;
 use std::sync::Arc;
 
 use crate::aabb::AABB;
-use crate::hit::Hit;
+use crate::hit::{Hit, next_object_id};
 use crate::interval::Interval;
 use crate::material::Material;
 use crate::ray::Ray;
@@ -13,6 +13,7 @@ pub struct Sphere {
     radius: f64,
     mat: Arc<dyn Material>,
     bbox: AABB,
+    id: u32,
 }
 
 impl Sphere {
@@ -23,6 +24,7 @@ impl Sphere {
             radius: f64::max(0.0, radius),
             mat,
             bbox: AABB::from((center - rvec, center + rvec)),
+            id: next_object_id(),
         }
     }
 
@@ -36,6 +38,7 @@ impl Sphere {
             radius: f64::max(0.0, radius),
             mat,
             bbox: AABB::from((box1, box2)),
+            id: next_object_id(),
         }
     }
 
@@ -44,6 +47,20 @@ impl Sphere {
         let phi = (-p.z()).atan2(p.x()) + PI;
         (phi / (2.0 * PI), theta / PI)
     }
+
+    // Returns the partial derivatives (dp/du, dp/dv) of the sphere surface at the
+    // point with outward unit normal p, for the parametrization in get_sphere_uv.
+    pub fn get_sphere_dpduv(p: Vec3, radius: f64) -> (Vec3, Vec3) {
+        let dpdu = Vec3::new(p.z(), 0.0, -p.x()) * (2.0 * PI * radius);
+        // sin(theta) vanishes at the poles, where dp/dv is undefined.
+        let sin_theta = f64::max(f64::sqrt(p.x() * p.x() + p.z() * p.z()), 1e-8);
+        let dpdv = Vec3::new(
+            -p.x() * p.y() / sin_theta,
+            sin_theta,
+            -p.y() * p.z() / sin_theta,
+        ) * (PI * radius);
+        (dpdu, dpdv)
+    }
 }
 
 impl Hit for Sphere {
@@ -60,25 +77,40 @@ impl Hit for Sphere {
         }
 
         let sqrtd = f64::sqrt(discriminant);
-        // find nearest root in (tmin, tmax)
-        let mut root = (h - sqrtd) / a;
-        if !ray_t.surrounds(root) {
-            root = (h + sqrtd) / a;
+        // find nearest root in (tmin, tmax) that isn't cut out by the material
+        for root in [(h - sqrtd) / a, (h + sqrtd) / a] {
             if !ray_t.surrounds(root) {
-                return false;
+                continue;
+            }
+            let p = r.at(root);
+            let outward_normal = (p - current_center) / self.radius;
+            let (u, v) = Self::get_sphere_uv(outward_normal);
+            if self.mat.cut_out(u, v, p) {
+                continue;
             }
-        }
 
-        rec.t = root;
-        rec.p = r.at(rec.t);
-        rec.mat = self.mat.clone();
-        let outward_normal = (rec.p - current_center) / self.radius;
-        rec.set_face_normal(r, outward_normal);
-        (rec.u, rec.v) = Self::get_sphere_uv(outward_normal);
-        return true;
+            rec.t = root;
+            rec.p = p;
+            rec.mat = self.mat.clone();
+            rec.set_face_normal(r, outward_normal);
+            (rec.u, rec.v) = (u, v);
+            (rec.dpdu, rec.dpdv) = Self::get_sphere_dpduv(outward_normal, self.radius);
+            rec.object_id = self.id;
+            return true;
+        }
+        false
     }
 
     fn bounding_box(&self) -> AABB {
         self.bbox
     }
+
+    fn area(&self) -> f64 {
+        4.0 * PI * self.radius * self.radius
+    }
+
+    fn sample_surface(&self, time: f64) -> Option<(Vec3, Vec3)> {
+        let normal = Vec3::random_unit_vector();
+        Some((self.center.at(time) + normal * self.radius, normal))
+    }
 }
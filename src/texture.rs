@@ -1,6 +1,6 @@
 use std::sync::Arc;
 
-use crate::{color::Color, perlin::Perlin, vec3::Vec3};
+use crate::{color::Color, image::Image, interval::Interval, perlin::Perlin, vec3::Vec3};
 
 pub trait Texture: Send + Sync {
     fn value(&self, u: f64, v: f64, p: Vec3) -> Color;
@@ -89,3 +89,47 @@ impl Texture for NoiseTexture {
             * (1.0 + f64::sin(self.scale * p.z() + 10.0 * self.noise.turb(p, 7)))
     }
 }
+
+pub struct ImageTexture {
+    image: Arc<Image>,
+    // Whether the image holds gamma-encoded colors that should be converted back
+    // to linear space on lookup. Data textures such as normal maps must not be.
+    gamma_encoded: bool,
+}
+
+impl ImageTexture {
+    // Creates a texture from an image holding gamma-encoded colors.
+    pub fn new(image: Arc<Image>) -> Self {
+        Self {
+            image,
+            gamma_encoded: true,
+        }
+    }
+
+    // Creates a texture returning the raw image values, for non-color data like
+    // normal maps.
+    pub fn new_raw(image: Arc<Image>) -> Self {
+        Self {
+            image,
+            gamma_encoded: false,
+        }
+    }
+}
+
+impl Texture for ImageTexture {
+    fn value(&self, u: f64, v: f64, _p: Vec3) -> Color {
+        let unit_interval = Interval::new(0.0, 1.0);
+        let u = unit_interval.clamp(u);
+        // Flip v to image coordinates
+        let v = 1.0 - unit_interval.clamp(v);
+
+        let i = (u * self.image.width() as f64) as usize;
+        let j = (v * self.image.height() as f64) as usize;
+        let pixel = self.image.pixel(i, j);
+        if self.gamma_encoded {
+            pixel * pixel
+        } else {
+            pixel
+        }
+    }
+}
@@ -0,0 +1,211 @@
+use std::str::FromStr;
+
+// The order tiles are rendered in.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum TileOrder {
+    // Row by row from the top left, like reading.
+    Scanline,
+    // Outwards from the middle of the image, so the subject usually appears
+    // first.
+    Spiral,
+    // Along a Hilbert curve, which keeps consecutive tiles next to each other
+    // so the scene data they need is more likely to still be cached.
+    Hilbert,
+}
+
+impl FromStr for TileOrder {
+    type Err = ();
+
+    // Parses the order's name in lower case, e.g. `hilbert`.
+    fn from_str(name: &str) -> Result<Self, ()> {
+        Ok(match name {
+            "scanline" => TileOrder::Scanline,
+            "spiral" => TileOrder::Spiral,
+            "hilbert" => TileOrder::Hilbert,
+            _ => return Err(()),
+        })
+    }
+}
+
+// How the image is divided into square tiles of pixels, which are rendered
+// whole by one thread at a time.
+#[derive(Clone, Copy)]
+pub struct Tiles {
+    pub size: usize,
+    pub order: TileOrder,
+}
+
+impl Default for Tiles {
+    fn default() -> Self {
+        Self {
+            size: 16,
+            order: TileOrder::Spiral,
+        }
+    }
+}
+
+// A rectangle of pixels, from (x0, y0) up to but not including (x1, y1).
+#[derive(Clone, Copy)]
+pub struct Tile {
+    pub x0: usize,
+    pub y0: usize,
+    pub x1: usize,
+    pub y1: usize,
+}
+
+impl Tile {
+    pub fn width(&self) -> usize {
+        self.x1 - self.x0
+    }
+
+    pub fn height(&self) -> usize {
+        self.y1 - self.y0
+    }
+
+    pub fn contains(&self, x: usize, y: usize) -> bool {
+        (self.x0..self.x1).contains(&x) && (self.y0..self.y1).contains(&y)
+    }
+
+    // Returns the indices of the tile's pixels in an image of the given width,
+    // row by row.
+    pub fn pixels(&self, width: usize) -> impl Iterator<Item = usize> {
+        let (x0, x1) = (self.x0, self.x1);
+        (self.y0..self.y1).flat_map(move |y| (x0..x1).map(move |x| y * width + x))
+    }
+}
+
+impl Tiles {
+    // Divides an image into tiles, in the order they should be rendered. Tiles
+    // on the right and bottom edges are cut short to fit.
+    pub fn split(&self, width: usize, height: usize) -> Vec<Tile> {
+        let size = self.size.max(1);
+        let (columns, rows) = (width.div_ceil(size), height.div_ceil(size));
+        let cells = match self.order {
+            TileOrder::Scanline => (0..rows)
+                .flat_map(|y| (0..columns).map(move |x| (x, y)))
+                .collect(),
+            TileOrder::Spiral => spiral(columns, rows),
+            TileOrder::Hilbert => hilbert(columns, rows),
+        };
+        cells
+            .into_iter()
+            .map(|(x, y)| Tile {
+                x0: x * size,
+                y0: y * size,
+                x1: usize::min((x + 1) * size, width),
+                y1: usize::min((y + 1) * size, height),
+            })
+            .collect()
+    }
+}
+
+// Returns the cells of a grid in a square spiral out from its middle.
+fn spiral(columns: usize, rows: usize) -> Vec<(usize, usize)> {
+    let mut cells = Vec::with_capacity(columns * rows);
+    let (mut x, mut y) = ((columns as i64 - 1) / 2, (rows as i64 - 1) / 2);
+    let (mut dx, mut dy) = (1, 0);
+    let mut run = 1;
+    while cells.len() < columns * rows {
+        // Each run length is walked twice, turning after each.
+        for _ in 0..2 {
+            for _ in 0..run {
+                if (0..columns as i64).contains(&x) && (0..rows as i64).contains(&y) {
+                    cells.push((x as usize, y as usize));
+                }
+                x += dx;
+                y += dy;
+            }
+            (dx, dy) = (-dy, dx);
+        }
+        run += 1;
+    }
+    cells
+}
+
+// Returns the cells of a grid along a Hilbert curve over the smallest power of
+// two square that covers it, skipping cells outside the grid.
+fn hilbert(columns: usize, rows: usize) -> Vec<(usize, usize)> {
+    let n = usize::max(columns, rows).next_power_of_two();
+    (0..n * n)
+        .map(|d| hilbert_point(n, d))
+        .filter(|&(x, y)| x < columns && y < rows)
+        .collect()
+}
+
+// Returns the cell at distance d along the Hilbert curve of an n by n grid.
+fn hilbert_point(n: usize, mut d: usize) -> (usize, usize) {
+    let (mut x, mut y) = (0, 0);
+    let mut s = 1;
+    while s < n {
+        let rx = 1 & (d / 2);
+        let ry = 1 & (d ^ rx);
+        // Rotate the quadrant so the curves of the smaller squares join up.
+        if ry == 0 {
+            if rx == 1 {
+                x = s - 1 - x;
+                y = s - 1 - y;
+            }
+            (x, y) = (y, x);
+        }
+        x += s * rx;
+        y += s * ry;
+        d /= 4;
+        s *= 2;
+    }
+    (x, y)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Every pixel of the image is in exactly one tile, whatever the order and
+    // however the tiles fit.
+    #[test]
+    fn tiles_cover_the_image_once() {
+        for order in [TileOrder::Scanline, TileOrder::Spiral, TileOrder::Hilbert] {
+            for (width, height, size) in [(64, 64, 16), (100, 37, 16), (5, 90, 8), (1, 1, 16)] {
+                let tiles = Tiles { size, order }.split(width, height);
+                assert_eq!(
+                    tiles.len(),
+                    width.div_ceil(size) * height.div_ceil(size),
+                    "{order:?} {width}x{height}"
+                );
+                let mut covered = vec![0; width * height];
+                for tile in &tiles {
+                    assert!(tile.width() > 0 && tile.height() > 0);
+                    for k in tile.pixels(width) {
+                        covered[k] += 1;
+                    }
+                }
+                assert!(
+                    covered.iter().all(|&n| n == 1),
+                    "{order:?} {width}x{height}"
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn hilbert_tiles_are_adjacent() {
+        let tiles = Tiles {
+            size: 1,
+            order: TileOrder::Hilbert,
+        }
+        .split(16, 16);
+        for pair in tiles.windows(2) {
+            let step = pair[0].x0.abs_diff(pair[1].x0) + pair[0].y0.abs_diff(pair[1].y0);
+            assert_eq!(step, 1);
+        }
+    }
+
+    #[test]
+    fn spiral_starts_in_the_middle() {
+        let tiles = Tiles {
+            size: 10,
+            order: TileOrder::Spiral,
+        }
+        .split(50, 30);
+        assert_eq!((tiles[0].x0, tiles[0].y0), (20, 10));
+    }
+}
@@ -0,0 +1,216 @@
+use std::str::FromStr;
+
+use crate::color::{Color, linear_to_gamma, linear_to_srgb, luminance};
+
+// How scene radiance is compressed into the [0,1] range a display can show.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum ToneMap {
+    // Values above one are clipped, so bright areas blow out to flat white.
+    Clip,
+    // Reinhard's L / (1 + L) applied to luminance, which keeps hues but never
+    // quite reaches white.
+    Reinhard,
+    // John Hable's filmic curve from Uncharted 2, with a toe that deepens
+    // shadows and a soft shoulder for highlights.
+    Filmic,
+    // Krzysztof Narkowicz's fit of the ACES reference rendering transform, a
+    // filmic curve with more contrast that desaturates highlights.
+    Aces,
+}
+
+// How display values are encoded before being quantized.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum TransferFunction {
+    // A square root, an approximation of the sRGB curve with gamma 2.
+    Gamma2,
+    // The exact sRGB curve, linear near black and gamma 2.4 above.
+    Srgb,
+}
+
+impl FromStr for ToneMap {
+    type Err = ();
+
+    // Parses the tone map's name in lower case, e.g. `aces`.
+    fn from_str(name: &str) -> Result<Self, ()> {
+        Ok(match name {
+            "clip" => ToneMap::Clip,
+            "reinhard" => ToneMap::Reinhard,
+            "filmic" => ToneMap::Filmic,
+            "aces" => ToneMap::Aces,
+            _ => return Err(()),
+        })
+    }
+}
+
+impl FromStr for TransferFunction {
+    type Err = ();
+
+    // Parses `gamma2` or `srgb`.
+    fn from_str(name: &str) -> Result<Self, ()> {
+        Ok(match name {
+            "gamma2" => TransferFunction::Gamma2,
+            "srgb" => TransferFunction::Srgb,
+            _ => return Err(()),
+        })
+    }
+}
+
+// Turns linear radiance into the display values written to the image: scales
+// it by the exposure, compresses it with the tone map, and encodes it with the
+// transfer function. Defaults to no exposure change, clipping and gamma 2.
+#[derive(Clone, Copy)]
+pub struct ToneMapping {
+    // Exposure adjustment in stops, each doubling the brightness.
+    pub exposure: f64,
+    pub tone_map: ToneMap,
+    pub transfer: TransferFunction,
+}
+
+impl Default for ToneMapping {
+    fn default() -> Self {
+        Self {
+            exposure: 0.0,
+            tone_map: ToneMap::Clip,
+            transfer: TransferFunction::Gamma2,
+        }
+    }
+}
+
+impl ToneMapping {
+    // Returns the display values for a linear color, each in [0,1].
+    pub fn apply(&self, color: Color) -> Color {
+        // Negative values, which filters with negative lobes can leave, are
+        // black: the curves aren't meant for them, and ACES maps them to white.
+        let color = map_channels(color * f64::exp2(self.exposure), |x| x.max(0.0));
+        let mapped = match self.tone_map {
+            ToneMap::Clip => color,
+            ToneMap::Reinhard => {
+                let l = luminance(color);
+                if l > 0.0 { color / (1.0 + l) } else { color }
+            }
+            ToneMap::Filmic => {
+                // Hable's constants: the curve is normalized so the white point
+                // maps to one, after a fixed exposure bias of two.
+                const WHITE: f64 = 11.2;
+                let scale = 1.0 / hable(WHITE);
+                map_channels(color, |x| hable(2.0 * x) * scale)
+            }
+            ToneMap::Aces => {
+                // The fit includes the reference transform's exposure, which
+                // is brighter than the other curves, so it's scaled back down.
+                map_channels(color * 0.6, |x| {
+                    (x * (2.51 * x + 0.03)) / (x * (2.43 * x + 0.59) + 0.14)
+                })
+            }
+        };
+        map_channels(mapped, |x| {
+            let x = x.clamp(0.0, 1.0);
+            match self.transfer {
+                TransferFunction::Gamma2 => linear_to_gamma(x),
+                TransferFunction::Srgb => linear_to_srgb(x),
+            }
+        })
+    }
+}
+
+fn map_channels(color: Color, f: impl Fn(f64) -> f64) -> Color {
+    Color::new(f(color.x()), f(color.y()), f(color.z()))
+}
+
+fn hable(x: f64) -> f64 {
+    const A: f64 = 0.15; // shoulder strength
+    const B: f64 = 0.50; // linear strength
+    const C: f64 = 0.10; // linear angle
+    const D: f64 = 0.20; // toe strength
+    const E: f64 = 0.02; // toe numerator
+    const F: f64 = 0.30; // toe denominator
+    ((x * (A * x + C * B) + D * E) / (x * (A * x + B) + D * F)) - E / F
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const TONE_MAPS: [ToneMap; 4] = [
+        ToneMap::Clip,
+        ToneMap::Reinhard,
+        ToneMap::Filmic,
+        ToneMap::Aces,
+    ];
+
+    fn mapping(tone_map: ToneMap, transfer: TransferFunction) -> ToneMapping {
+        ToneMapping {
+            tone_map,
+            transfer,
+            ..Default::default()
+        }
+    }
+
+    fn gray(value: f64) -> Color {
+        Color::new(value, value, value)
+    }
+
+    fn assert_close(a: f64, b: f64) {
+        assert!((a - b).abs() < 1e-9, "{a} != {b}");
+    }
+
+    #[test]
+    fn black_stays_black_and_values_stay_in_range() {
+        for tone_map in TONE_MAPS {
+            for transfer in [TransferFunction::Gamma2, TransferFunction::Srgb] {
+                let mapping = mapping(tone_map, transfer);
+                assert_eq!(mapping.apply(gray(0.0)).x(), 0.0, "{tone_map:?}");
+                assert_eq!(mapping.apply(gray(-1.0)).x(), 0.0, "{tone_map:?}");
+                let mut last = 0.0;
+                for value in [0.001, 0.01, 0.1, 0.5, 1.0, 4.0, 100.0, 1e6] {
+                    let mapped = mapping.apply(gray(value)).x();
+                    assert!((0.0..=1.0).contains(&mapped), "{tone_map:?} {value}");
+                    assert!(mapped >= last, "{tone_map:?} isn't increasing at {value}");
+                    last = mapped;
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn white_points() {
+        let apply = |tone_map, value| {
+            mapping(tone_map, TransferFunction::Srgb)
+                .apply(gray(value))
+                .x()
+        };
+        assert_close(apply(ToneMap::Clip, 1.0), 1.0);
+        assert_close(apply(ToneMap::Clip, 2.0), 1.0);
+        // Filmic reaches white at the white point, after its exposure bias.
+        assert_close(apply(ToneMap::Filmic, 5.6), 1.0);
+        assert!(apply(ToneMap::Filmic, 1.0) < 1.0);
+        assert!(apply(ToneMap::Aces, 1e6) > 0.999);
+        // Reinhard only approaches white.
+        assert!(apply(ToneMap::Reinhard, 1e6) < 1.0);
+        assert!(apply(ToneMap::Reinhard, 1e6) > 0.999);
+    }
+
+    #[test]
+    fn transfer_function_endpoints() {
+        for transfer in [TransferFunction::Gamma2, TransferFunction::Srgb] {
+            let apply = |value| mapping(ToneMap::Clip, transfer).apply(gray(value)).x();
+            assert_eq!(apply(0.0), 0.0);
+            assert_close(apply(1.0), 1.0);
+        }
+        assert_close(linear_to_gamma(0.25), 0.5);
+        // The two pieces of the sRGB curve meet.
+        let knee = 0.0031308;
+        assert!((linear_to_srgb(knee) - linear_to_srgb(knee + 1e-12)).abs() < 1e-6);
+        assert_close(linear_to_srgb(0.5), 0.7353569830524495);
+    }
+
+    #[test]
+    fn exposure_is_in_stops() {
+        let mapping = ToneMapping {
+            exposure: 1.0,
+            transfer: TransferFunction::Gamma2,
+            ..Default::default()
+        };
+        assert_close(mapping.apply(gray(0.125)).x(), 0.5);
+    }
+}
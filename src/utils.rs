@@ -1,19 +1,49 @@
-use rand::Rng;
+use std::cell::Cell;
 
-// Samples a random f64 from [0,1).
+use crate::sampler::{self, mix_bits};
+
+thread_local! {
+    // State of this thread's random number generator (SplitMix64). Seeded from
+    // entropy, so results differ between runs unless seed_random is called.
+    static RNG_STATE: Cell<u64> = Cell::new(rand::random());
+}
+
+// Seeds the random number generator of the current thread, so the numbers it
+// draws from now on are the same on every run.
+pub fn seed_random(seed: u64) {
+    RNG_STATE.set(mix_bits(seed));
+}
+
+fn next_u64() -> u64 {
+    let state = RNG_STATE.get().wrapping_add(0x9e3779b97f4a7c15);
+    RNG_STATE.set(state);
+    mix_bits(state)
+}
+
+// Samples a uniform f64 from [0,1) straight from this thread's generator.
+fn next_f64() -> f64 {
+    (next_u64() >> 11) as f64 / (1u64 << 53) as f64
+}
+
+// Samples a random f64 from [0,1). While a pixel sample is being rendered, the
+// value is the next dimension of the camera's sampler.
 pub fn random_f64() -> f64 {
-    let mut rng = rand::rng();
-    rng.random()
+    sampler::next_1d().unwrap_or_else(next_f64)
+}
+
+// Samples a pair of f64s from [0,1), for choices that depend on two random
+// numbers together, such as directions and points on a surface. Samplers
+// distribute the two values well together.
+pub fn random_2d() -> (f64, f64) {
+    sampler::next_2d().unwrap_or_else(|| (next_f64(), next_f64()))
 }
 
 // Samples a random f64 from [min,max).
 pub fn random_range_f64(min: f64, max: f64) -> f64 {
-    let mut rng = rand::rng();
-    rng.random_range(min..max)
+    min + (max - min) * next_f64()
 }
 
 // Samples a random int from [min,max].
 pub fn random_range_int(min: i32, max: i32) -> i32 {
-    let mut rng = rand::rng();
-    rng.random_range(min..=max)
+    min + (next_f64() * (max - min + 1) as f64) as i32
 }
@@ -1,7 +1,8 @@
+use std::f64::consts::PI;
 use std::fmt::{self, Display};
 use std::ops::{Mul, MulAssign};
 
-use crate::utils::{random_f64, random_range_f64};
+use crate::utils::{random_2d, random_f64, random_range_f64};
 
 #[derive(Debug, Clone, Copy)]
 pub struct Vec3 {
@@ -33,6 +34,10 @@ impl Vec3 {
         self.e[0] * self.e[0] + self.e[1] * self.e[1] + self.e[2] * self.e[2]
     }
 
+    pub fn max_component(&self) -> f64 {
+        f64::max(self.e[0], f64::max(self.e[1], self.e[2]))
+    }
+
     pub fn near_zero(&self) -> bool {
         const EPS: f64 = 1e-8;
         f64::abs(self.e[0]) < EPS && f64::abs(self.e[1]) < EPS && f64::abs(self.e[2]) < EPS
@@ -67,13 +72,13 @@ impl Vec3 {
     }
 
     pub fn random_unit_vector() -> Self {
-        loop {
-            let p = Vec3::random_range(-1.0, 1.0);
-            let lensq = p.length_squared();
-            if 1e-160 < lensq && lensq <= 1.0 {
-                return p / f64::sqrt(lensq);
-            }
-        }
+        // Uniform in height and angle around the z axis, which is uniform over
+        // the sphere.
+        let (r1, r2) = random_2d();
+        let z = 1.0 - 2.0 * r1;
+        let r = f64::sqrt(f64::max(0.0, 1.0 - z * z));
+        let phi = 2.0 * PI * r2;
+        Vec3::new(r * f64::cos(phi), r * f64::sin(phi), z)
     }
 
     pub fn random_on_hemisphere(normal: Vec3) -> Vec3 {
@@ -86,16 +91,20 @@ impl Vec3 {
     }
 
     pub fn random_in_unit_disk() -> Vec3 {
-        loop {
-            let p = Vec3::new(
-                random_range_f64(-1.0, 1.0),
-                random_range_f64(-1.0, 1.0),
-                0.0,
-            );
-            if p.length_squared() < 1.0 {
-                return p;
-            }
+        // Map the unit square onto the disk with concentric squares going to
+        // concentric circles, which keeps well spread points well spread
+        // (Shirley and Chiu).
+        let (r1, r2) = random_2d();
+        let (x, y) = (2.0 * r1 - 1.0, 2.0 * r2 - 1.0);
+        if x == 0.0 && y == 0.0 {
+            return Vec3::ZERO;
         }
+        let (r, theta) = if x.abs() > y.abs() {
+            (x, PI / 4.0 * (y / x))
+        } else {
+            (y, PI / 2.0 - PI / 4.0 * (x / y))
+        };
+        Vec3::new(r * f64::cos(theta), r * f64::sin(theta), 0.0)
     }
 
     pub const ZERO: Vec3 = Vec3 { e: [0.0, 0.0, 0.0] };